@@ -0,0 +1,51 @@
+use std::time::{Duration, Instant};
+
+use snake_interpreter::jit::Jit;
+use snake_interpreter::optimize::OptLevel;
+
+// iterations of the loop in every line, well within a line's fuel
+const ITERATIONS: i64 = 500_000;
+const RUNS: usize = 20;
+
+// A loop whose body is `depth` nested lets, each computed from the one before, with the
+// sum of all of them taken in the innermost one so that they are live until then.
+fn nested_lets(depth: usize) -> String {
+    let mut line = "let { var y0 := 0 | var y1 := 0 | } { repeat-until { ".to_string();
+    line += "let { var z1 := ( y1 + ( set y0 := add1 y0 ) ) | } { ";
+    for k in 2..=depth {
+        line += &format!("let {{ var z{} := add1 z{} | }} {{ ", k, k - 1);
+    }
+    let sum = (2..=depth).fold("z1".to_string(), |sum, k| format!("( {} + z{} )", sum, k));
+    line += &format!("set y1 := ( {} - ( {} * z1 ) )", sum, depth);
+    line += &" }".repeat(depth);
+    line += &format!(" }} {{ ( y0 = {} ) }} }}", ITERATIONS);
+    line
+}
+
+// the fastest of a few runs, and the line's result
+fn time(line: &str, opt: OptLevel) -> (Duration, String) {
+    let mut jit = Jit::new();
+    jit.set_opt_level(opt);
+    let mut best = Duration::MAX;
+    let mut result = String::new();
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        let value = jit.run_line(line).unwrap_or_else(|e| panic!("{}: {}", line, e));
+        best = best.min(start.elapsed());
+        result = format!("{:?}", value);
+    }
+    (best, result)
+}
+
+// Compares let bindings on the stack (-O1) with let bindings in registers (-O2).
+fn main() {
+    println!("{:>6} {:>12} {:>12} {:>8}", "depth", "stack", "registers", "speedup");
+    for depth in [1, 2, 4, 5, 8, 16, 32] {
+        let line = nested_lets(depth);
+        let (stack, stack_result) = time(&line, OptLevel::Peephole);
+        let (registers, registers_result) = time(&line, OptLevel::Full);
+        assert_eq!(stack_result, registers_result, "depth {}", depth);
+        println!("{:>6} {:>10.2}ms {:>10.2}ms {:>7.2}x", depth, stack.as_secs_f64() * 1e3,
+            registers.as_secs_f64() * 1e3, stack.as_secs_f64() / registers.as_secs_f64());
+    }
+}
@@ -1,7 +1,4 @@
 use std::env;
-use std::sync::{LazyLock, Mutex};
-
-static IN_STRUCT: LazyLock<Mutex<bool>> = LazyLock::new(|| false.into());
 
 #[link(name = "our_code")]
 extern "C" {
@@ -10,67 +7,52 @@ extern "C" {
     // Courtesy of Max New (https://maxsnew.com/teaching/eecs-483-fa22/hw_adder_assignment.html)
     #[link_name = "\x01our_code_starts_here"]
     fn our_code_starts_here(input: i64) -> i64;
+    #[link_name = "\x01snek_heap"]
+    static snek_heap: i64;
 }
 
 #[export_name = "\x01snek_error"]
 pub extern "C" fn snek_error(errcode: i64) {
-    // TODO: print error message according to writeup
-    if errcode == 1 {
-        eprintln!("Integer overflow");
-        std::process::exit(1);
+    // the same codes as RuntimeError::from_code in the interpreter
+    match errcode {
+        1 => eprintln!("Integer overflow"),
+        2 => eprintln!("Out of fuel"),
+        3 => eprintln!("Stack overflow"),
+        4 => eprintln!("Null dereference"),
+        5 => eprintln!("Out of heap memory"),
+        6 => eprintln!("Wrong type"),
+        7 => eprintln!("Divide by zero"),
+        _ => eprintln!("Runtime error {errcode}"),
     }
-    eprintln!("an error ocurred {errcode}");
     std::process::exit(1);
 }
 
-#[export_name = "\x01snek_print"]
-// 0 - int, 1 - bool, 2 - pointer, 3 - (, 4 - )
-pub extern "C" fn snek_print(value: i64, type_flag: u64) {
-    if type_flag == 1 {
-        if value == 1 {
-            print!("true ");
-        }
-        else {
-            print!("false ");
-        }
+// Values carry their type in their low bits, like in src/runtime.rs: ints end in 0,
+// booleans are 7 (true) and 3 (false), and struct pointers end in 01, with null as 1.
+fn snek_str(value: i64) -> String {
+    if value & 1 == 0 {
+        format!("{}", value >> 1)
     }
-    else if type_flag == 0 {
-        print!("{} ", value);
+    else if value == 7 {
+        "true".to_string()
     }
-    else if type_flag == 2 {
-        if value == 0 {
-            print!("pointer: null ")
-        }
-        else {
-            print!("pointer: {} ", value)
-        }
-        
+    else if value == 3 {
+        "false".to_string()
     }
-
-    let in_struct = IN_STRUCT.lock().unwrap();
-    
-    if !*in_struct {
-        println!("");
-    }
-
-    drop(in_struct);
-
-
-    
-    if type_flag == 3 {
-        let mut in_struct = IN_STRUCT.lock().unwrap();
-        *in_struct = true;
-        print!("(");
-        drop(in_struct);
+    else if value == 1 {
+        "null".to_string()
     }
-    else if type_flag == 4 {
-        let mut in_struct = IN_STRUCT.lock().unwrap();
-        *in_struct = false;
-        println!(")");
-        drop(in_struct);
+    else {
+        let heap_start = std::ptr::addr_of!(snek_heap) as i64;
+        format!("<struct at {}>", (value - 1 - heap_start) / 8)
     }
 }
 
+#[export_name = "\x01snek_print"]
+pub extern "C" fn snek_print(value: i64) {
+    println!("{}", snek_str(value));
+}
+
 
 fn parse_input(input: &str) -> i64 {
     if input.is_empty() {
@@ -85,5 +67,5 @@ fn main() {
     let input = parse_input(&input);
 
     let i: i64 = unsafe { our_code_starts_here(input) };
-    println!("{i}");
+    println!("{}", snek_str(i));
 }
@@ -0,0 +1,129 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{exit, Command};
+
+use im::HashMap;
+use snake_interpreter::compile::{check_fun, compile_line, instrs_to_nasm};
+use snake_interpreter::grammar::LineParser;
+use snake_interpreter::lexer::Lexer;
+use snake_interpreter::optimize::OptLevel;
+use snake_interpreter::utils::{check_struct, CompileError, Expr, FunDef, Line, StructDef};
+
+const USAGE: &str = "usage: snek-compile <file.snek> [-o <executable>] [-S] [-O0|-O1|-O2]";
+
+struct Program<'a> {
+    structs: Vec<StructDef>,
+    funs: Vec<FunDef>,
+    main_line: &'a str,
+    main_expr: Expr,
+}
+
+// A .snek file is a list of program lines ended by ";", the way the game writes them out.
+// Every fun or struct line defines a function or a struct, and the last line is the
+// expression that gets run.
+fn parse_program(src: &str) -> Result<Program<'_>, String> {
+    let mut structs: Vec<StructDef> = vec![];
+    let mut funs: Vec<FunDef> = vec![];
+    let mut main_expr = None;
+    for line in src.split(';').map(str::trim).filter(|line| !line.is_empty()) {
+        if main_expr.is_some() {
+            return Err("only the last line may be an expression".to_string());
+        }
+        let parsed = LineParser::new().parse(Lexer::new(line))
+            .map_err(|e| describe(line, &CompileError::from(e)))?;
+        match parsed {
+            Line::Fun(def) => {
+                check_fun(&def, &structs, &funs, &HashMap::new()).map_err(|e| describe(line, &e))?;
+                funs.push(def);
+            },
+            Line::Struct(def) => {
+                check_struct(&def, &structs).map_err(|e| describe(line, &e))?;
+                structs.push(def);
+            },
+            Line::Expr(expr) => main_expr = Some((line, expr)),
+        }
+    }
+    match main_expr {
+        Some((main_line, main_expr)) => Ok(Program { structs, funs, main_line, main_expr }),
+        None => Err("no expression to run".to_string()),
+    }
+}
+
+fn describe(line: &str, error: &CompileError) -> String {
+    let span = error.span();
+    format!("{}\n  in: {}\n  at: {}", error, line, line.get(span.start..span.end).unwrap_or(""))
+}
+
+fn run(program: &str, args: &[&str]) {
+    let status = Command::new(program).args(args).status().unwrap_or_else(|e| {
+        eprintln!("could not run {}: {}", program, e);
+        exit(1);
+    });
+    if !status.success() {
+        eprintln!("{} failed", program);
+        exit(1);
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut input = None;
+    let mut output = None;
+    let mut asm_only = false;
+    let mut opt = OptLevel::default();
+    let mut args_iter = args.iter();
+    while let Some(arg) = args_iter.next() {
+        match arg.as_str() {
+            "-o" => output = args_iter.next().cloned(),
+            "-S" => asm_only = true,
+            _ if arg.starts_with("-O") => opt = OptLevel::from_level(&arg[2..]).unwrap_or_else(|| {
+                eprintln!("{}", USAGE);
+                exit(1);
+            }),
+            _ if input.is_none() => input = Some(arg.clone()),
+            _ => {
+                eprintln!("{}", USAGE);
+                exit(1);
+            },
+        }
+    }
+    let input = input.unwrap_or_else(|| {
+        eprintln!("{}", USAGE);
+        exit(1);
+    });
+
+    let src = fs::read_to_string(&input).unwrap_or_else(|e| {
+        eprintln!("could not read {}: {}", input, e);
+        exit(1);
+    });
+    let program = parse_program(&src).unwrap_or_else(|e| {
+        eprintln!("{}: {}", input, e);
+        exit(1);
+    });
+    let instrs = compile_line(&program.main_expr, &program.structs, &program.funs, &HashMap::new(), opt).unwrap_or_else(|e| {
+        eprintln!("{}: {}", input, describe(program.main_line, &e));
+        exit(1);
+    });
+
+    let exe = PathBuf::from(output.unwrap_or_else(|| {
+        Path::new(&input).with_extension("run").to_string_lossy().to_string()
+    }));
+    let asm_file = exe.with_extension("s");
+    fs::write(&asm_file, instrs_to_nasm(&instrs)).unwrap();
+    if asm_only {
+        return;
+    }
+
+    // runtime/start.rs links against libour_code, so give it one of its own
+    let build_dir = exe.with_extension("build");
+    fs::create_dir_all(&build_dir).unwrap();
+    let obj_file = build_dir.join("our_code.o");
+    let lib_file = build_dir.join("libour_code.a");
+    let runtime = Path::new(env!("CARGO_MANIFEST_DIR")).join("runtime").join("start.rs");
+    run("nasm", &["-f", "elf64", &asm_file.to_string_lossy(), "-o", &obj_file.to_string_lossy()]);
+    let _ = fs::remove_file(&lib_file);
+    run("ar", &["rcs", &lib_file.to_string_lossy(), &obj_file.to_string_lossy()]);
+    run("rustc", &["-L", &build_dir.to_string_lossy(), &runtime.to_string_lossy(),
+        "-o", &exe.to_string_lossy()]);
+}
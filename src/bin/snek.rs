@@ -0,0 +1,66 @@
+use std::env;
+use std::io::{self, BufRead, Write};
+use std::process::exit;
+
+use snake_interpreter::jit::Jit;
+use snake_interpreter::runtime::Value;
+use snake_interpreter::utils::SnekError;
+
+// Each input line is one program line, like the ones the snake builds. Starting it with "def"
+// saves the result as the next x variable, the same as eating "def" at the start of a line.
+// `--input <n>` sets what `input` reads in every line.
+fn main() {
+    let mut jit = Jit::new();
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.as_slice() {
+        [] => {},
+        [flag, value] if flag == "--input" => jit.set_input(value.parse().unwrap_or_else(|_| {
+            eprintln!("input must be an integer, got {}", value);
+            exit(1);
+        })),
+        _ => {
+            eprintln!("usage: snek [--input <n>]");
+            exit(1);
+        },
+    }
+    let stdin = io::stdin();
+    loop {
+        print!("snek> ");
+        io::stdout().flush().unwrap();
+        let mut input = String::new();
+        if stdin.lock().read_line(&mut input).unwrap() == 0 {
+            println!();
+            break;
+        }
+
+        let input = input.trim().trim_end_matches(';').trim();
+        let (is_def_line, line) = match input.strip_prefix("def ") {
+            Some(line) => (true, line.trim()),
+            None => (false, input),
+        };
+        if line.is_empty() {
+            continue;
+        }
+
+        let res = jit.run_line(line);
+        for printed in jit.output() {
+            println!("{}", printed);
+        }
+        match res {
+            Ok(None) if line.starts_with("struct") => println!("defined {}", jit.struct_defs().last().unwrap().name),
+            Ok(None) => println!("defined {}", jit.fun_defs().last().unwrap().name),
+            Ok(Some(Value::Int(value))) if is_def_line => {
+                println!("x{} = {}", jit.def_bindings().len(), value);
+                jit.save_def(value);
+            },
+            Ok(Some(value)) if is_def_line => println!("{} (only ints can be saved)", value),
+            Ok(Some(value)) => println!("{}", value),
+            Err(SnekError::Compile(error)) => {
+                let span = error.span();
+                println!("error: {}", error);
+                println!("  at: {}", line.get(span.start..span.end).unwrap_or(""));
+            },
+            Err(error) => println!("error: {}", error),
+        }
+    }
+}
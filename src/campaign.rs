@@ -0,0 +1,80 @@
+use std::fs;
+use std::path::Path;
+
+use super::level::Level;
+
+// The levels in a directory, played in the order of their file names. Levels are the
+// files ending in .level; the names of the finished ones are kept next to them in
+// progress.txt, so a campaign picks up where it was left:
+//
+//   finished first steps
+//   finished walls
+pub struct Campaign {
+    levels: Vec<Level>,
+    finished: Vec<String>,
+    progress_path: String,
+}
+
+impl Campaign {
+    pub fn load(dir: &str) -> Result<Campaign, String> {
+        let entries = fs::read_dir(dir).map_err(|e| format!("could not read {}: {}", dir, e))?;
+        let mut paths: Vec<String> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "level"))
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect();
+        paths.sort();
+        let levels = paths.iter().map(|path| Level::read(path)).collect::<Result<Vec<Level>, String>>()?;
+        if levels.is_empty() {
+            return Err(format!("there are no .level files in {}", dir));
+        }
+        // progress is kept by name, so two levels cannot share one
+        for (idx, level) in levels.iter().enumerate() {
+            if levels[..idx].iter().any(|other| other.name == level.name) {
+                return Err(format!("two levels are called {}", level.name));
+            }
+        }
+
+        let progress_path = Path::new(dir).join("progress.txt").to_string_lossy().into_owned();
+        let finished = match fs::read_to_string(&progress_path) {
+            Ok(src) => Campaign::parse_progress(&src)?,
+            Err(_) => vec![],
+        };
+        Ok(Campaign { levels, finished, progress_path })
+    }
+
+    fn parse_progress(src: &str) -> Result<Vec<String>, String> {
+        let mut finished = vec![];
+        for (line_num, line) in src.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.strip_prefix("finished ") {
+                Some(name) => finished.push(name.trim().to_string()),
+                None => return Err(format!("progress line {}: cannot read '{}'", line_num + 1, line)),
+            }
+        }
+        Ok(finished)
+    }
+
+    // the first level that is not finished yet, and its number counting from 1
+    pub fn current(&self) -> Option<(usize, &Level)> {
+        self.levels.iter().enumerate()
+            .find(|(_, level)| !self.finished.contains(&level.name))
+            .map(|(idx, level)| (idx + 1, level))
+    }
+
+    pub fn num_levels(&self) -> usize {
+        self.levels.len()
+    }
+
+    // Marks the current level as finished and saves the progress.
+    pub fn finish_current(&mut self) -> Result<(), String> {
+        let Some((_, level)) = self.current() else { return Ok(()) };
+        self.finished.push(level.name.clone());
+        let contents: String = self.finished.iter().map(|name| format!("finished {}\n", name)).collect();
+        fs::write(&self.progress_path, contents).map_err(|e| format!("could not save {}: {}", self.progress_path, e))
+    }
+}
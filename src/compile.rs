@@ -8,107 +8,568 @@ use utils::Reg;
 use utils::Val;
 use utils::Type;
 use utils::Expr;
+use utils::CompileError;
+use utils::FunDef;
+use utils::StructDef;
 use utils::typecheck;
+use utils::typecheck_fun;
+use utils::field_of;
+use utils::struct_fields;
+use super::runtime;
+use runtime::RuntimeError;
+use super::optimize::{self, OptLevel};
+use super::regalloc::{Home, LetHomes, RegPool};
 
 // use std::sync::{LazyLock, Mutex};
 use std::collections::HashSet;
-use dynasmrt::{dynasm, DynasmApi};
+use std::collections::HashMap as StdHashMap;
+use dynasmrt::{dynasm, DynamicLabel, DynasmApi, DynasmLabelApi};
 
 
-pub fn compile_to_instrs(e: &Expr, stack_bindings: im::HashMap<String, i32>, 
-    mut variable_types: &mut HashMap<String, Type>, stack_counter: i32, 
-    defined_vars: &HashMap<String, i32>) -> Vec<Instr> {
+// Holds the address of the run's context (see runtime::RunCtx) from the entry frame on, so
+// it is callee-saved and never given to let bindings.
+pub const CTX_REG: Reg = Reg::R15;
+
+// a word of the run's context
+fn ctx_slot(offset: i32) -> Val {
+    Val::RegOffset(CTX_REG, offset)
+}
+
+// state shared by everything compiled into one jitted buffer
+pub struct CompileCtx {
+    // heap variables, inlined as immediates
+    pub defined_vars: HashMap<String, i64>,
+    // arity of every function that can be called
+    pub functions: HashMap<String, usize>,
+    pub label_counter: i32,
+    // whether let bindings go in registers, and the ones left for the body being compiled
+    pub alloc_regs: bool,
+    pub regs: RegPool,
+}
+
+impl CompileCtx {
+    pub fn new(defined_vars: HashMap<String, i64>, funs: &[FunDef]) -> CompileCtx {
+        CompileCtx {
+            defined_vars,
+            functions: funs.iter().map(|def| (def.name.clone(), def.params.len())).collect(),
+            label_counter: 0,
+            alloc_regs: true,
+            regs: RegPool::empty(),
+        }
+    }
+
+    // the registers the let bindings of a function body or line may use
+    fn pool_for(&self, body: &Expr) -> RegPool {
+        if self.alloc_regs { RegPool::for_body(body) } else { RegPool::empty() }
+    }
+
+    fn next_label(&mut self) -> i32 {
+        self.label_counter += 1;
+        self.label_counter
+    }
+
+    // jumps to the error handler if the last arithmetic instruction overflowed
+    fn overflow_check(&mut self) -> Vec<Instr> {
+        let label_num = self.next_label();
+        vec![
+            Instr::Jno("no_overflow".to_string(), label_num),
+            Instr::IMov(Val::Reg(Reg::RDI), Val::Imm(RuntimeError::Overflow.code())),
+            Instr::Jmp("snek_error_handler".to_string(), 0),
+            Instr::Label("no_overflow".to_string(), label_num),
+        ]
+    }
+
+    // an int literal or heap variable, or an overflow error if it does not fit in 63 bits
+    fn int_to_instrs(&mut self, n: i64) -> Vec<Instr> {
+        match runtime::encode_int(n) {
+            Some(value) => vec![Instr::IMov(Val::Reg(Reg::RAX), Val::Imm(value))],
+            None => vec![
+                Instr::IMov(Val::Reg(Reg::RDI), Val::Imm(RuntimeError::Overflow.code())),
+                Instr::Jmp("snek_error_handler".to_string(), 0),
+            ],
+        }
+    }
+
+    // jumps to the error handler if the divisor in the given register is 0, which idiv
+    // would otherwise turn into a SIGFPE
+    fn zero_check(&mut self, reg: Reg) -> Vec<Instr> {
+        let label_num = self.next_label();
+        vec![
+            Instr::ICmp(Val::Reg(reg), Val::Imm(0)),
+            Instr::Jne("not_zero".to_string(), label_num),
+            Instr::IMov(Val::Reg(Reg::RDI), Val::Imm(RuntimeError::DivideByZero.code())),
+            Instr::Jmp("snek_error_handler".to_string(), 0),
+            Instr::Label("not_zero".to_string(), label_num),
+        ]
+    }
+
+    // jumps to the error handler if the pointer in the given register is null
+    fn null_check(&mut self, reg: Reg) -> Vec<Instr> {
+        let label_num = self.next_label();
+        vec![
+            Instr::ICmp(Val::Reg(reg), Val::Imm(runtime::NULL_VAL)),
+            Instr::Jne("not_null".to_string(), label_num),
+            Instr::IMov(Val::Reg(Reg::RDI), Val::Imm(RuntimeError::NullDereference.code())),
+            Instr::Jmp("snek_error_handler".to_string(), 0),
+            Instr::Label("not_null".to_string(), label_num),
+        ]
+    }
+}
+
+// Jumps to the wrong type handler unless the value in the register has the tag of the given
+// type. The typechecker already rules this out; the checks keep a miscompiled line from
+// reading an int as a pointer.
+fn tag_check(reg: Reg, ty: &Type) -> Vec<Instr> {
+    let wrong_type = || "snek_wrong_type".to_string();
+    let test = |bit: i64| Instr::ITest(Val::Reg(reg), Val::Imm(bit));
+    match ty {
+        Type::Bool => vec![test(1), Instr::Je(wrong_type(), 0), test(2), Instr::Je(wrong_type(), 0)],
+        Type::Struct(_) | Type::Null => vec![test(1), Instr::Je(wrong_type(), 0), test(2), Instr::Jne(wrong_type(), 0)],
+        _ => vec![test(1), Instr::Jne(wrong_type(), 0)],
+    }
+}
+
+// `stack_bindings` holds where each let binding and parameter in scope is kept, on the
+// stack or in a register.
+pub fn compile_to_instrs(e: &Expr, stack_bindings: im::HashMap<String, Val>, 
+    variable_types: &mut HashMap<String, Type>, stack_counter: i32, 
+    ctx: &mut CompileCtx, break_target: Option<(i32, i32)>) -> Result<Vec<Instr>, CompileError> {
     match e {
-        Expr::Number(n) => return vec![Instr::IMov(Val::Reg(Reg::RAX), Val::Imm(*n))],
-        Expr::Id(x) => {
+        Expr::Number(n) => Ok(ctx.int_to_instrs(*n)),
+        Expr::Boolean(true) => Ok(vec![Instr::IMov(Val::Reg(Reg::RAX), Val::Imm(runtime::TRUE_VAL))]),
+        Expr::Boolean(false) => Ok(vec![Instr::IMov(Val::Reg(Reg::RAX), Val::Imm(runtime::FALSE_VAL))]),
+        Expr::Id(x, span) => {
             match stack_bindings.get(x) {
                 None => {
-                    match &mut defined_vars.get(x) {
-                        Some (val) => {
-                            return vec![Instr::IMov(Val::Reg(Reg::RAX), Val::Imm(**val))]
+                    match ctx.defined_vars.get(x) {
+                        Some(val) => Ok(ctx.int_to_instrs(*val)),
+                        // saved by compile_entry, so that function bodies can read it too; it
+                        // comes in untagged
+                        None if x == "input" => {
+                            let mut v = vec![
+                                Instr::IMov(Val::Reg(Reg::RAX), ctx_slot(runtime::INPUT_OFFSET)),
+                                Instr::IAdd(Val::Reg(Reg::RAX), Val::Reg(Reg::RAX)),
+                            ];
+                            v.append(&mut ctx.overflow_check());
+                            Ok(v)
                         },
-                        _ => panic!("Invalid: Unbound variable identifier {}", x),
+                        _ => Err(CompileError::UnboundIdentifier(x.clone(), *span)),
                     }
                 },
-                Some(val) => return vec![Instr::IMov(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RBP, -val))],
+                Some(val) => Ok(vec![Instr::IMov(Val::Reg(Reg::RAX), val.clone())]),
             }
         },
-        Expr::UnOp(op, subexpr) => {
+        Expr::UnOp(op, subexpr, _) => {
             let mut v = compile_to_instrs(subexpr, stack_bindings.clone(), variable_types,
-                stack_counter, defined_vars);
+                stack_counter, ctx, break_target)?;
+            v.append(&mut tag_check(Reg::RAX, &Type::Int));
+            // 1 is 2 once tagged
             match op {
                 Op1::Add1 => {
-                    v.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::Imm(1)));
+                    v.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::Imm(2)));
                 }
                 Op1::Sub1 => {
-                    v.push(Instr::ISub(Val::Reg(Reg::RAX), Val::Imm(1)));
+                    v.push(Instr::ISub(Val::Reg(Reg::RAX), Val::Imm(2)));
                 }
             }
-            return v;
+            v.append(&mut ctx.overflow_check());
+            Ok(v)
         },
-        Expr::BinOp(op, subexpr1, subexpr2) => {
-            let mut v1 = compile_to_instrs(subexpr1, stack_bindings.clone(), &mut variable_types,
-                stack_counter, defined_vars);
+        Expr::BinOp(op, subexpr1, subexpr2, _) => {
+            let mut v1 = compile_to_instrs(subexpr1, stack_bindings.clone(), variable_types,
+                stack_counter, ctx, break_target)?;
             // move first instructions to stack
             v1.push(Instr::Push(Val::Reg(Reg::RAX))); // -8
-            let mut v2 = compile_to_instrs(subexpr2, stack_bindings.clone(), &mut variable_types,
-                stack_counter + 8, defined_vars);
+            let mut v2 = compile_to_instrs(subexpr2, stack_bindings.clone(), variable_types,
+                stack_counter + 8, ctx, break_target)?;
             v1.append(&mut v2);
             // move second instructions to RCX
             v1.push(Instr::IMov(Val::Reg(Reg::RCX), Val::Reg(Reg::RAX)));
             v1.push(Instr::Pop(Val::Reg(Reg::RAX))); // -8
             // move first instructions to rax
-            
+            // only = takes values other than ints
+            if *op != Op2::Equal {
+                v1.append(&mut tag_check(Reg::RAX, &Type::Int));
+                v1.append(&mut tag_check(Reg::RCX, &Type::Int));
+            }
+
             match op {
-                Op2::Plus => v1.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX))), // -16
-                Op2::Minus => v1.push(Instr::ISub(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX))),
-                Op2::Times => v1.push(Instr::IMul(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX))),
+                Op2::Plus => {
+                    v1.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX))); // -16
+                    v1.append(&mut ctx.overflow_check());
+                },
+                Op2::Minus => {
+                    v1.push(Instr::ISub(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX)));
+                    v1.append(&mut ctx.overflow_check());
+                },
+                // one of the factors is untagged, so the product comes out tagged once
+                Op2::Times => {
+                    v1.push(Instr::ISar(Val::Reg(Reg::RCX), Val::Imm(1)));
+                    v1.push(Instr::IMul(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX)));
+                    v1.append(&mut ctx.overflow_check());
+                },
+                // Both round toward zero. The tags cancel out in the quotient, which is tagged
+                // again and can only overflow for the smallest int divided by -1, while the
+                // remainder comes out tagged already.
+                Op2::Divide | Op2::Modulo => {
+                    v1.append(&mut ctx.zero_check(Reg::RCX));
+                    v1.push(Instr::ICqo());
+                    v1.push(Instr::IDiv(Val::Reg(Reg::RCX)));
+                    if *op == Op2::Divide {
+                        v1.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::Reg(Reg::RAX)));
+                        v1.append(&mut ctx.overflow_check());
+                    } else {
+                        v1.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Reg(Reg::RDX)));
+                    }
+                },
+                // tagging keeps the order of ints, so comparisons work on the tagged values
+                _ => {
+                    v1.push(Instr::ICmp(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX)));
+                    v1.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Imm(runtime::FALSE_VAL)));
+                    v1.push(Instr::IMov(Val::Reg(Reg::RCX), Val::Imm(runtime::TRUE_VAL)));
+                    match op {
+                        Op2::Greater => v1.push(Instr::ICMovg(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX))),
+                        Op2::Less => v1.push(Instr::ICMovl(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX))),
+                        Op2::GreaterEqual => v1.push(Instr::ICMovge(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX))),
+                        Op2::LessEqual => v1.push(Instr::ICMovle(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX))),
+                        _ => v1.push(Instr::ICMove(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX))),
+                    }
+                },
             }
-            return v1;
+            Ok(v1)
         },
-        Expr::Let(vec, e) => {
+        Expr::Let(vec, e, span) => {
             let mut v = Vec::new(); 
             let mut items: HashSet<String> = HashSet::new();
             let mut mutable_copy = stack_bindings;
             let mut new_types = variable_types.clone();
             let mut new_scope_stack_counter = stack_counter;
-            for item in vec {
+            let mut homes = LetHomes::new();
+            for (idx, item) in vec.iter().enumerate() {
                 if items.contains(&item.0) {
-                    panic!("Invalid: Duplicate binding");
+                    return Err(CompileError::DuplicateBinding(item.0.clone(), *span));
                 }
                 items.insert(item.0.clone());
         
                 let mut new_binding_expr = compile_to_instrs(&item.1, mutable_copy.clone(),
-                &mut new_types, new_scope_stack_counter, defined_vars);
+                &mut new_types, new_scope_stack_counter, ctx, break_target)?;
                 
-                new_types = new_types.update(item.0.clone(), typecheck(&item.1, &mut new_types.clone(), ));
+                new_types = new_types.update(item.0.clone(), typecheck(&item.1, &new_types, break_target.is_some())?);
                 v.append(&mut new_binding_expr);
 
-                mutable_copy = mutable_copy.update(item.0.clone(), new_scope_stack_counter);
-                new_scope_stack_counter += 8;   
-                
-                v.push(Instr::Push(Val::Reg(Reg::RAX)));
+                match homes.place(&mut ctx.regs, vec, idx, e) {
+                    Home::Reg(reg) => {
+                        mutable_copy = mutable_copy.update(item.0.clone(), Val::Reg(reg));
+                        v.push(Instr::IMov(Val::Reg(reg), Val::Reg(Reg::RAX)));
+                    },
+                    Home::Stack => {
+                        mutable_copy = mutable_copy.update(item.0.clone(), Val::RegOffset(Reg::RBP, -new_scope_stack_counter));
+                        new_scope_stack_counter += 8;
+                        v.push(Instr::Push(Val::Reg(Reg::RAX)));
+                    },
+                    Home::Unused => {},
+                }
             }
             v.append(&mut compile_to_instrs(e, mutable_copy.clone(), &mut new_types,
-                new_scope_stack_counter, defined_vars));
-            v.push(Instr::IAdd(Val::Reg(Reg::RSP), Val::Imm(8 * vec.len() as i32)));
-            return v;
+                new_scope_stack_counter, ctx, break_target)?);
+            homes.finish(&mut ctx.regs);
+            if new_scope_stack_counter > stack_counter {
+                v.push(Instr::IAdd(Val::Reg(Reg::RSP), Val::Imm((new_scope_stack_counter - stack_counter) as i64)));
+            }
+            Ok(v)
         },
-        Expr::Set(var_name, e) => {
-            let mut e_vec = compile_to_instrs(e, stack_bindings.clone(), &mut variable_types, stack_counter,
-                defined_vars);
+        Expr::Set(var_name, e, span) => {
+            let mut e_vec = compile_to_instrs(e, stack_bindings.clone(), variable_types, stack_counter,
+                ctx, break_target)?;
             match stack_bindings.get(var_name) {
-                None => panic!("Invalid: Unbound variable identifier {}", var_name),
+                None => Err(CompileError::UnboundIdentifier(var_name.clone(), *span)),
                 Some(val) => {
-                    // move new value into the spot on the stack where the old var value was stored
-                    e_vec.push(Instr::IMov(Val::RegOffset(Reg::RBP, -val), Val::Reg(Reg::RAX)));
-                    return e_vec;
+                    // move new value into wherever the old var value was stored
+                    e_vec.push(Instr::IMov(val.clone(), Val::Reg(Reg::RAX)));
+                    Ok(e_vec)
                 },
             }
         },
+        Expr::If(cond, thn, els, _) => {
+            let label_num = ctx.next_label();
+            let mut v = compile_to_instrs(cond, stack_bindings.clone(), variable_types,
+                stack_counter, ctx, break_target)?;
+            v.append(&mut tag_check(Reg::RAX, &Type::Bool));
+            v.push(Instr::ICmp(Val::Reg(Reg::RAX), Val::Imm(runtime::FALSE_VAL)));
+            v.push(Instr::Je("if_else".to_string(), label_num));
+            v.append(&mut compile_to_instrs(thn, stack_bindings.clone(), variable_types,
+                stack_counter, ctx, break_target)?);
+            v.push(Instr::Jmp("if_end".to_string(), label_num));
+            v.push(Instr::Label("if_else".to_string(), label_num));
+            v.append(&mut compile_to_instrs(els, stack_bindings, variable_types,
+                stack_counter, ctx, break_target)?);
+            v.push(Instr::Label("if_end".to_string(), label_num));
+            Ok(v)
+        },
+        Expr::RepeatUntil(body, cond, _) => {
+            let label_num = ctx.next_label();
+            let mut v = vec![Instr::Label("loop_start".to_string(), label_num)];
+            v.append(&mut compile_to_instrs(body, stack_bindings.clone(), variable_types,
+                stack_counter, ctx, Some((label_num, stack_counter)))?);
+            // keep the body's value while the condition is evaluated
+            v.push(Instr::Push(Val::Reg(Reg::RAX)));
+            v.append(&mut compile_to_instrs(cond, stack_bindings, variable_types,
+                stack_counter + 8, ctx, Some((label_num, stack_counter)))?);
+            v.push(Instr::IMov(Val::Reg(Reg::RCX), Val::Reg(Reg::RAX)));
+            v.push(Instr::Pop(Val::Reg(Reg::RAX)));
+            v.append(&mut tag_check(Reg::RCX, &Type::Bool));
+            v.push(Instr::ICmp(Val::Reg(Reg::RCX), Val::Imm(runtime::FALSE_VAL)));
+            v.push(Instr::Jne("loop_end".to_string(), label_num));
+            // every back-edge burns one unit of fuel so a snake-built infinite loop still ends
+            v.push(Instr::ISub(ctx_slot(runtime::FUEL_OFFSET), Val::Imm(1)));
+            v.push(Instr::Jne("loop_start".to_string(), label_num));
+            v.push(Instr::IMov(Val::Reg(Reg::RDI), Val::Imm(RuntimeError::OutOfFuel.code())));
+            v.push(Instr::Jmp("snek_error_handler".to_string(), 0));
+            v.push(Instr::Label("loop_end".to_string(), label_num));
+            Ok(v)
+        },
+        Expr::Break(value, span) => {
+            let (label_num, loop_stack_counter) = match break_target {
+                Some(target) => target,
+                None => return Err(CompileError::BreakOutsideLoop(*span)),
+            };
+            let mut v = compile_to_instrs(value, stack_bindings, variable_types,
+                stack_counter, ctx, break_target)?;
+            // drop everything pushed since the loop started
+            v.push(Instr::IMov(Val::Reg(Reg::RSP), Val::Reg(Reg::RBP)));
+            v.push(Instr::ISub(Val::Reg(Reg::RSP), Val::Imm((loop_stack_counter - 8) as i64)));
+            v.push(Instr::Jmp("loop_end".to_string(), label_num));
+            Ok(v)
+        },
+        Expr::Call(name, args, span) => {
+            match ctx.functions.get(name) {
+                None => return Err(CompileError::UnboundIdentifier(name.clone(), *span)),
+                Some(arity) if *arity != args.len() => return Err(CompileError::WrongArity(name.clone(), *span)),
+                _ => {},
+            }
+            // arguments are pushed in order, so the first one ends up furthest from the callee's rbp
+            let mut v = Vec::new();
+            for (idx, arg) in args.iter().enumerate() {
+                v.append(&mut compile_to_instrs(arg, stack_bindings.clone(), variable_types,
+                    stack_counter + 8 * idx as i32, ctx, break_target)?);
+                v.push(Instr::Push(Val::Reg(Reg::RAX)));
+            }
+            v.push(Instr::Call(format!("fun_{}", name), 0));
+            if !args.is_empty() {
+                v.push(Instr::IAdd(Val::Reg(Reg::RSP), Val::Imm(8 * args.len() as i64)));
+            }
+            Ok(v)
+        },
+        Expr::Print(value, _) => {
+            let mut v = compile_to_instrs(value, stack_bindings, variable_types,
+                stack_counter, ctx, break_target)?;
+            // keep the value for after the call, and align the stack for Rust while
+            // remembering where it was in the slot just above the return address
+            v.push(Instr::Push(Val::Reg(Reg::RAX)));
+            v.push(Instr::IMov(Val::Reg(Reg::RDI), Val::Reg(Reg::RAX)));
+            v.push(Instr::IMov(Val::Reg(Reg::RSI), Val::Reg(CTX_REG)));
+            v.push(Instr::IMov(Val::Reg(Reg::RCX), Val::Reg(Reg::RSP)));
+            v.push(Instr::IAnd(Val::Reg(Reg::RSP), Val::Imm(-16)));
+            v.push(Instr::ISub(Val::Reg(Reg::RSP), Val::Imm(8)));
+            v.push(Instr::Push(Val::Reg(Reg::RCX)));
+            v.push(Instr::CallSnekPrint());
+            v.push(Instr::Pop(Val::Reg(Reg::RSP)));
+            v.push(Instr::Pop(Val::Reg(Reg::RAX)));
+            Ok(v)
+        },
+        Expr::Null => Ok(vec![Instr::IMov(Val::Reg(Reg::RAX), Val::Imm(runtime::NULL_VAL))]),
+        Expr::Alloc(name, values, span) => {
+            if struct_fields(name, variable_types).is_none() {
+                return Err(CompileError::UnboundIdentifier(name.clone(), *span));
+            }
+            // the fields wait on the stack until the heap has room for them
+            let mut v = Vec::new();
+            for (idx, value) in values.iter().enumerate() {
+                v.append(&mut compile_to_instrs(value, stack_bindings.clone(), variable_types,
+                    stack_counter + 8 * idx as i32, ctx, break_target)?);
+                v.push(Instr::Push(Val::Reg(Reg::RAX)));
+            }
+            // a struct without fields still takes a word, so that no two structs are equal
+            let size = 8 * values.len().max(1) as i64;
+            let label_num = ctx.next_label();
+            v.push(Instr::IMov(Val::Reg(Reg::RAX), ctx_slot(runtime::HEAP_PTR_OFFSET)));
+            v.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::Imm(size)));
+            v.push(Instr::ICmp(Val::Reg(Reg::RAX), ctx_slot(runtime::HEAP_END_OFFSET)));
+            v.push(Instr::Jle("heap_ok".to_string(), label_num));
+            v.push(Instr::IMov(Val::Reg(Reg::RDI), Val::Imm(RuntimeError::OutOfMemory.code())));
+            v.push(Instr::Jmp("snek_error_handler".to_string(), 0));
+            v.push(Instr::Label("heap_ok".to_string(), label_num));
+            v.push(Instr::IMov(ctx_slot(runtime::HEAP_PTR_OFFSET), Val::Reg(Reg::RAX)));
+            v.push(Instr::ISub(Val::Reg(Reg::RAX), Val::Imm(size)));
+            for idx in (0..values.len()).rev() {
+                v.push(Instr::Pop(Val::Reg(Reg::RCX)));
+                v.push(Instr::IMov(Val::RegOffset(Reg::RAX, 8 * idx as i32), Val::Reg(Reg::RCX)));
+            }
+            v.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::Imm(runtime::POINTER_TAG)));
+            Ok(v)
+        },
+        Expr::Lookup(pointer, field, span) => {
+            let pointer_ty = typecheck(pointer, variable_types, break_target.is_some())?;
+            let (idx, _) = field_of(pointer_ty, field, *span, variable_types)?;
+            let mut v = compile_to_instrs(pointer, stack_bindings, variable_types,
+                stack_counter, ctx, break_target)?;
+            v.append(&mut tag_check(Reg::RAX, &Type::Null));
+            v.append(&mut ctx.null_check(Reg::RAX));
+            // the offset takes the pointer's tag back off
+            v.push(Instr::IMov(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RAX, 8 * idx as i32 - runtime::POINTER_TAG as i32)));
+            Ok(v)
+        },
+        Expr::Update(pointer, field, value, span) => {
+            let pointer_ty = typecheck(pointer, variable_types, break_target.is_some())?;
+            let (idx, _) = field_of(pointer_ty, field, *span, variable_types)?;
+            let mut v = compile_to_instrs(pointer, stack_bindings.clone(), variable_types,
+                stack_counter, ctx, break_target)?;
+            v.push(Instr::Push(Val::Reg(Reg::RAX)));
+            v.append(&mut compile_to_instrs(value, stack_bindings, variable_types,
+                stack_counter + 8, ctx, break_target)?);
+            v.push(Instr::Pop(Val::Reg(Reg::RCX)));
+            v.append(&mut tag_check(Reg::RCX, &Type::Null));
+            v.append(&mut ctx.null_check(Reg::RCX));
+            // the update evaluates to the value stored
+            v.push(Instr::IMov(Val::RegOffset(Reg::RCX, 8 * idx as i32 - runtime::POINTER_TAG as i32), Val::Reg(Reg::RAX)));
+            Ok(v)
+        },
     }
 }
 
+// Compiles a function body behind its label. Parameters live above the saved rbp, the
+// registers its lets use and the return address; the result is returned in rax.
+pub fn compile_fun(def: &FunDef, variable_types: &HashMap<String, Type>,
+    ctx: &mut CompileCtx) -> Result<Vec<Instr>, CompileError> {
+    ctx.regs = ctx.pool_for(&def.body);
+    let saved = ctx.regs.saved().to_vec();
+    let mut stack_bindings = HashMap::new();
+    let mut fun_types = variable_types.clone();
+    for (idx, param) in def.params.iter().enumerate() {
+        let offset = 16 + 8 * (saved.len() + def.params.len() - 1 - idx) as i32;
+        stack_bindings.insert(param.clone(), Val::RegOffset(Reg::RBP, offset));
+        fun_types.insert(param.clone(), Type::Int);
+    }
+
+    let label_num = ctx.next_label();
+    let mut v = vec![Instr::Label(format!("fun_{}", def.name), 0)];
+    v.extend(saved.iter().map(|reg| Instr::Push(Val::Reg(*reg))));
+    v.extend([
+        Instr::Push(Val::Reg(Reg::RBP)),
+        Instr::IMov(Val::Reg(Reg::RBP), Val::Reg(Reg::RSP)),
+        // calls burn fuel like loop iterations, and deep recursion stops before the real stack does
+        Instr::ISub(ctx_slot(runtime::FUEL_OFFSET), Val::Imm(1)),
+        Instr::Jne("fun_fueled".to_string(), label_num),
+        Instr::IMov(Val::Reg(Reg::RDI), Val::Imm(RuntimeError::OutOfFuel.code())),
+        Instr::Jmp("snek_error_handler".to_string(), 0),
+        Instr::Label("fun_fueled".to_string(), label_num),
+        Instr::IMov(Val::Reg(Reg::RCX), ctx_slot(runtime::ENTRY_RSP_OFFSET)),
+        Instr::ISub(Val::Reg(Reg::RCX), Val::Reg(Reg::RSP)),
+        Instr::ICmp(Val::Reg(Reg::RCX), Val::Imm(runtime::STACK_LIMIT as i64)),
+        Instr::Jg("fun_stack_overflow".to_string(), label_num),
+    ]);
+    v.append(&mut compile_to_instrs(&def.body, stack_bindings, &mut fun_types, 8, ctx, None)?);
+    v.push(Instr::Pop(Val::Reg(Reg::RBP)));
+    v.extend(saved.iter().rev().map(|reg| Instr::Pop(Val::Reg(*reg))));
+    v.push(Instr::Ret());
+    v.push(Instr::Label("fun_stack_overflow".to_string(), label_num));
+    v.push(Instr::IMov(Val::Reg(Reg::RDI), Val::Imm(RuntimeError::StackOverflow.code())));
+    v.push(Instr::Jmp("snek_error_handler".to_string(), 0));
+    Ok(v)
+}
+
+// Types of the heap variables, the fields of every struct, and every function, in
+// definition order so that each function can call the ones before it and itself.
+pub fn global_types(defined_vars: &HashMap<String, i64>, structs: &[StructDef], funs: &[FunDef]) -> Result<HashMap<String, Type>, CompileError> {
+    let mut variable_types: HashMap<String, Type> = defined_vars.keys()
+        .map(|name| (name.clone(), Type::Int)).collect();
+    variable_types.insert("input".to_string(), Type::Int);
+    for def in structs {
+        variable_types.insert(format!("struct {}", def.name), Type::StructDef(def.fields.clone()));
+    }
+    for def in funs {
+        let fun_ty = typecheck_fun(def, &variable_types)?;
+        variable_types.insert(def.name.clone(), fun_ty);
+    }
+    Ok(variable_types)
+}
+
+// Typechecks and compiles a new function definition without running anything, so that
+// errors show up on the line that defines it rather than on the first line that calls it.
+// Gives back the function's instructions on their own.
+pub fn check_fun(def: &FunDef, structs: &[StructDef], funs: &[FunDef],
+    defined_vars: &HashMap<String, i64>) -> Result<Vec<Instr>, CompileError> {
+    let mut all_funs = funs.to_vec();
+    all_funs.push(def.clone());
+    let variable_types = global_types(defined_vars, structs, &all_funs)?;
+    let mut ctx = CompileCtx::new(defined_vars.clone(), &all_funs);
+    compile_fun(def, &variable_types, &mut ctx)
+}
+
+// Typechecks and compiles a whole program line, together with every function it may
+// call, into a function callable from Rust. Optimizations only run once the line and the
+// functions typecheck.
+pub fn compile_line(expression: &Expr, structs: &[StructDef], funs: &[FunDef],
+    defined_vars: &HashMap<String, i64>, opt: OptLevel) -> Result<Vec<Instr>, CompileError> {
+    let mut variable_types = global_types(defined_vars, structs, funs)?;
+    typecheck(expression, &variable_types, false)?;
+    let (expression, funs) = match opt {
+        OptLevel::Full => (optimize::fold(expression, defined_vars),
+            funs.iter().map(|def| optimize::fold_fun(def, defined_vars)).collect()),
+        _ => (expression.clone(), funs.to_vec()),
+    };
+    let mut ctx = CompileCtx::new(defined_vars.clone(), &funs);
+    ctx.alloc_regs = opt == OptLevel::Full;
+    ctx.regs = ctx.pool_for(&expression);
+    let saved = ctx.regs.saved().to_vec();
+    let mut instrs = compile_entry(compile_to_instrs(&expression, HashMap::new(), &mut variable_types,
+        8, &mut ctx, None)?, &saved);
+    for def in &funs {
+        instrs.append(&mut compile_fun(def, &variable_types, &mut ctx)?);
+    }
+    match opt {
+        OptLevel::None => Ok(instrs),
+        _ => Ok(optimize::peephole(instrs)),
+    }
+}
+
+// Wraps a compiled line into a function callable from Rust, taking the line's input in rdi
+// and the run's context in rsi. The stack pointer after the prologue is saved so that the
+// shared error handler can unwind straight back to it. The registers in `saved` and the
+// context register are Rust's to keep, so they are pushed before rbp, which leaves the frame
+// the stack limit is counted from where it was.
+pub fn compile_entry(mut body: Vec<Instr>, saved: &[Reg]) -> Vec<Instr> {
+    let mut instrs: Vec<Instr> = saved.iter().map(|reg| Instr::Push(Val::Reg(*reg))).collect();
+    instrs.extend([
+        Instr::Push(Val::Reg(CTX_REG)),
+        Instr::Push(Val::Reg(Reg::RBP)),
+        Instr::IMov(Val::Reg(Reg::RBP), Val::Reg(Reg::RSP)),
+        Instr::IMov(Val::Reg(CTX_REG), Val::Reg(Reg::RSI)),
+        Instr::IMov(ctx_slot(runtime::ENTRY_RSP_OFFSET), Val::Reg(Reg::RSP)),
+        Instr::IMov(ctx_slot(runtime::INPUT_OFFSET), Val::Reg(Reg::RDI)),
+    ]);
+    instrs.append(&mut body);
+    instrs.push(Instr::Label("snek_exit".to_string(), 0));
+    instrs.push(Instr::Pop(Val::Reg(Reg::RBP)));
+    instrs.push(Instr::Pop(Val::Reg(CTX_REG)));
+    instrs.extend(saved.iter().rev().map(|reg| Instr::Pop(Val::Reg(*reg))));
+    instrs.push(Instr::Ret());
+
+    // jumped to with the error code in rdi
+    instrs.push(Instr::Label("snek_error_handler".to_string(), 0));
+    instrs.push(Instr::IAnd(Val::Reg(Reg::RSP), Val::Imm(-16)));
+    instrs.push(Instr::IMov(Val::Reg(Reg::RSI), Val::Reg(CTX_REG)));
+    instrs.push(Instr::CallSnekErr());
+    instrs.push(Instr::IMov(Val::Reg(Reg::RSP), ctx_slot(runtime::ENTRY_RSP_OFFSET)));
+    instrs.push(Instr::Jmp("snek_exit".to_string(), 0));
+
+    // jumped to when a tag check fails
+    instrs.push(Instr::Label("snek_wrong_type".to_string(), 0));
+    instrs.push(Instr::IMov(Val::Reg(Reg::RDI), Val::Imm(RuntimeError::WrongType.code())));
+    instrs.push(Instr::Jmp("snek_error_handler".to_string(), 0));
+    instrs
+}
+
 
 fn reg_to_dynasm(r: &Reg) -> u8 {
     match r {
@@ -121,36 +582,51 @@ fn reg_to_dynasm(r: &Reg) -> u8 {
         Reg::RBX => Rq::RBX as u8,
         Reg::RDI => Rq::RDI as u8,
         Reg::RSI => Rq::RSI as u8,
+        Reg::RDX => Rq::RDX as u8,
         Reg::RSP => Rq::RSP as u8,
         Reg::RCX => Rq::RCX as u8,
     }
 }
 
-fn mov_to_asm(ops: &mut dynasmrt::x64::Assembler, dest: &Val, src: &Val) {
+// Only mov takes a 64-bit immediate; the compiler keeps the others small.
+fn imm32(n: i64) -> Result<i32, CompileError> {
+    i32::try_from(n).map_err(|_| CompileError::Assemble(format!("immediate {} does not fit in 32 bits", n)))
+}
+
+// an instruction with operands x64 has no encoding for
+fn unencodable(op: &str, operands: &[&Val]) -> CompileError {
+    CompileError::Assemble(format!("cannot assemble {} {:?}", op, operands))
+}
+
+fn mov_to_asm(ops: &mut dynasmrt::x64::Assembler, dest: &Val, src: &Val) -> Result<(), CompileError> {
     match (dest, src) {
         (Val::Reg(dest_reg), Val::Reg(src_reg)) => {
             dynasm!(ops; .arch x64; mov Rq(reg_to_dynasm(dest_reg)), Rq(reg_to_dynasm(src_reg)));
         }
-        (Val::Reg(dest_reg), Val::Imm(n)) => {
-            dynasm!(ops; .arch x64; mov Rq(reg_to_dynasm(dest_reg)), *n);
-        }
+        // values that fit are sign-extended from 32 bits, the rest need the 10-byte movabs
+        (Val::Reg(dest_reg), Val::Imm(n)) => match i32::try_from(*n) {
+            Ok(n) => dynasm!(ops; .arch x64; mov Rq(reg_to_dynasm(dest_reg)), n),
+            Err(_) => dynasm!(ops; .arch x64; mov Rq(reg_to_dynasm(dest_reg)), QWORD *n),
+        },
         (Val::Reg(dest_reg), Val::RegOffset(src_reg, offset)) => {
             dynasm!(ops; .arch x64; mov Rq(reg_to_dynasm(dest_reg)), [Rq(reg_to_dynasm(src_reg)) + *offset]);
         }
         (Val::RegOffset(dest_reg, offset), Val::Reg(src_reg)) => {
             dynasm!(ops; .arch x64; mov [Rq(reg_to_dynasm(dest_reg)) + *offset], Rq(reg_to_dynasm(src_reg)));
         }
-        _ => panic!("invalid mov"),
+        _ => return Err(unencodable("mov", &[dest, src])),
     }
+    Ok(())
 }
 
-fn add_to_asm(ops: &mut dynasmrt::x64::Assembler, dest: &Val, src: &Val) {
+fn add_to_asm(ops: &mut dynasmrt::x64::Assembler, dest: &Val, src: &Val) -> Result<(), CompileError> {
     match (dest, src) {
         (Val::Reg(dest_reg), Val::Reg(src_reg)) => {
             dynasm!(ops; .arch x64; add Rq(reg_to_dynasm(dest_reg)), Rq(reg_to_dynasm(src_reg)));
         }
         (Val::Reg(dest_reg), Val::Imm(n)) => {
-            dynasm!(ops; .arch x64; add Rq(reg_to_dynasm(dest_reg)), *n);
+            let n = imm32(*n)?;
+            dynasm!(ops; .arch x64; add Rq(reg_to_dynasm(dest_reg)), n);
         }
         (Val::Reg(dest_reg), Val::RegOffset(src_reg, offset)) => {
             dynasm!(ops; .arch x64; add Rq(reg_to_dynasm(dest_reg)), [Rq(reg_to_dynasm(src_reg)) + *offset]);
@@ -159,19 +635,22 @@ fn add_to_asm(ops: &mut dynasmrt::x64::Assembler, dest: &Val, src: &Val) {
             dynasm!(ops; .arch x64; add [Rq(reg_to_dynasm(dest_reg)) + *offset], Rq(reg_to_dynasm(src_reg)));
         }
         (Val::RegOffset(dest_reg, offset), Val::Imm(n)) => {
-            dynasm!(ops; .arch x64; add [Rq(reg_to_dynasm(dest_reg)) + *offset], (*n).try_into().unwrap());
+            let n = imm32(*n)?;
+            dynasm!(ops; .arch x64; add QWORD [Rq(reg_to_dynasm(dest_reg)) + *offset], n);
         }
-        _ => panic!("invalid add"),
+        _ => return Err(unencodable("add", &[dest, src])),
     }
+    Ok(())
 }
 
-fn sub_to_asm(ops: &mut dynasmrt::x64::Assembler, dest: &Val, src: &Val) {
+fn sub_to_asm(ops: &mut dynasmrt::x64::Assembler, dest: &Val, src: &Val) -> Result<(), CompileError> {
     match (dest, src) {
         (Val::Reg(dest_reg), Val::Reg(src_reg)) => {
             dynasm!(ops; .arch x64; sub Rq(reg_to_dynasm(dest_reg)), Rq(reg_to_dynasm(src_reg)));
         }
         (Val::Reg(dest_reg), Val::Imm(n)) => {
-            dynasm!(ops; .arch x64; sub Rq(reg_to_dynasm(dest_reg)), *n);
+            let n = imm32(*n)?;
+            dynasm!(ops; .arch x64; sub Rq(reg_to_dynasm(dest_reg)), n);
         }
         (Val::Reg(dest_reg), Val::RegOffset(src_reg, offset)) => {
             dynasm!(ops; .arch x64; sub Rq(reg_to_dynasm(dest_reg)), [Rq(reg_to_dynasm(src_reg)) + *offset]);
@@ -180,13 +659,15 @@ fn sub_to_asm(ops: &mut dynasmrt::x64::Assembler, dest: &Val, src: &Val) {
             dynasm!(ops; .arch x64; sub [Rq(reg_to_dynasm(dest_reg)) + *offset], Rq(reg_to_dynasm(src_reg)));
         }
         (Val::RegOffset(dest_reg, offset), Val::Imm(n)) => {
-            dynasm!(ops; .arch x64; sub [Rq(reg_to_dynasm(dest_reg)) + *offset], (*n).try_into().unwrap());
+            let n = imm32(*n)?;
+            dynasm!(ops; .arch x64; sub QWORD [Rq(reg_to_dynasm(dest_reg)) + *offset], n);
         }
-        _ => panic!("invalid add"),
+        _ => return Err(unencodable("sub", &[dest, src])),
     }
+    Ok(())
 }
 
-fn mul_to_asm(ops: &mut dynasmrt::x64::Assembler, dest: &Val, src: &Val) {
+fn mul_to_asm(ops: &mut dynasmrt::x64::Assembler, dest: &Val, src: &Val) -> Result<(), CompileError> {
     match (dest, src) {
         (Val::Reg(dest_reg), Val::Reg(src_reg)) => {
             dynasm!(ops; .arch x64; imul Rq(reg_to_dynasm(dest_reg)), Rq(reg_to_dynasm(src_reg)));
@@ -194,44 +675,233 @@ fn mul_to_asm(ops: &mut dynasmrt::x64::Assembler, dest: &Val, src: &Val) {
         (Val::Reg(dest_reg), Val::RegOffset(src_reg, offset)) => {
             dynasm!(ops; .arch x64; imul Rq(reg_to_dynasm(dest_reg)), [Rq(reg_to_dynasm(src_reg)) + *offset]);
         }
-        _ => panic!("invalid add"),
+        _ => return Err(unencodable("imul", &[dest, src])),
     }
+    Ok(())
 }
 
-fn pop_to_asm(ops: &mut dynasmrt::x64::Assembler, val: &Val) {
+fn div_to_asm(ops: &mut dynasmrt::x64::Assembler, src: &Val) -> Result<(), CompileError> {
+    match src {
+        Val::Reg(src_reg) => {
+            dynasm!(ops; .arch x64; idiv Rq(reg_to_dynasm(src_reg)));
+        }
+        _ => return Err(unencodable("idiv", &[src])),
+    }
+    Ok(())
+}
+
+fn and_to_asm(ops: &mut dynasmrt::x64::Assembler, dest: &Val, src: &Val) -> Result<(), CompileError> {
+    match (dest, src) {
+        (Val::Reg(dest_reg), Val::Reg(src_reg)) => {
+            dynasm!(ops; .arch x64; and Rq(reg_to_dynasm(dest_reg)), Rq(reg_to_dynasm(src_reg)));
+        }
+        (Val::Reg(dest_reg), Val::Imm(n)) => {
+            let n = imm32(*n)?;
+            dynasm!(ops; .arch x64; and Rq(reg_to_dynasm(dest_reg)), n);
+        }
+        _ => return Err(unencodable("and", &[dest, src])),
+    }
+    Ok(())
+}
+
+fn sar_to_asm(ops: &mut dynasmrt::x64::Assembler, dest: &Val, src: &Val) -> Result<(), CompileError> {
+    match (dest, src) {
+        (Val::Reg(dest_reg), Val::Imm(n)) => {
+            let n = i8::try_from(*n).map_err(|_| unencodable("sar", &[dest, src]))?;
+            dynasm!(ops; .arch x64; sar Rq(reg_to_dynasm(dest_reg)), n);
+        }
+        _ => return Err(unencodable("sar", &[dest, src])),
+    }
+    Ok(())
+}
+
+fn test_to_asm(ops: &mut dynasmrt::x64::Assembler, dest: &Val, src: &Val) -> Result<(), CompileError> {
+    match (dest, src) {
+        (Val::Reg(dest_reg), Val::Imm(n)) => {
+            let n = imm32(*n)?;
+            dynasm!(ops; .arch x64; test Rq(reg_to_dynasm(dest_reg)), n);
+        }
+        _ => return Err(unencodable("test", &[dest, src])),
+    }
+    Ok(())
+}
+
+fn cmp_to_asm(ops: &mut dynasmrt::x64::Assembler, dest: &Val, src: &Val) -> Result<(), CompileError> {
+    match (dest, src) {
+        (Val::Reg(dest_reg), Val::Reg(src_reg)) => {
+            dynasm!(ops; .arch x64; cmp Rq(reg_to_dynasm(dest_reg)), Rq(reg_to_dynasm(src_reg)));
+        }
+        (Val::Reg(dest_reg), Val::Imm(n)) => {
+            let n = imm32(*n)?;
+            dynasm!(ops; .arch x64; cmp Rq(reg_to_dynasm(dest_reg)), n);
+        }
+        (Val::Reg(dest_reg), Val::RegOffset(src_reg, offset)) => {
+            dynasm!(ops; .arch x64; cmp Rq(reg_to_dynasm(dest_reg)), [Rq(reg_to_dynasm(src_reg)) + *offset]);
+        }
+        _ => return Err(unencodable("cmp", &[dest, src])),
+    }
+    Ok(())
+}
+
+fn cmov_to_asm(ops: &mut dynasmrt::x64::Assembler, i: &Instr) -> Result<(), CompileError> {
+    match i {
+        Instr::ICMovg(Val::Reg(dest_reg), Val::Reg(src_reg)) => {
+            dynasm!(ops; .arch x64; cmovg Rq(reg_to_dynasm(dest_reg)), Rq(reg_to_dynasm(src_reg)));
+        }
+        Instr::ICMovl(Val::Reg(dest_reg), Val::Reg(src_reg)) => {
+            dynasm!(ops; .arch x64; cmovl Rq(reg_to_dynasm(dest_reg)), Rq(reg_to_dynasm(src_reg)));
+        }
+        Instr::ICMovge(Val::Reg(dest_reg), Val::Reg(src_reg)) => {
+            dynasm!(ops; .arch x64; cmovge Rq(reg_to_dynasm(dest_reg)), Rq(reg_to_dynasm(src_reg)));
+        }
+        Instr::ICMovle(Val::Reg(dest_reg), Val::Reg(src_reg)) => {
+            dynasm!(ops; .arch x64; cmovle Rq(reg_to_dynasm(dest_reg)), Rq(reg_to_dynasm(src_reg)));
+        }
+        Instr::ICMove(Val::Reg(dest_reg), Val::Reg(src_reg)) => {
+            dynasm!(ops; .arch x64; cmove Rq(reg_to_dynasm(dest_reg)), Rq(reg_to_dynasm(src_reg)));
+        }
+        _ => return Err(CompileError::Assemble(format!("cannot assemble {:?}", i))),
+    }
+    Ok(())
+}
+
+// labels are named by the compiler; dynasm needs a dynamic label per distinct name
+fn label_to_dynasm(ops: &mut dynasmrt::x64::Assembler, labels: &mut StdHashMap<String, DynamicLabel>,
+    name: &str, num: i32) -> DynamicLabel {
+    *labels.entry(format!("{}{}", name, num)).or_insert_with(|| ops.new_dynamic_label())
+}
+
+fn pop_to_asm(ops: &mut dynasmrt::x64::Assembler, val: &Val) -> Result<(), CompileError> {
     match val {
         Val::Reg(r) => {
             dynasm!(ops; .arch x64; pop Rq(reg_to_dynasm(r)));
         }
-        _ => panic!("invalid pop"),
+        _ => return Err(unencodable("pop", &[val])),
     }
+    Ok(())
 }
-fn push_to_asm(ops: &mut dynasmrt::x64::Assembler, val: &Val) {
+fn push_to_asm(ops: &mut dynasmrt::x64::Assembler, val: &Val) -> Result<(), CompileError> {
     match val {
         Val::Reg(r) => {
             dynasm!(ops; .arch x64; push Rq(reg_to_dynasm(r)));
         }
         Val::Imm(n) => {
-            dynasm!(ops; .arch x64; push *n);
+            let n = imm32(*n)?;
+            dynasm!(ops; .arch x64; push n);
         }
-        _ => panic!("invalid pop"),
+        _ => return Err(unencodable("push", &[val])),
     }
+    Ok(())
 }
 
-fn instr_to_asm(i: &Instr, ops: &mut dynasmrt::x64::Assembler) {
+fn instr_to_asm(i: &Instr, ops: &mut dynasmrt::x64::Assembler, labels: &mut StdHashMap<String, DynamicLabel>)
+    -> Result<(), CompileError> {
     match i {
-        Instr::IMov(dest, src) => mov_to_asm(ops, dest, src),
-        Instr::IAdd(dest, src) => add_to_asm(ops, dest, src),
-        Instr::ISub(dest, src) => sub_to_asm(ops, dest, src),
-        Instr::IMul(dest, src) => mul_to_asm(ops, dest, src),
-        Instr::Pop(val) => pop_to_asm(ops, val),
-        Instr::Push(val) => push_to_asm(ops, val),
-        _ => {
-            panic!("Instruction not supported");
+        Instr::IMov(dest, src) => mov_to_asm(ops, dest, src)?,
+        Instr::IAdd(dest, src) => add_to_asm(ops, dest, src)?,
+        Instr::ISub(dest, src) => sub_to_asm(ops, dest, src)?,
+        Instr::IMul(dest, src) => mul_to_asm(ops, dest, src)?,
+        Instr::IDiv(src) => div_to_asm(ops, src)?,
+        Instr::ICqo() => {
+            dynasm!(ops; .arch x64; cqo);
+        }
+        Instr::IAnd(dest, src) => and_to_asm(ops, dest, src)?,
+        Instr::ISar(dest, src) => sar_to_asm(ops, dest, src)?,
+        Instr::ICmp(dest, src) => cmp_to_asm(ops, dest, src)?,
+        Instr::ITest(dest, src) => test_to_asm(ops, dest, src)?,
+        Instr::ICMovg(..) | Instr::ICMovl(..) | Instr::ICMovge(..) | Instr::ICMovle(..)
+            | Instr::ICMove(..) => cmov_to_asm(ops, i)?,
+        Instr::Label(name, num) => {
+            let label = label_to_dynasm(ops, labels, name, *num);
+            dynasm!(ops; .arch x64; =>label);
+        }
+        Instr::Jmp(name, num) => {
+            let label = label_to_dynasm(ops, labels, name, *num);
+            dynasm!(ops; .arch x64; jmp =>label);
+        }
+        Instr::Je(name, num) => {
+            let label = label_to_dynasm(ops, labels, name, *num);
+            dynasm!(ops; .arch x64; je =>label);
+        }
+        Instr::Jne(name, num) => {
+            let label = label_to_dynasm(ops, labels, name, *num);
+            dynasm!(ops; .arch x64; jne =>label);
+        }
+        Instr::Jg(name, num) => {
+            let label = label_to_dynasm(ops, labels, name, *num);
+            dynasm!(ops; .arch x64; jg =>label);
+        }
+        Instr::Jle(name, num) => {
+            let label = label_to_dynasm(ops, labels, name, *num);
+            dynasm!(ops; .arch x64; jle =>label);
+        }
+        Instr::Jno(name, num) => {
+            let label = label_to_dynasm(ops, labels, name, *num);
+            dynasm!(ops; .arch x64; jno =>label);
         }
+        Instr::Call(name, num) => {
+            let label = label_to_dynasm(ops, labels, name, *num);
+            dynasm!(ops; .arch x64; call =>label);
+        }
+        // unlike runtime/start.rs, our snek_error returns; the caller unwinds afterwards
+        Instr::CallSnekErr() => {
+            dynasm!(ops; .arch x64
+                ; mov rax, QWORD runtime::symbol_address("snek_error")
+                ; call rax);
+        }
+        Instr::CallSnekPrint() => {
+            dynasm!(ops; .arch x64
+                ; mov rax, QWORD runtime::symbol_address("snek_print")
+                ; call rax);
+        }
+        Instr::Pop(val) => pop_to_asm(ops, val)?,
+        Instr::Push(val) => push_to_asm(ops, val)?,
+        Instr::Ret() => {
+            dynasm!(ops; .arch x64; ret);
+        }
+    }
+    Ok(())
+}
+
+// Renders a compiled line as a nasm file that runtime/start.rs can link against. The
+// runtime's snek_error exits the process, so the unwinding data only needs to exist. The
+// executable runs a single line, so its context is a block of data laid out like
+// runtime::RunCtx, which the entry point passes on where the JIT passes its own.
+pub fn instrs_to_nasm(cmds: &[Instr]) -> String {
+    let mut slots = [
+        (runtime::FUEL_OFFSET, runtime::FUEL.to_string()),
+        (runtime::ENTRY_RSP_OFFSET, "0".to_string()),
+        (runtime::ERROR_OFFSET, "0".to_string()),
+        (runtime::INPUT_OFFSET, "0".to_string()),
+        (runtime::HEAP_PTR_OFFSET, "snek_heap".to_string()),
+        (runtime::HEAP_END_OFFSET, format!("snek_heap + {}", 8 * runtime::HEAP_WORDS)),
+    ];
+    slots.sort();
+    let mut asm = String::new();
+    asm.push_str("section .data\n");
+    asm.push_str("snek_ctx:\n");
+    for (_, value) in slots {
+        asm.push_str(&format!("dq {}\n", value));
+    }
+    asm.push_str("section .bss\n");
+    // pointers keep their tag in the low bits, so structs have to be 8-aligned
+    asm.push_str("alignb 8\n");
+    asm.push_str(&format!("snek_heap: resq {}\n", runtime::HEAP_WORDS));
+    asm.push_str("section .text\n");
+    asm.push_str("extern snek_error\n");
+    asm.push_str("extern snek_print\n");
+    asm.push_str("global our_code_starts_here\n");
+    // runtime/start.rs shows pointers relative to it
+    asm.push_str("global snek_heap\n");
+    asm.push_str("our_code_starts_here:\n");
+    asm.push_str("lea rsi, [rel snek_ctx]\n");
+    for cmd in cmds {
+        asm.push_str(&utils::instr_to_str(cmd));
     }
+    asm
 }
 
-pub fn instrs_to_asm(cmds: &Vec<Instr>, ops: &mut dynasmrt::x64::Assembler) {
-    cmds.iter().for_each(|c| instr_to_asm(c, ops))
+pub fn instrs_to_asm(cmds: &[Instr], ops: &mut dynasmrt::x64::Assembler) -> Result<(), CompileError> {
+    let mut labels = StdHashMap::new();
+    cmds.iter().try_for_each(|c| instr_to_asm(c, ops, &mut labels))
 }
@@ -1,6 +1,8 @@
 use piston_window::types::Color;
 use piston_window::*;
 
+use super::runtime::Value;
+
 const BLOCK_SIZE: f64 = 25.0;
 
 pub fn to_gui_coord(game_coord: i32) -> f64 {
@@ -14,7 +16,7 @@ pub fn to_gui_coord_u32(game_coord: i32) -> u32 {
 pub fn draw_block(color: Color, op: &str, x: i32, y: i32, con: &Context, g: &mut G2d, font: &mut Glyphs) {
     let gui_x = to_gui_coord(x);
     let gui_y = to_gui_coord(y);
-    let new_draw_state = con.draw_state.clone();
+    let new_draw_state = con.draw_state;
     rectangle(color, [gui_x, gui_y,
         BLOCK_SIZE, BLOCK_SIZE], con.transform, g);
 
@@ -46,7 +48,7 @@ pub fn draw_rectange(color: Color, start_x: i32, start_y: i32, width: i32, heigh
 pub fn draw_text(text: String, color: Color, start_x: f64, start_y: f64, con: &Context, g: &mut G2d, font: &mut Glyphs) {
     // let gui_x = to_gui_coord(start_x);
     // let gui_y = to_gui_coord(start_y);
-    let new_draw_state = con.draw_state.clone();
+    let new_draw_state = con.draw_state;
 
     text::Text::new_color(color, 18) // Text color and font size
         .draw(
@@ -70,7 +72,7 @@ pub fn draw_blocks_count(blocks_num: i64, con: &Context, g: &mut G2d, font: &mut
 
     let gui_x = width + to_gui_coord(1);
     let gui_y = 30.0;
-    let new_draw_state = con.draw_state.clone();
+    let new_draw_state = con.draw_state;
 
     text::Text::new_color([1.0, 1.0, 1.0, 1.0], 15) // Text color and font size
         .draw(
@@ -85,11 +87,12 @@ pub fn draw_blocks_count(blocks_num: i64, con: &Context, g: &mut G2d, font: &mut
         });
 }
 
-pub fn draw_program_line(program_line: String, result: Option<i32>, x: i32, y: i32, con: &Context, g: &mut G2d, font: &mut Glyphs) {
+#[allow(clippy::too_many_arguments)]
+pub fn draw_program_line(program_line: String, result: Option<Value>, error: Option<String>, x: i32, y: i32, con: &Context, g: &mut G2d, font: &mut Glyphs) {
     let gui_x = to_gui_coord(x);
     let gui_y = to_gui_coord(y);
 
-    let new_draw_state = con.draw_state.clone();
+    let new_draw_state = con.draw_state;
 
     let font_size = 20;
 
@@ -113,24 +116,70 @@ pub fn draw_program_line(program_line: String, result: Option<i32>, x: i32, y: i
         .sum();
 
     // if it exists, draw the result in blue
-    match result {
-        Some(res) => {
-            let mut text_to_draw = "".to_string();
-            text_to_draw.push_str(" -> ");
-            text_to_draw.push_str(&res.to_string());
-            text::Text::new_color([0.0, 0.0, 1.0, 1.0], font_size) // Text color and font size
-            .draw(
-                &text_to_draw, // The text to display
-                font,
-                &new_draw_state,
-                con.transform.trans(gui_x + width, gui_y),
-                g,
-            )
+    if let Some(res) = result {
+        let mut text_to_draw = "".to_string();
+        text_to_draw.push_str(" -> ");
+        text_to_draw.push_str(&res.to_string());
+        text::Text::new_color([0.0, 0.0, 1.0, 1.0], font_size) // Text color and font size
+        .draw(
+            &text_to_draw, // The text to display
+            font,
+            &new_draw_state,
+            con.transform.trans(gui_x + width, gui_y),
+            g,
+        )
+        .unwrap_or_else(|e| {
+            eprintln!("Error drawing text: {:?}", e);  // Print error if text drawing fails
+        });
+    }
+
+    // if the line failed to compile, draw the error in red
+    if let Some(err) = error {
+        let mut text_to_draw = "".to_string();
+        text_to_draw.push_str(" !! ");
+        text_to_draw.push_str(&err);
+        text::Text::new_color([0.91, 0.30, 0.24, 1.0], font_size) // Text color and font size
+        .draw(
+            &text_to_draw, // The text to display
+            font,
+            &new_draw_state,
+            con.transform.trans(gui_x + width, gui_y),
+            g,
+        )
+        .unwrap_or_else(|e| {
+            eprintln!("Error drawing text: {:?}", e);  // Print error if text drawing fails
+        });
+    }
+
+}
+
+// Draws rows of small text in columns inside a dark box, filling each column before the
+// next. Gives back how many rows fit, so the caller can say what was left out.
+#[allow(clippy::too_many_arguments)]
+pub fn draw_text_columns(title: &str, rows: &[String], x: i32, y: i32, width: i32, height: i32, columns: usize, con: &Context, g: &mut G2d, font: &mut Glyphs) -> usize {
+    draw_rectange([0.08, 0.08, 0.12, 0.95], x, y, width, height, con, g);
+    let new_draw_state = con.draw_state;
+    let font_size = 14;
+    let row_height = 17.0;
+    let gui_x = to_gui_coord(x) + 10.0;
+    let gui_y = to_gui_coord(y) + 20.0;
+    text::Text::new_color([1.0, 1.0, 1.0, 1.0], 16)
+        .draw(title, font, &new_draw_state, con.transform.trans(gui_x, gui_y), g)
+        .unwrap_or_else(|e| {
+            eprintln!("Error drawing text: {:?}", e);  // Print error if text drawing fails
+        });
+
+    let column_width = (to_gui_coord(width) - 20.0) / columns as f64;
+    let rows_per_column = ((to_gui_coord(height) - 35.0) / row_height).max(0.0) as usize;
+    let shown = rows.len().min(rows_per_column * columns);
+    for (idx, row) in rows[..shown].iter().enumerate() {
+        let row_x = gui_x + column_width * (idx / rows_per_column) as f64;
+        let row_y = gui_y + row_height * (1 + idx % rows_per_column) as f64;
+        text::Text::new_color([0.75, 0.9, 0.75, 1.0], font_size)
+            .draw(row, font, &new_draw_state, con.transform.trans(row_x, row_y), g)
             .unwrap_or_else(|e| {
                 eprintln!("Error drawing text: {:?}", e);  // Print error if text drawing fails
             });
-        },
-        None => ()
     }
-
+    shown
 }
@@ -0,0 +1,391 @@
+use im::HashMap;
+use std::collections::{HashMap as StdHashMap, VecDeque};
+use std::sync::Arc;
+
+use crate::compile::global_types;
+use crate::runtime::{self, RuntimeError, Value};
+use crate::utils::{typecheck, CompileError, Expr, FunDef, Op1, Op2, SnekError, Span, StructDef, Type};
+
+// A tree-walking interpreter with the semantics of the jitted code, used as an oracle for
+// compile_to_instrs. Values are kept untagged, with booleans as 1 and 0, but ints overflow
+// outside of the jitted code's 63 bits, and fuel and the stack limit run out at exactly the
+// point they would in the jitted code. Like compile_line, it typechecks the line first.
+pub struct Env {
+    // heap variables (x0..xn)
+    defined_vars: HashMap<String, i64>,
+    structs: HashMap<String, StructDef>,
+    // shared so that a call does not copy the body, whose nodes key `node_types`
+    funs: HashMap<String, Arc<FunDef>>,
+    // types of the heap variables, structs and functions, which print needs to show a value
+    types: HashMap<String, Type>,
+    // types of the nodes typechecked so far, since loops reach the same ones again and again
+    node_types: StdHashMap<usize, Type>,
+    fuel: i64,
+    // Words allocated so far. A pointer is 8 more than the byte offset of its first word,
+    // so that null stays 0, and remembers which struct it points to.
+    heap: Vec<i64>,
+    pointer_types: HashMap<i64, String>,
+    output: VecDeque<String>,
+    input: i64,
+    // how many registers the current frame has left for let bindings, which only matters
+    // for how much stack the jitted code would be using
+    free_regs: usize,
+}
+
+impl Env {
+    pub fn new(defined_vars: HashMap<String, i64>, structs: &[StructDef], funs: &[FunDef]) -> Env {
+        Env {
+            // a program that does not typecheck fails later, where the jitted code would not have run
+            types: global_types(&defined_vars, structs, funs).unwrap_or_default(),
+            node_types: StdHashMap::new(),
+            defined_vars,
+            structs: structs.iter().map(|def| (def.name.clone(), def.clone())).collect(),
+            funs: funs.iter().map(|def| (def.name.clone(), Arc::new(def.clone()))).collect(),
+            fuel: runtime::FUEL,
+            heap: vec![],
+            pointer_types: HashMap::new(),
+            output: VecDeque::new(),
+            input: 0,
+            free_regs: 0,
+        }
+    }
+
+    pub fn set_input(&mut self, input: i64) {
+        self.input = input;
+    }
+
+    // what the last line printed, formatted and cut short like the jitted code's output
+    pub fn output(&self) -> Vec<String> {
+        self.output.iter().cloned().collect()
+    }
+
+    // The type of a subexpression, given the locals in scope there. Scopes are lexical, so
+    // a node has the same type every time it is reached.
+    fn type_of(&mut self, e: &Expr, locals: &[(String, i64, Type)], in_loop: bool) -> Result<Type, CompileError> {
+        if let Some(ty) = self.node_types.get(&(e as *const Expr as usize)) {
+            return Ok(ty.clone());
+        }
+        let mut types = self.types.clone();
+        for (name, _, ty) in locals {
+            types.insert(name.clone(), ty.clone());
+        }
+        let ty = typecheck(e, &types, in_loop)?;
+        self.node_types.insert(e as *const Expr as usize, ty.clone());
+        Ok(ty)
+    }
+
+    // the heap word holding a field of the struct a pointer points to
+    fn field_slot(&self, pointer: i64, field: &str, span: Span) -> Result<usize, Unwind> {
+        if pointer == 0 {
+            return Err(RuntimeError::NullDereference.into());
+        }
+        let def = &self.structs[&self.pointer_types[&pointer]];
+        match def.fields.iter().position(|(name, _)| name == field) {
+            Some(idx) => Ok((pointer / 8 - 1) as usize + idx),
+            None => Err(CompileError::UnknownField(field.to_string(), span).into()),
+        }
+    }
+}
+
+// how the evaluation of a subexpression stopped early
+enum Unwind {
+    Break(i64),
+    Error(SnekError),
+}
+
+impl From<CompileError> for Unwind {
+    fn from(error: CompileError) -> Self {
+        Unwind::Error(error.into())
+    }
+}
+
+impl From<RuntimeError> for Unwind {
+    fn from(error: RuntimeError) -> Self {
+        Unwind::Error(error.into())
+    }
+}
+
+// Runs one program line with a full tank of fuel and an empty heap, like a single call
+// of the jitted code.
+pub fn eval(e: &Expr, env: &mut Env) -> Result<Value, SnekError> {
+    env.fuel = runtime::FUEL;
+    env.heap.clear();
+    env.pointer_types.clear();
+    env.output.clear();
+    env.node_types.clear();
+    env.free_regs = regs_for(e);
+    let ty = env.type_of(e, &[], false)?;
+    match eval_expr(e, &mut vec![], 8, 0, false, env) {
+        Ok(value) => Ok(to_value(value, &ty)),
+        Err(Unwind::Error(error)) => Err(error),
+        // typecheck keeps breaks inside loops, so this is only reached for unchecked lines
+        Err(Unwind::Break(_)) => unreachable!("break escaped its loop"),
+    }
+}
+
+// ints that do not fit once tagged overflow too
+fn checked(value: Option<i64>) -> Result<i64, Unwind> {
+    value.filter(|value| runtime::encode_int(*value).is_some())
+        .ok_or(Unwind::from(RuntimeError::Overflow))
+}
+
+// pointers here start 8 bytes past the start of the heap
+fn to_value(value: i64, ty: &Type) -> Value {
+    match ty {
+        Type::Bool => Value::Bool(value != 0),
+        Type::Struct(_) | Type::Null if value == 0 => Value::Null,
+        Type::Struct(_) | Type::Null => Value::Struct(value / 8 - 1),
+        _ => Value::Int(value),
+    }
+}
+
+// The jitted code keeps let bindings in the callee-saved rbx, r12, r13 and r14 while it has
+// them, and saves every one a function body or line can use at its start. This is worked out
+// here on its own rather than asked of the register allocator, so that a mistake there shows
+// up as a difference in stack depth.
+const LET_REGS: usize = 4;
+
+// whether `name` is read or set anywhere in `e`, shadowed or not
+fn uses(e: &Expr, name: &str) -> bool {
+    match e {
+        Expr::Number(_) | Expr::Boolean(_) | Expr::Null => false,
+        Expr::Id(x, _) => x == name,
+        Expr::Set(x, value, _) => x == name || uses(value, name),
+        Expr::UnOp(_, e, _) | Expr::Break(e, _) | Expr::Print(e, _) | Expr::Lookup(e, _, _) => uses(e, name),
+        Expr::BinOp(_, e1, e2, _) | Expr::RepeatUntil(e1, e2, _) | Expr::Update(e1, _, e2, _) => {
+            uses(e1, name) || uses(e2, name)
+        },
+        Expr::If(cond, thn, els, _) => [cond, thn, els].iter().any(|e| uses(e, name)),
+        Expr::Let(bindings, body, _) => bindings.iter().any(|(_, value)| uses(value, name)) || uses(body, name),
+        Expr::Call(_, args, _) | Expr::Alloc(_, args, _) => args.iter().any(|arg| uses(arg, name)),
+    }
+}
+
+// whether a binding is read or set once the let's bindings up to `idx` are done
+fn needed_after(name: &str, bindings: &[(String, Expr)], idx: usize, body: &Expr) -> bool {
+    bindings[idx + 1..].iter().any(|(_, value)| uses(value, name)) || uses(body, name)
+}
+
+// the most bindings of the lets in `e` that can be in registers at once
+fn lets_at_once(e: &Expr) -> usize {
+    match e {
+        Expr::Number(_) | Expr::Boolean(_) | Expr::Null | Expr::Id(..) => 0,
+        Expr::Let(bindings, body, _) => {
+            let values = bindings.iter().enumerate().map(|(idx, (_, value))| idx + lets_at_once(value));
+            values.fold(bindings.len() + lets_at_once(body), usize::max)
+        },
+        Expr::UnOp(_, e, _) | Expr::Break(e, _) | Expr::Print(e, _) | Expr::Lookup(e, _, _) | Expr::Set(_, e, _) => {
+            lets_at_once(e)
+        },
+        Expr::BinOp(_, e1, e2, _) | Expr::RepeatUntil(e1, e2, _) | Expr::Update(e1, _, e2, _) => {
+            lets_at_once(e1).max(lets_at_once(e2))
+        },
+        Expr::If(cond, thn, els, _) => lets_at_once(cond).max(lets_at_once(thn)).max(lets_at_once(els)),
+        Expr::Call(_, args, _) | Expr::Alloc(_, args, _) => args.iter().map(lets_at_once).max().unwrap_or(0),
+    }
+}
+
+// the registers a function body or line saves, and so has for its bindings
+fn regs_for(body: &Expr) -> usize {
+    lets_at_once(body).min(LET_REGS)
+}
+
+// `locals` are the let bindings and parameters of the current function with their types,
+// innermost last.
+// `stack_counter` is the same as in compile_to_instrs and `depth` is how far the current
+// frame's rbp is below the entry frame's, which is what the stack limit is checked against.
+fn eval_expr(e: &Expr, locals: &mut Vec<(String, i64, Type)>, stack_counter: i32, depth: i32,
+    in_loop: bool, env: &mut Env) -> Result<i64, Unwind> {
+    match e {
+        Expr::Number(n) => checked(Some(*n)),
+        Expr::Boolean(b) => Ok(*b as i64),
+        Expr::Id(x, span) => {
+            match locals.iter().rev().find(|(name, _, _)| name == x) {
+                Some((_, value, _)) => Ok(*value),
+                None => match env.defined_vars.get(x) {
+                    Some(value) => checked(Some(*value)),
+                    None if x == "input" => checked(Some(env.input)),
+                    None => Err(CompileError::UnboundIdentifier(x.clone(), *span).into()),
+                },
+            }
+        },
+        Expr::UnOp(op, subexpr, _) => {
+            let value = eval_expr(subexpr, locals, stack_counter, depth, in_loop, env)?;
+            match op {
+                Op1::Add1 => checked(value.checked_add(1)),
+                Op1::Sub1 => checked(value.checked_sub(1)),
+            }
+        },
+        Expr::BinOp(op, subexpr1, subexpr2, _) => {
+            let value1 = eval_expr(subexpr1, locals, stack_counter, depth, in_loop, env)?;
+            let value2 = eval_expr(subexpr2, locals, stack_counter + 8, depth, in_loop, env)?;
+            match op {
+                Op2::Plus => checked(value1.checked_add(value2)),
+                Op2::Minus => checked(value1.checked_sub(value2)),
+                Op2::Times => checked(value1.checked_mul(value2)),
+                Op2::Divide | Op2::Modulo if value2 == 0 => Err(RuntimeError::DivideByZero.into()),
+                Op2::Divide => checked(value1.checked_div(value2)),
+                Op2::Modulo => checked(value1.checked_rem(value2)),
+                Op2::Greater => Ok((value1 > value2) as i64),
+                Op2::Less => Ok((value1 < value2) as i64),
+                Op2::GreaterEqual => Ok((value1 >= value2) as i64),
+                Op2::LessEqual => Ok((value1 <= value2) as i64),
+                Op2::Equal => Ok((value1 == value2) as i64),
+            }
+        },
+        Expr::Let(bindings, body, span) => {
+            let scope_start = locals.len();
+            let mut new_stack_counter = stack_counter;
+            // the bindings of this let that hold a register
+            let mut in_regs: Vec<usize> = vec![];
+            for (idx, (name, value_expr)) in bindings.iter().enumerate() {
+                if bindings[..idx].iter().any(|(other, _)| other == name) {
+                    return Err(CompileError::DuplicateBinding(name.clone(), *span).into());
+                }
+                let ty = env.type_of(value_expr, locals, in_loop)?;
+                let value = eval_expr(value_expr, locals, new_stack_counter, depth, in_loop, env)?;
+                locals.push((name.clone(), value, ty));
+                // earlier bindings that are not needed anymore give their registers back, then
+                // this one takes one if it is needed and one is left, or else goes on the stack
+                in_regs.retain(|other| {
+                    let needed = needed_after(&bindings[*other].0, bindings, idx, body);
+                    env.free_regs += !needed as usize;
+                    needed
+                });
+                if needed_after(name, bindings, idx, body) {
+                    if env.free_regs > 0 {
+                        env.free_regs -= 1;
+                        in_regs.push(idx);
+                    } else {
+                        new_stack_counter += 8;
+                    }
+                }
+            }
+            // a break out of the body leaves its bindings behind; the loop drops them
+            let value = eval_expr(body, locals, new_stack_counter, depth, in_loop, env)?;
+            env.free_regs += in_regs.len();
+            locals.truncate(scope_start);
+            Ok(value)
+        },
+        Expr::Set(var_name, value_expr, span) => {
+            let value = eval_expr(value_expr, locals, stack_counter, depth, in_loop, env)?;
+            // like the compiled code, only stack variables can be set
+            match locals.iter_mut().rev().find(|(name, _, _)| name == var_name) {
+                Some((_, slot, _)) => {
+                    *slot = value;
+                    Ok(value)
+                },
+                None => Err(CompileError::UnboundIdentifier(var_name.clone(), *span).into()),
+            }
+        },
+        Expr::If(cond, thn, els, _) => {
+            match eval_expr(cond, locals, stack_counter, depth, in_loop, env)? {
+                0 => eval_expr(els, locals, stack_counter, depth, in_loop, env),
+                _ => eval_expr(thn, locals, stack_counter, depth, in_loop, env),
+            }
+        },
+        Expr::RepeatUntil(body, cond, _) => {
+            let scope_start = locals.len();
+            let free_regs = env.free_regs;
+            loop {
+                let iteration = eval_expr(body, locals, stack_counter, depth, true, env)
+                    .and_then(|value| {
+                        let done = eval_expr(cond, locals, stack_counter + 8, depth, true, env)?;
+                        Ok((value, done))
+                    });
+                match iteration {
+                    Ok((_, 0)) => {},
+                    Ok((value, _)) => return Ok(value),
+                    Err(Unwind::Break(value)) => {
+                        locals.truncate(scope_start);
+                        env.free_regs = free_regs;
+                        return Ok(value);
+                    },
+                    Err(error) => return Err(error),
+                }
+                env.fuel -= 1;
+                if env.fuel == 0 {
+                    return Err(RuntimeError::OutOfFuel.into());
+                }
+            }
+        },
+        Expr::Break(value, span) => {
+            if !in_loop {
+                return Err(CompileError::BreakOutsideLoop(*span).into());
+            }
+            let value = eval_expr(value, locals, stack_counter, depth, in_loop, env)?;
+            Err(Unwind::Break(value))
+        },
+        Expr::Call(name, args, span) => {
+            let def = match env.funs.get(name) {
+                None => return Err(CompileError::UnboundIdentifier(name.clone(), *span).into()),
+                Some(def) if def.params.len() != args.len() => {
+                    return Err(CompileError::WrongArity(name.clone(), *span).into());
+                },
+                Some(def) => def.clone(),
+            };
+            let mut params = Vec::new();
+            for (idx, (param, arg)) in def.params.iter().zip(args.iter()).enumerate() {
+                let value = eval_expr(arg, locals, stack_counter + 8 * idx as i32, depth, in_loop, env)?;
+                params.push((param.clone(), value, Type::Int));
+            }
+            // the callee's rbp sits below the arguments, the return address, the registers the
+            // callee saves and the saved rbp
+            let callee_regs = regs_for(&def.body);
+            let callee_depth = depth + (stack_counter - 8) + 8 * args.len() as i32 + 16
+                + 8 * callee_regs as i32;
+            env.fuel -= 1;
+            if env.fuel == 0 {
+                return Err(RuntimeError::OutOfFuel.into());
+            }
+            if callee_depth > runtime::STACK_LIMIT {
+                return Err(RuntimeError::StackOverflow.into());
+            }
+            let free_regs = std::mem::replace(&mut env.free_regs, callee_regs);
+            let value = eval_expr(&def.body, &mut params, 8, callee_depth, false, env);
+            env.free_regs = free_regs;
+            value
+        },
+        Expr::Print(value, _) => {
+            let ty = env.type_of(value, locals, in_loop)?;
+            let value = eval_expr(value, locals, stack_counter, depth, in_loop, env)?;
+            env.output.push_back(to_value(value, &ty).to_string());
+            if env.output.len() > runtime::OUTPUT_LIMIT {
+                env.output.pop_front();
+            }
+            Ok(value)
+        },
+        Expr::Null => Ok(0),
+        Expr::Alloc(name, values, span) => {
+            if !env.structs.contains_key(name) {
+                return Err(CompileError::UnboundIdentifier(name.clone(), *span).into());
+            }
+            let mut fields = Vec::new();
+            for (idx, value) in values.iter().enumerate() {
+                fields.push(eval_expr(value, locals, stack_counter + 8 * idx as i32, depth, in_loop, env)?);
+            }
+            // an empty struct still takes a word, as in the jitted code
+            let size = fields.len().max(1);
+            if env.heap.len() + size > runtime::HEAP_WORDS {
+                return Err(RuntimeError::OutOfMemory.into());
+            }
+            let pointer = 8 * env.heap.len() as i64 + 8;
+            env.heap.extend(fields);
+            env.heap.resize((pointer / 8 - 1) as usize + size, 0);
+            env.pointer_types.insert(pointer, name.clone());
+            Ok(pointer)
+        },
+        Expr::Lookup(pointer, field, span) => {
+            let pointer = eval_expr(pointer, locals, stack_counter, depth, in_loop, env)?;
+            let slot = env.field_slot(pointer, field, *span)?;
+            Ok(env.heap[slot])
+        },
+        Expr::Update(pointer, field, value, span) => {
+            let pointer = eval_expr(pointer, locals, stack_counter, depth, in_loop, env)?;
+            let value = eval_expr(value, locals, stack_counter + 8, depth, in_loop, env)?;
+            let slot = env.field_slot(pointer, field, *span)?;
+            env.heap[slot] = value;
+            Ok(value)
+        },
+    }
+}
@@ -1,24 +1,14 @@
-use im::{HashMap, HashSet};
-use lalrpop_util::ParseError;
 use piston_window::types::Color;
 use piston_window::*;
 
-use super::utils::Instr;
-use super::utils::Reg;
-use super::utils::Val;
+use crate::utils::{type_to_str, SnekError};
 
-use crate::utils::Type;
-
-use super::lexer::Lexer;
-use super::compile::{compile_to_instrs, instrs_to_asm};
-use super::grammar::ExpressionParser;
-
-use std::mem;
-use dynasmrt::{dynasm, DynasmApi};
-
-use super::drawing::{draw_block, draw_rectange, draw_program_line, draw_blocks_count, draw_text};
-use rand::{thread_rng, Rng};
-use super::snake::{Direction, Snake};
+use super::drawing::{draw_block, draw_rectange, draw_program_line, draw_blocks_count, draw_text, draw_text_columns, to_gui_coord};
+use super::game_core::GameCore;
+use super::level::Level;
+use super::puzzle::Puzzle;
+use super::replay::Replay;
+use super::snake::Direction;
 
 const FOOD_COLOR: Color = [0.90, 0.49, 0.13, 1.0];
 const BORDER_COLOR: Color = [0.741, 0.765, 0.78, 1.0];
@@ -27,17 +17,18 @@ const GAMEOVER_COLOR: Color = [0.91, 0.30, 0.24, 0.5];
 const MOVING_PERIOD: f64 = 0.2; // in second
 // const RESTART_TIME: f64 = 1.0; // in second
 
-pub struct Food {
-    food_x: i32,
-    food_y: i32,
-    instr: String,
-}
-
-pub struct Program {
-    line: String,
-    y_value: i32,
-    result: Option<i32>,
-}
+// row of the first program line in the code section
+const PROG_PRINT_START_Y: i32 = 4;
+const OUTPUT_COLOR: Color = [0.7, 0.7, 0.7, 1.0];
+// printed values shown under a program line; earlier ones are cut off
+const OUTPUT_VALUES_SHOWN: usize = 10;
+// what a level says about a line, like what it still needs
+const LEVEL_NOTE_COLOR: Color = [0.91, 0.30, 0.24, 1.0];
+// the instruction panel covers this many blocks at the right of the code section
+const ASM_PANEL_WIDTH: i32 = 20;
+const ASM_PANEL_COLUMNS: usize = 2;
+// how many instructions page up and page down scroll by
+const ASM_SCROLL_STEP: usize = 10;
 
 #[derive(Debug)]
 pub enum GameState {
@@ -47,173 +38,327 @@ pub enum GameState {
     ReachedGoal
 }
 
+// Draws a GameCore and drives it from the keyboard and the clock.
 pub struct Game {
-    snake: Snake,
-
-    // Food
-    food_list: Vec<Food>,
+    core: GameCore,
 
     // Game Space
     window_start_x: i32,
     width: i32,
     height: i32,
 
-    // Game state
-    is_game_over: bool,
-    reached_goal: bool,
-    goal: i32,
-
     // When the game is running, it represents the waiting time from the previous moving
     // When the game is over, it represents the waiting time from the end of the game
     waiting_time: f64,
 
-    is_def_line: bool,
-    prog_line: String,
-    program: Vec<Program>,
-
-    def_bindings: Vec<i32>,
-    num_let_bindings: i32,
-    in_set: bool,
-    in_let_binding: bool,
-
-    prog_print_x: i32,
-    prog_print_y: i32,
+    // a recorded round being played back instead of reading the arrow keys
+    replaying: Option<Replay>,
 
-    last_instr: String,
-
-    count_blocks: bool,
+    // The panel with the instructions a program line compiled to, toggled with A. It shows
+    // the latest line unless an earlier one was picked with [ and ].
+    show_asm: bool,
+    asm_line: Option<usize>,
+    asm_scroll: usize,
 }
 
 impl Game {
-    pub fn new(start_x: i32, width: i32, height: i32, start_goal: i32) -> Game {
-        let mut g = Game {
-            snake: Snake::new(start_x + 2, 2),
+    pub fn new(start_x: i32, width: i32, height: i32, seed: u64, puzzle: Option<&'static Puzzle>) -> Game {
+        let mut core = GameCore::new(start_x, width, height, seed);
+        if let Some(puzzle) = puzzle {
+            core.set_puzzle(puzzle);
+        }
+        Game {
+            core,
+            window_start_x: start_x,
+            width,
+            height,
             waiting_time: 0.0,
-            food_list: vec![],
+            replaying: None,
+            show_asm: false,
+            asm_line: None,
+            asm_scroll: 0,
+        }
+    }
+
+    // a level of a campaign, on a board of its own inside the `width` by `height` the window has
+    pub fn for_level(start_x: i32, width: i32, height: i32, seed: u64, level: Level) -> Game {
+        let mut game = Game::new(start_x, width, height, seed, None);
+        game.core.set_level(level);
+        game
+    }
+
+    pub fn from_replay(start_x: i32, width: i32, height: i32, replay: Replay) -> Result<Game, String> {
+        let core = GameCore::from_replay(start_x, width, height, &replay)?;
+        Ok(Game {
+            core,
             window_start_x: start_x,
-            width: width,
-            height: height,
-            is_game_over: false,
-            is_def_line: false,
-            prog_line: "( ".to_string(),
-            program: vec![],
-            def_bindings: vec![],
-            prog_print_x: start_x + width + 1,
-            prog_print_y: 4,
-            reached_goal: false,
-            goal: start_goal,
-            num_let_bindings: 0,
-            in_set: false,
-            last_instr: "".to_string(),
-            count_blocks: false,
-            in_let_binding: false,
+            width,
+            height,
+            waiting_time: 0.0,
+            replaying: Some(replay),
+            show_asm: false,
+            asm_line: None,
+            asm_scroll: 0,
+        })
+    }
+
+    pub fn goal(&self) -> i64 {
+        self.core.goal()
+    }
 
+    // what the player is asked for, as shown above the code
+    pub fn goal_text(&self) -> String {
+        let goal = match self.core.puzzle() {
+            Some(puzzle) => {
+                let inputs: Vec<String> = puzzle.inputs.iter().map(|input| input.to_string()).collect();
+                format!("{} for input {}", puzzle.description, inputs.join(", "))
+            },
+            None => self.core.goal().to_string(),
         };
-        // make food list anything that could follow (
-        g.update_food();
-        g
+        let Some(level) = self.core.level() else { return goal };
+        // and what the level holds the program to
+        let mut rules = vec![];
+        if let Some(max) = level.max_lines {
+            rules.push(format!("at most {} lines", max));
+        }
+        if !level.required.is_empty() {
+            rules.push(format!("using {}", level.required.join(", ")));
+        }
+        match rules.is_empty() {
+            true => format!("{} ({})", goal, level.name),
+            false => format!("{} ({}: {})", goal, level.name, rules.join(", ")),
+        }
     }
 
     pub fn key_pressed(&mut self, key: Key) {
-        if self.is_game_over {
+        // the instruction panel can be looked at during a replay too
+        if self.asm_key_pressed(key) {
+            return;
+        }
+        if self.core.is_game_over() || self.replaying.is_some() {
+            return;
+        }
+
+        if key == Key::E {
+            match self.core.export_program("program.snek") {
+                Ok(()) => println!("exported program to program.snek"),
+                Err(error) => println!("could not export program: {}", error),
+            }
             return;
         }
 
         let dir = match key {
-            Key::Up => Some(Direction::Up),
-            Key::Down => Some(Direction::Down),
-            Key::Left => Some(Direction::Left),
-            Key::Right => Some(Direction::Right),
+            Key::Up => Direction::Up,
+            Key::Down => Direction::Down,
+            Key::Left => Direction::Left,
+            Key::Right => Direction::Right,
             // Ignore other keys
             _ => return,
         };
 
-        if dir.unwrap() == self.snake.head_direction().opposite() {
-            return;
+        if self.core.tick(Some(dir)) {
+            self.waiting_time = 0.0;
         }
+    }
 
-        // Check if the snake hits the border
-        self.update_snake(dir);
+    // Handles the keys of the instruction panel, and says whether the key was one of them.
+    fn asm_key_pressed(&mut self, key: Key) -> bool {
+        let lines = self.core.program().len();
+        let shown = self.asm_line.unwrap_or(lines.saturating_sub(1));
+        match key {
+            Key::A => self.show_asm = !self.show_asm,
+            Key::LeftBracket if self.show_asm => self.asm_line = Some(shown.saturating_sub(1)),
+            // going past the last line follows the latest one again
+            Key::RightBracket if self.show_asm => self.asm_line = Some(shown + 1).filter(|line| *line + 1 < lines),
+            Key::PageUp if self.show_asm => self.asm_scroll = self.asm_scroll.saturating_sub(ASM_SCROLL_STEP),
+            Key::PageDown if self.show_asm => self.asm_scroll += ASM_SCROLL_STEP,
+            _ => return false,
+        }
+        if matches!(key, Key::LeftBracket | Key::RightBracket) {
+            self.asm_scroll = 0;
+        }
+        true
     }
 
-    pub fn draw(&self, con: &Context, g: &mut G2d, mut font: &mut Glyphs) {
-        self.snake.draw(con, g, &mut font);
+    pub fn draw(&self, con: &Context, g: &mut G2d, font: &mut Glyphs) {
+        self.core.snake().draw(con, g, font);
 
-        for food in &self.food_list {
+        for food in self.core.food() {
             draw_block(Self::instr_to_color(food.instr.clone()), 
-                &food.instr.clone(), food.food_x, food.food_y, con, g, font);
+                &food.instr, food.food_x, food.food_y, con, g, font);
         }
 
-        // Draw the border
-        draw_rectange(BORDER_COLOR, self.window_start_x, 0, self.width, 1, con, g); // top
-        draw_rectange(BORDER_COLOR, self.window_start_x, self.height - 1, self.width, 1, con, g); // bottom
-        draw_rectange(BORDER_COLOR, self.window_start_x, 0, 1, self.height, con, g); // left
-        draw_rectange(BORDER_COLOR, self.window_start_x + self.width - 1, 0, 1, self.height, con, g); // right
+        // Draw the border, which is around a level's own board in a campaign
+        let (width, height) = self.core.board();
+        draw_rectange(BORDER_COLOR, self.window_start_x, 0, width, 1, con, g); // top
+        draw_rectange(BORDER_COLOR, self.window_start_x, height - 1, width, 1, con, g); // bottom
+        draw_rectange(BORDER_COLOR, self.window_start_x, 0, 1, height, con, g); // left
+        draw_rectange(BORDER_COLOR, self.window_start_x + width - 1, 0, 1, height, con, g); // right
+        for (x, y) in self.core.obstacles() {
+            draw_rectange(BORDER_COLOR, x, y, 1, 1, con, g);
+        }
 
-        draw_blocks_count(self.snake.blocks_traveled(), con, g, font);
+        draw_blocks_count(self.core.snake().blocks_traveled(), con, g, font);
 
         // draw heap variables and their values
         let heap_x = 20.0;
         let mut heap_y = 70.0;
-        for (var_num, value) in self.def_bindings.clone().into_iter().enumerate() {
-            let mut text_to_draw = "x".to_string();
-            text_to_draw.push_str(&var_num.to_string());
-            text_to_draw.push_str(": ");
-            text_to_draw.push_str(&value.to_string());
+        for binding in self.core.symbols().heap() {
+            let text_to_draw = format!("{}: {}", binding.name, binding.value.unwrap());
+            draw_text(text_to_draw, [1.0, 1.0, 1.0, 1.0], heap_x, heap_y, con, g, font);
+            heap_y += 20.0;
+        }
+        // structs and functions are listed with their fields and parameters below the heap variables
+        for def in self.core.jit().struct_defs() {
+            let fields: Vec<String> = def.fields.iter().map(|(name, ty)| format!("{} {}", name, type_to_str(ty.clone()))).collect();
+            let text_to_draw = format!("{} {{ {} }}", def.name, fields.join(", "));
+            draw_text(text_to_draw, [1.0, 1.0, 1.0, 1.0], heap_x, heap_y, con, g, font);
+            heap_y += 20.0;
+        }
+        for def in self.core.jit().fun_defs() {
+            let text_to_draw = format!("{}({})", def.name, def.params.join(", "));
             draw_text(text_to_draw, [1.0, 1.0, 1.0, 1.0], heap_x, heap_y, con, g, font);
             heap_y += 20.0;
         }
 
         let temp_x = 140.0;
         let mut temp_y = 70.0;
-        // draw temp variables and their values
-        for var_num in 0..self.num_let_bindings {
-            let mut text_to_draw = "y".to_string();
-            text_to_draw.push_str(&var_num.to_string());
-            draw_text(text_to_draw, [1.0, 1.0, 1.0, 1.0], temp_x, temp_y, con, g, font);
+        // draw temp variables, each nested let a step further in than the one around it
+        for binding in self.core.symbols().line_bindings() {
+            let indent = 10.0 * binding.depth.saturating_sub(1) as f64;
+            draw_text(binding.name.clone(), [1.0, 1.0, 1.0, 1.0], temp_x + indent, temp_y, con, g, font);
             temp_y += 20.0;
         }
 
-        for program in &self.program {
-            draw_program_line(program.line.clone(), program.result, self.prog_print_x, program.y_value, con, g, font);
+        // one program line per row, with the line being built below the ones that ran
+        let prog_print_x = self.window_start_x + self.width + 1;
+        let mut prog_print_y = PROG_PRINT_START_Y;
+        for program in self.core.program() {
+            match &program.result {
+                Ok(res) => draw_program_line(program.line.clone(), *res, None,
+                    prog_print_x, prog_print_y, con, g, font),
+                Err(error) => draw_program_line(program.line.clone(), None, Some(Self::error_to_str(&program.line, error)),
+                    prog_print_x, prog_print_y, con, g, font),
+            }
+            prog_print_y += 1;
+            if let (Some(puzzle), Some(score)) = (self.core.puzzle(), program.score) {
+                draw_text(format!("right for {} of {} inputs", score, puzzle.inputs.len()), OUTPUT_COLOR,
+                    to_gui_coord(prog_print_x + 1), to_gui_coord(prog_print_y), con, g, font);
+                prog_print_y += 1;
+            }
+            // what the line printed goes on its own row underneath it
+            if !program.output.is_empty() {
+                draw_text(Self::output_to_str(&program.output), OUTPUT_COLOR,
+                    to_gui_coord(prog_print_x + 1), to_gui_coord(prog_print_y), con, g, font);
+                prog_print_y += 1;
+            }
+            if let Some(note) = &program.level_note {
+                draw_text(note.clone(), LEVEL_NOTE_COLOR,
+                    to_gui_coord(prog_print_x + 1), to_gui_coord(prog_print_y), con, g, font);
+                prog_print_y += 1;
+            }
+        }
+        draw_program_line(self.core.prog_line().to_string(), None, None, prog_print_x, prog_print_y, con, g, font);
+
+        if self.show_asm {
+            self.draw_asm_panel(con, g, font);
         }
-        draw_program_line(self.prog_line.clone(), None, self.prog_print_x, self.prog_print_y, con, g, font);
 
         // Draw a game-over rectangle
-        if self.is_game_over {
+        if self.core.is_game_over() {
             draw_rectange(GAMEOVER_COLOR, self.window_start_x, 0, self.width, self.height, con, g);
         }
     }
 
+    // the instructions of the picked program line, over the right of the code section
+    fn draw_asm_panel(&self, con: &Context, g: &mut G2d, font: &mut Glyphs) {
+        let program = self.core.program();
+        let picked = self.asm_line.filter(|line| *line < program.len())
+            .or(program.len().checked_sub(1))
+            .map(|line| (line, &program[line]));
+        let (title, asm) = match picked {
+            Some((line, prog)) if prog.asm.is_empty() => (format!("line {}: did not compile", line + 1), &prog.asm[..]),
+            Some((line, prog)) => (format!("line {}: {} instructions", line + 1, prog.asm.len()), &prog.asm[..]),
+            None => ("no line has run yet".to_string(), &[][..]),
+        };
+        let scroll = self.asm_scroll.min(asm.len().saturating_sub(1));
+        let x = self.window_start_x + 2 * self.width - ASM_PANEL_WIDTH;
+        let shown = draw_text_columns(&title, &asm[scroll..], x, 0, ASM_PANEL_WIDTH, self.height,
+            ASM_PANEL_COLUMNS, con, g, font);
+        if scroll + shown < asm.len() {
+            draw_text(format!("{} more (page down)", asm.len() - scroll - shown), OUTPUT_COLOR,
+                to_gui_coord(x + 10), to_gui_coord(self.height) - 10.0, con, g, font);
+        }
+    }
+
+    // the last few values a line printed, on one row
+    fn output_to_str(output: &[String]) -> String {
+        let shown = &output[output.len().saturating_sub(OUTPUT_VALUES_SHOWN)..];
+        let cut = if shown.len() < output.len() { "... " } else { "" };
+        format!("> {}{}", cut, shown.join(" "))
+    }
+
+    // describe an error, quoting the part of the line a compile error's span covers
+    fn error_to_str(line: &str, error: &SnekError) -> String {
+        let span = match error {
+            SnekError::Compile(e) => e.span(),
+            SnekError::Runtime(_) => return error.to_string(),
+        };
+        match line.get(span.start..span.end) {
+            Some(snippet) if !snippet.trim().is_empty() => format!("{} at '{}'", error, snippet.trim()),
+            _ => error.to_string(),
+        }
+    }
+
     fn instr_to_color(instr: String) -> Color {
         // [red, green, blue, alpha]
         // All values are between 0.0 and 1.0.
         // For example, black is `[0.0, 0.0, 0.0, 1.0]` and white is `[1.0, 1.0, 1.0, 1.0]`.
         match instr.as_str() {
-            "+" => return [0.0, 1.0, 1.0, 1.0], // yellow
-            "-" => return [1.0, 0.5, 0.0, 1.0], // orange
-            "*" => return [1.0, 0.0, 0.0, 1.0], // red
-            "END" => return [0.0, 1.0, 0.0, 1.0], // yellow green
-            "def" => return [0.0, 1.0, 0.5, 1.0], // bluer green
-            "add1" => return [0.0, 1.0, 1.0, 1.0], // cyan
-            "sub1" => return [0.0, 0.5, 1.0, 1.0], // blue
-            "let" => return [0.0, 0.0, 1.0, 1.0], // dark blue
-            "set" => return [0.5, 0.0, 1.0, 1.0], // purplish blue
-            "(" => return [1.0, 0.0, 1.0, 1.0], // pink
-            ")" => return [0.5, 0.0, 0.5, 1.0], // purple
-            "{" => return [0.5, 0.0, 0.0, 1.0], // maroon
-            "}" => return [0.0, 0.5, 0.0, 1.0], // dark green
-            "var" => return [0.0, 0.0, 0.5, 1.0], // navy blue
-            ":=" => return [0.0, 0.5, 0.5, 1.0], // dark teal
-            "id" => return [1.0, 0.5, 0.5, 1.0], // salmon
-            "int" => return [0.27, 0.11, 0.39, 1.0], //grape
-            "end_int" => return [0.27, 0.11, 0.39, 1.0], //grape
-            "|" => return [0.91, 0.384, 0.384, 1.0], //pale red
-            ";" => return [0.91, 0.384, 0.384, 1.0], //pale red
+            "+" => [0.0, 1.0, 1.0, 1.0], // yellow
+            "-" => [1.0, 0.5, 0.0, 1.0], // orange
+            "*" => [1.0, 0.0, 0.0, 1.0], // red
+            "/" => [0.8, 0.2, 0.2, 1.0], // brick red
+            "%" => [0.6, 0.3, 0.1, 1.0], // brown
+            "END" => [0.0, 1.0, 0.0, 1.0], // yellow green
+            "def" => [0.0, 1.0, 0.5, 1.0], // bluer green
+            "add1" => [0.0, 1.0, 1.0, 1.0], // cyan
+            "sub1" => [0.0, 0.5, 1.0, 1.0], // blue
+            "let" => [0.0, 0.0, 1.0, 1.0], // dark blue
+            "set" => [0.5, 0.0, 1.0, 1.0], // purplish blue
+            "(" => [1.0, 0.0, 1.0, 1.0], // pink
+            ")" => [0.5, 0.0, 0.5, 1.0], // purple
+            "{" => [0.5, 0.0, 0.0, 1.0], // maroon
+            "}" => [0.0, 0.5, 0.0, 1.0], // dark green
+            "var" => [0.0, 0.0, 0.5, 1.0], // navy blue
+            ":=" => [0.0, 0.5, 0.5, 1.0], // dark teal
+            "id" => [1.0, 0.5, 0.5, 1.0], // salmon
+            "int" => [0.27, 0.11, 0.39, 1.0], //grape
+            "end_int" => [0.27, 0.11, 0.39, 1.0], //grape
+            "if" => [0.6, 0.4, 0.2, 1.0], // brown
+            "true" => [0.2, 0.6, 0.2, 1.0], // leaf green
+            "false" => [0.6, 0.2, 0.2, 1.0], // brick red
+            ">" | "<" | ">=" | "<=" | "=" => [0.8, 0.8, 0.0, 1.0], // mustard
+            "repeat-until" => [0.3, 0.3, 0.3, 1.0], // charcoal
+            "break" => [0.7, 0.7, 0.7, 1.0], // light gray
+            "fun" => [0.0, 0.6, 0.3, 1.0], // jungle green
+            "param" => [0.6, 0.3, 0.6, 1.0], // plum
+            "apply" => [0.3, 0.6, 0.6, 1.0], // slate teal
+            "struct" => [0.8, 0.4, 0.0, 1.0], // rust
+            "field" => [0.8, 0.6, 0.4, 1.0], // tan
+            "alloc" => [0.4, 0.8, 0.4, 1.0], // mint
+            "lookup" => [0.4, 0.4, 0.8, 1.0], // periwinkle
+            "index" => [0.8, 0.4, 0.8, 1.0], // orchid
+            "null" => [0.5, 0.5, 0.5, 1.0], // gray
+            "[" | "]" => [0.6, 0.6, 0.2, 1.0], // olive
+            "i64" | "boolean" => [0.2, 0.4, 0.4, 1.0], // dark slate
+            "print" => [1.0, 1.0, 0.6, 1.0], // pale yellow
+            "input" => [0.6, 1.0, 0.6, 1.0], // pale green
+            "|" => [0.91, 0.384, 0.384, 1.0], //pale red
+            ";" => [0.91, 0.384, 0.384, 1.0], //pale red
             // identifier case
-            _ => return FOOD_COLOR, // orange?
+            _ => FOOD_COLOR, // orange?
         }
-        
     }    
 
     pub fn update(&mut self, delta_time: f64) -> GameState {
@@ -221,399 +366,54 @@ impl Game {
 
         let mut final_state = GameState::GameStarted;
 
-        if self.is_game_over {
+        if self.core.is_game_over() {
             final_state = GameState::SnakeDied
-        } else if self.reached_goal {
+        } else if self.core.reached_goal() {
             final_state = GameState::ReachedGoal
         }
 
         // If the game is over
-        if self.is_game_over || self.reached_goal {
-            self.restart();
-            self.is_game_over = false;
-            return final_state;
+        if self.core.is_game_over() || self.core.reached_goal() {
+            match self.replaying.take() {
+                Some(_) => println!("replay finished"),
+                None => self.save_replay(),
+            }
+            self.core.restart();
+            self.waiting_time = 0.0;
+            final_state
         }
         else {
             // Move the snake
 
             if self.waiting_time > MOVING_PERIOD {
-                self.update_snake(None);
-            }
-
-            return final_state;
-        }
-    }
-
-    fn update_prog(&mut self) {
-        let instr_eaten = self.last_instr.clone();
-        let next_num_instr = self.snake.blocks_traveled().to_string();
-        self.snake.reset_blocks_traveled();
-
-        match instr_eaten.as_str() {
-            "int" => {
-                self.count_blocks = true;
-            }, //start of int blocks traveled
-            "id" => {}, //start of display of id blocks
-            "end_int" => { //end of int blocks traveled
-                self.count_blocks = false;
-                self.prog_line.push_str(&next_num_instr);
-            },
-            ";" => {
-                println!("{}", self.prog_line);
-                if self.is_def_line {
-                    println!("saving result to var number {}", self.def_bindings.len());
-                }
-
-                let res = self.run_line();
-                println!("res of running prev line: {}", res);
-
-                if res == self.goal {
-                    self.reached_goal = true;
-                }
-
-                self.program.push(Program{
-                    line: self.prog_line.clone(), 
-                    y_value: self.prog_print_y, 
-                    result: Some(res)
-                });
-
-                // move y to next line so that new prog line is printed below
-                self.prog_print_y += 1;
-
-                // save result of program line to heap or temp binding
-                if self.is_def_line {
-                    self.def_bindings.push(res);
+                let dir = self.replaying.as_ref().and_then(|replay| replay.turn_at(self.core.ticks()));
+                self.core.tick(dir);
+                self.waiting_time = 0.0;
+                // a replay cut short hands the snake back to the keyboard
+                if self.replaying.as_ref().is_some_and(|replay| self.core.ticks() >= replay.ticks) {
+                    self.replaying = None;
                 }
-
-                // start new program line
-                self.prog_line = "( ".to_string();
-
-                self.is_def_line = false;
-                self.num_let_bindings = 0;
-                // self.snake = Snake::new(self.snake.head_position().0, self.snake.head_position().0); 
-            },
-            "def" => self.is_def_line = true,
-            "var" => {
-                let var_name = "y".to_owned() + &self.num_let_bindings.to_string();
-                self.prog_line.push_str(&" var ".to_string());
-                self.prog_line.push_str(&var_name);
-                self.prog_line.push_str(&" := ".to_string());
-            },
-            "let" => {
-                self.prog_line.push_str(&" let { ".to_string());
-                self.in_let_binding = true;
-            }
-            "{" => {
-                self.prog_line.push_str(&" { ".to_string());
-            },
-            // assuming that the let finished so temp bindings go out of scope
-            "}" => {
-                self.prog_line.push_str(&" } ".to_string());
-                self.in_let_binding = false;
-            },
-            ")" => {
-                self.prog_line.push_str(&" ".to_string());
-                self.prog_line.push_str(&instr_eaten.clone());
-                self.prog_line.push_str(&" ".to_string());
-            },
-            "+"| "-" | "*" => {
-                // if an existing var was eaten before this instr, don't add number of blocks moved to program
-                self.prog_line.push_str(&" ".to_string());
-                self.prog_line.push_str(&instr_eaten.clone());
-                self.prog_line.push_str(&" ".to_string());
-            },
-            "(" => {
-                self.prog_line.push_str(&instr_eaten.clone());
-                self.prog_line.push_str(&" ".to_string());
-            },
-            ":=" | "add1" | "sub1" => {
-                self.prog_line.push_str(&instr_eaten.clone());
-                self.prog_line.push_str(&" ".to_string());
-                self.in_set = false; //once you define var to set
-            },
-            "set" => {
-                println!("ate instr: {}", &instr_eaten.clone());
-                self.prog_line.push_str(&" set ".to_string());
-                self.in_set = true;
-            },
-            "|" => {
-                println!("ate instr: {}", &instr_eaten.clone());
-                self.prog_line.push_str(&" | ".to_string());
-                self.num_let_bindings += 1;
-            },
-            _ => {
-                // just ate a heap variable
-                if instr_eaten.chars().next().unwrap() == 'x' {
-                    self.prog_line.push_str(&" ".to_string());
-                    let var_num = instr_eaten.chars().nth(1).unwrap().to_digit(10).unwrap() as usize;
-                    if var_num < self.def_bindings.len() {
-                        self.prog_line.push_str(&self.def_bindings[var_num].to_string());
-                    } else {
-                        panic!("var {} not found in def bindings {}", instr_eaten, var_num)
-                    }
-                }
-                // just ate a temp variable
-                else if instr_eaten.chars().next().unwrap() == 'y' {
-                    self.prog_line.push_str(&" ".to_string());
-                    self.prog_line.push_str(&instr_eaten);
-                    self.prog_line.push_str(&" ");
-                }
-                else {
-                    println!("ate instr: {}", &instr_eaten.clone());
-                    self.prog_line.push_str(&" ".to_string());
-                    self.prog_line.push_str(&instr_eaten.clone());
-                    self.prog_line.push_str(&" ".to_string());
-                }
-            }
-        }
-    }
-
-    fn check_eating(&mut self) {
-        let (head_x, head_y): (i32, i32) = self.snake.head_position();
-        let mut instr_eaten = "".to_string();
-        for food in &self.food_list {
-            if food.food_x == head_x && food.food_y == head_y { //ate food
-                instr_eaten = food.instr.clone();
-                break;
-            }
-        }
-        
-        if instr_eaten != "" { //ate something
-            self.last_instr = instr_eaten.clone();
-            self.update_prog();
-            self.update_food();
-            self.snake.restore_last_removed();
-        }
-    }
-
-    fn generate_next_tokens(&mut self, last_instr: String) -> HashSet<String> {
-
-        //ID CASE
-        if last_instr == "id" {
-            let mut var_names: HashSet<String> = HashSet::new();
-            // add identifier names from heap bindings if we are not in a set expression
-            if !self.in_set {
-                for idx in 0..self.def_bindings.len() {
-                    let mut var_name = "x".to_string();
-                    var_name.push_str(&idx.to_string());
-                    var_names.insert(var_name);
-                }
-            }
-            
-            // add identifier names from temporary let bindings
-            for idx in 0..self.num_let_bindings {
-                let mut var_name = "y".to_string();
-                var_name.push_str(&idx.to_string());
-                var_names.insert(var_name);
-            }
-
-            return var_names;
-        }
-
-        let mut tokens: HashSet<String> = HashSet::new(); //all tokens to return
-
-        //INT CASE
-        if last_instr == "int" {
-            tokens.insert("end_int".to_string());
-            return tokens;
-        }
-
-        if last_instr == "" || last_instr == ";" {  //first instr of line can be def, no other instr
-            tokens.insert("def".to_string());
-        }
-
-        let lexer = Lexer::new(&self.prog_line);
-        let parser = ExpressionParser::new();
-        let ast = parser.parse(lexer);
-        
-        match ast {
-            Err(error_message) => {
-                match error_message {
-                    ParseError::InvalidToken { location: _} => {
-                        // println!("invalid token: {} | {}", location, self.prog_line);
-                    },
-                    ParseError::UnrecognizedEof { location: _, expected } => {
-                        // println!("unrecognized EOF: {} | {}", location, self.prog_line);
-                        tokens.extend(expected.iter().cloned());
-                    },
-                    ParseError::UnrecognizedToken { token: _, expected } => {
-                        // println!("unrecognized token: {:#?} | {}", token, self.prog_line);
-                        tokens.extend(expected.iter().cloned());
-                    },
-                    ParseError::ExtraToken { token: _} => {
-                        // println!("extra token: {:#?} | {}", token, self.prog_line);
-                    },
-                    ParseError::User { error} => panic!("user error: {} | {}", error, self.prog_line),
-                }
-            }
-            Ok(_expression) => {
-                tokens.insert(";".to_string());
-                return tokens;
-            }
-        };
-
-        let mut processed_tokens: HashSet<String> = HashSet::new();
-        for token in &tokens {
-            let mut processed_line = str::replace(&token, "\"", "");
-            if processed_line == "identifier" {
-                processed_line = "id".to_string();
             }
-            processed_tokens.insert(processed_line);
-        }
-
-        if (last_instr == "end_int" || last_instr.starts_with("x") || last_instr.starts_with("y")) && !self.in_set {
-            processed_tokens.insert("+".to_string());
-            processed_tokens.insert("-".to_string());
-            processed_tokens.insert("*".to_string());
-        }
-
-        //if there are no bindings
-        if self.num_let_bindings == 0 && self.def_bindings.is_empty() {
-            processed_tokens.retain(|x| x != "id");
-        }
-        if self.num_let_bindings == 0 {
-            processed_tokens.retain(|x| x != "set");
-        }
 
-        if self.in_let_binding {
-            processed_tokens.retain(|x| x != "let");
+            final_state
         }
-
-        println!("{:?}", processed_tokens);
-        return processed_tokens;
     }
 
-    fn check_if_the_snake_alive(&self, dir: Option<Direction>) -> bool {
-        let (next_x, next_y) = self.snake.next_head_position(dir);
-
-        // Check if the snake hits itself
-        if self.snake.is_overlap_except_tail(next_x, next_y) {
-            return false;
-        }
-
-        // Check if the snake overlaps with the border
-        next_x > self.window_start_x && next_y > 0 && next_x < self.width + self.window_start_x - 1 && next_y < self.height - 1
-    }
-
-    fn update_food(&mut self) {
-        let last_instr = self.last_instr.clone();
-        let mut rng = thread_rng();
-        // let next_instrs = self.generate_instructions(last_instr);
-        let next_instrs = self.generate_next_tokens(last_instr);
-
-        let mut token_positions: HashSet<(i32, i32)> = HashSet::new();
-
-        // generate a new token for every instr
-        // generate 5 end_int tokens if one token is end_int
-        let mut num_new_tokens = next_instrs.len();
-        if next_instrs.contains("end_int") {
-            num_new_tokens += 4;
-        }
-
-        while token_positions.len() < num_new_tokens {
-            let mut new_x = rng.gen_range((self.window_start_x + 1)..(self.width - 1));
-            let mut new_y = rng.gen_range(1..(self.height - 1));
-            while self.snake.is_overlap_except_tail(new_x, new_y) {
-                new_x = rng.gen_range((self.window_start_x + 1)..(self.width - 1));
-                new_y = rng.gen_range(1..(self.height - 1));
+    // Writes the round that just ended so it can be watched again with --replay.
+    fn save_replay(&self) {
+        let path = format!("replay-{}.txt", self.core.seed());
+        let mut contents = String::new();
+        for program in self.core.program() {
+            match &program.result {
+                Ok(Some(res)) => contents.push_str(&format!("# {} -> {}\n", program.line.trim(), res)),
+                Ok(None) => contents.push_str(&format!("# {}\n", program.line.trim())),
+                Err(error) => contents.push_str(&format!("# {} !! {}\n", program.line.trim(), error)),
             }
-            token_positions.insert((new_x, new_y));
-        }
-
-        // add all non end_int food tokens
-        self.food_list = vec![];
-        for instr in next_instrs {
-            if instr != "end_int" {
-                let position = token_positions.iter().next().unwrap().clone();
-                token_positions.remove(&position);
-                let new_food = Food {
-                    food_x: position.0,
-                    food_y: position.1,
-                    instr: instr.to_string(),
-                };
-                self.food_list.push(new_food);
-            }
-        }
-        // add all end_int food tokens with remaining positions
-        // if there were no end_int tokens, this block is skipped
-        while token_positions.len() > 0 {
-            let position = token_positions.iter().next().unwrap().clone();
-            token_positions.remove(&position);
-            let new_food = Food {
-                food_x: position.0,
-                food_y: position.1,
-                instr: "end_int".to_string(),
-            };
-            self.food_list.push(new_food);
-        }
-    }
-
-    fn update_snake(&mut self, dir: Option<Direction>) {
-        if self.check_if_the_snake_alive(dir) {
-            self.snake.move_forward(dir, self.count_blocks);
-            self.check_eating();
-        } else {
-            self.is_game_over = true;
         }
-        self.waiting_time = 0.0;
-    }
-
-    fn run_line(&mut self) -> i32 {
-        let stack_bindings: HashMap<String, i32> = HashMap::new();
-        let mut variable_types: HashMap<String, Type> = HashMap::new();
-        let lexer = Lexer::new(&self.prog_line);
-        let parser = ExpressionParser::new();
-        let ast = parser.parse(lexer);
-        
-        
-        match ast {
-            Err(error_message) => {
-                panic!("{}", error_message);
-            }
-            Ok(expression) => {
-                println!("{:?}", expression);
-                let mut ops = dynasmrt::x64::Assembler::new().unwrap();
-                let start = ops.offset();
-        
-                let mut compilation_bindings = HashMap::new();
-                for (idx, value) in self.def_bindings.clone().into_iter().enumerate() {
-                    let mut var_name = "x".to_string();
-                    var_name.push_str(&idx.to_string());
-                    compilation_bindings.insert(var_name, value);
-                }
-                let mut instrs = vec![];
-                instrs.push(Instr::Push(Val::Reg(Reg::RBP)));
-                instrs.push(Instr::IMov(Val::Reg(Reg::RBP), Val::Reg(Reg::RSP)));
-                instrs.append(&mut compile_to_instrs(&expression, stack_bindings, &mut variable_types,
-                    8, &compilation_bindings));
-                instrs.push(Instr::Pop(Val::Reg(Reg::RBP)));
-                println!("{:?}", instrs);
-                instrs_to_asm(&instrs, &mut ops);
-                dynasm!(ops
-                ; .arch x64
-                ; ret);
-                let buf = ops.finalize().unwrap();
-                let jitted_fn: extern "C" fn() -> i32 = unsafe { mem::transmute(buf.ptr(start)) };
-    
-                println!("{}", jitted_fn());
-                return jitted_fn();
-            }
+        contents.push_str(&self.core.replay().to_string());
+        match std::fs::write(&path, contents) {
+            Ok(()) => println!("saved replay to {}", path),
+            Err(error) => println!("could not save replay: {}", error),
         }
     }
-
-    fn restart(&mut self) {
-        self.snake = Snake::new(self.window_start_x + 2, 2); 
-        self.waiting_time = 0.0;
-        self.last_instr = "".to_string();
-        self.prog_line = "( ".to_string();
-        self.program.clear();
-        self.is_game_over = false;
-        self.is_def_line = false;
-        self.def_bindings.clear();
-        self.reached_goal = false;
-        self.num_let_bindings = 0;
-        self.in_set = false;
-        self.update_food();
-
-    }
 }
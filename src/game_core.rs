@@ -0,0 +1,586 @@
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+
+use crate::utils::{CompileError, SnekError, Span};
+
+use super::jit::Jit;
+use super::level::Level;
+use super::next_tokens::{Globals, NextTokens};
+use super::puzzle::Puzzle;
+use super::replay::Replay;
+use super::runtime::Value;
+use super::snake::{Direction, Snake};
+use super::symbols::{Binding, BindingKind, SymbolTable};
+
+pub struct Food {
+    pub food_x: i32,
+    pub food_y: i32,
+    pub instr: String,
+}
+
+pub struct Program {
+    pub line: String,
+    // function and struct definitions have no result
+    pub result: Result<Option<Value>, SnekError>,
+    // values the line printed, even if it then failed
+    pub output: Vec<String>,
+    // in a puzzle, how many inputs the line gave the right output for
+    pub score: Option<usize>,
+    // the instructions the line compiled to, empty if it did not compile
+    pub asm: Vec<String>,
+    // in a level, why the line did not reach the goal or that it was the last one allowed
+    pub level_note: Option<String>,
+}
+
+// The rules of the game without any drawing or timing: the snake moves one block per tick,
+// eating food grows the program line and ";" runs it. `Game` renders it and drives it from
+// the keyboard and the clock, while tests and bots can call `tick` directly.
+pub struct GameCore {
+    snake: Snake,
+
+    // Food
+    food_list: Vec<Food>,
+
+    // Game Space
+    window_start_x: i32,
+    width: i32,
+    height: i32,
+
+    // Game state
+    is_game_over: bool,
+    reached_goal: bool,
+    goal: i64,
+    // replaces the goal with a line that has to work for several inputs
+    puzzle: Option<&'static Puzzle>,
+    // a campaign level's board, food, obstacles and limits; its goal or puzzle is set above
+    level: Option<Level>,
+    // where food appears comes from here, so a round's seed replays its board
+    seed: u64,
+    rng: StdRng,
+    // ticks taken this round and the turns among them, for replays
+    ticks: u64,
+    turns: Vec<(u64, Direction)>,
+
+    is_def_line: bool,
+    prog_line: String,
+    program: Vec<Program>,
+
+    // def results (x0..xn), structs and functions of the program so far
+    jit: Jit,
+    // what the x variables and the line's own bindings stand for
+    symbols: SymbolTable,
+    // what can come next on the line, which is what food appears for
+    tokens: NextTokens,
+
+    // a fun line is "fun fN ( aK | ... ) { body }"
+    is_fun_line: bool,
+
+    // a struct line is "struct sN { gK type | ... }"
+    is_struct_line: bool,
+
+    last_instr: String,
+
+    count_blocks: bool,
+}
+
+impl GameCore {
+    // the goal is picked from the seed too, so one number is enough to share a board
+    pub fn new(start_x: i32, width: i32, height: i32, seed: u64) -> GameCore {
+        let goal = StdRng::seed_from_u64(seed).gen_range(0..100);
+        GameCore::with_goal(start_x, width, height, seed, goal)
+    }
+
+    pub fn with_goal(start_x: i32, width: i32, height: i32, seed: u64, goal: i64) -> GameCore {
+        let jit = Jit::new();
+        let mut g = GameCore {
+            snake: Snake::new(start_x + 2, 2),
+            food_list: vec![],
+            window_start_x: start_x,
+            width,
+            height,
+            is_game_over: false,
+            is_def_line: false,
+            prog_line: "( ".to_string(),
+            program: vec![],
+            tokens: NextTokens::new(Globals::new(&jit, false)),
+            jit,
+            symbols: SymbolTable::new(),
+            reached_goal: false,
+            goal,
+            puzzle: None,
+            level: None,
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+            ticks: 0,
+            turns: vec![],
+            last_instr: "".to_string(),
+            count_blocks: false,
+            is_fun_line: false,
+            is_struct_line: false,
+        };
+        // make food list anything that could follow (
+        g.update_food();
+        g
+    }
+
+    pub fn goal(&self) -> i64 {
+        self.goal
+    }
+
+    pub fn puzzle(&self) -> Option<&'static Puzzle> {
+        self.puzzle
+    }
+
+    // The core a replay was recorded on, before its first tick.
+    pub fn from_replay(start_x: i32, width: i32, height: i32, replay: &Replay) -> Result<GameCore, String> {
+        let mut core = GameCore::with_goal(start_x, width, height, replay.seed, replay.goal);
+        // a level brings its puzzle with it, and Replay::parse only keeps names of puzzles that exist
+        match &replay.level {
+            Some(path) => core.set_level(Level::read(path)?),
+            None => {
+                if let Some(puzzle) = replay.puzzle.as_deref().and_then(Puzzle::by_name) {
+                    core.set_puzzle(puzzle);
+                }
+            },
+        }
+        Ok(core)
+    }
+
+    // Plays the puzzle instead of the goal. The value of `input` becomes food too.
+    pub fn set_puzzle(&mut self, puzzle: &'static Puzzle) {
+        self.puzzle = Some(puzzle);
+        self.tokens = self.new_line_tokens();
+        self.restart_food();
+    }
+
+    // Plays a level of a campaign: its goal or puzzle, on its board, with only the food it
+    // allows and its obstacles in the way.
+    pub fn set_level(&mut self, level: Level) {
+        if let Some(goal) = level.goal {
+            self.goal = goal;
+        }
+        self.puzzle = level.puzzle.as_deref().and_then(Puzzle::by_name);
+        (self.width, self.height) = level.board;
+        self.level = Some(level);
+        self.tokens = self.new_line_tokens();
+        self.restart_food();
+    }
+
+    // Places the food again from the round's seed, as if the round had started with the
+    // puzzle or level set. A restarted round draws its first food straight from a fresh seed,
+    // so this keeps the rounds after the first one the same when they are replayed.
+    fn restart_food(&mut self) {
+        self.rng = StdRng::seed_from_u64(self.seed);
+        self.update_food();
+    }
+
+    pub fn level(&self) -> Option<&Level> {
+        self.level.as_ref()
+    }
+
+    // width and height of the board in blocks, including the border
+    pub fn board(&self) -> (i32, i32) {
+        (self.width, self.height)
+    }
+
+    // the level's obstacles, where they are on the window
+    pub fn obstacles(&self) -> Vec<(i32, i32)> {
+        self.level.iter()
+            .flat_map(|level| level.obstacles.iter())
+            .map(|(x, y)| (self.window_start_x + x, *y))
+            .collect()
+    }
+
+    // seed of the current round
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    // the current round so far, which plays the same way again on a core made with `from_replay`
+    pub fn replay(&self) -> Replay {
+        Replay {
+            seed: self.seed,
+            goal: self.goal,
+            puzzle: self.puzzle.map(|puzzle| puzzle.name.to_string()),
+            level: self.level.as_ref().and_then(|level| level.path.clone()),
+            turns: self.turns.clone(),
+            ticks: self.ticks,
+        }
+    }
+
+    pub fn is_game_over(&self) -> bool {
+        self.is_game_over
+    }
+
+    pub fn reached_goal(&self) -> bool {
+        self.reached_goal
+    }
+
+    pub fn snake(&self) -> &Snake {
+        &self.snake
+    }
+
+    pub fn food(&self) -> &[Food] {
+        &self.food_list
+    }
+
+    // lines that have been run, oldest first
+    pub fn program(&self) -> &[Program] {
+        &self.program
+    }
+
+    // the line the snake is building
+    pub fn prog_line(&self) -> &str {
+        &self.prog_line
+    }
+
+    pub fn jit(&self) -> &Jit {
+        &self.jit
+    }
+
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
+    // what can follow the start of a new line, given the program so far
+    fn new_line_tokens(&self) -> NextTokens {
+        let allowed = self.level.as_ref().and_then(|level| level.allowed.clone());
+        NextTokens::new(Globals::new(&self.jit, self.puzzle.is_some()).restricted_to(allowed))
+    }
+
+    // Moves the snake one block, turning first if a direction is given. Turning back into
+    // the snake's own neck is ignored like it is on the keyboard, and the snake stays put.
+    // Returns whether the snake moved (or died trying).
+    pub fn tick(&mut self, dir: Option<Direction>) -> bool {
+        if self.is_game_over {
+            return false;
+        }
+        if dir.is_some_and(|dir| dir == self.snake.head_direction().opposite()) {
+            return false;
+        }
+        if let Some(dir) = dir {
+            self.turns.push((self.ticks, dir));
+        }
+        self.ticks += 1;
+        if self.check_if_the_snake_alive(dir) {
+            self.snake.move_forward(dir, self.count_blocks);
+            self.check_eating();
+        } else {
+            self.is_game_over = true;
+        }
+        true
+    }
+
+    fn update_prog(&mut self) {
+        let instr_eaten = self.last_instr.clone();
+        let next_num_instr = self.snake.blocks_traveled().to_string();
+        self.snake.reset_blocks_traveled();
+        // The binding, parameter, field, function or struct the food brings with it. Only those
+        // foods have one, and a line left without a name fails to compile like any other.
+        let name = self.tokens.name_for(&instr_eaten).unwrap_or_default();
+        self.tokens.eat(&instr_eaten);
+        // lets open and close, and their bindings come into scope, as the line moves along
+        self.symbols.follow_lets(&self.tokens.let_scopes());
+
+        match instr_eaten.as_str() {
+            "int" => {
+                self.count_blocks = true;
+            }, //start of int blocks traveled
+            "id" => {}, //start of display of id blocks
+            "end_int" => { //end of int blocks traveled
+                self.count_blocks = false;
+                self.prog_line.push_str(&next_num_instr);
+            },
+            ";" => {
+                let (res, output, score) = self.run_line();
+                // x variables are ints, so a def line giving anything else fails instead
+                let res = match res {
+                    Ok(Some(value)) if self.is_def_line && !matches!(value, Value::Int(_)) => {
+                        let error = CompileError::TypeMismatch(format!("only ints can be saved, not {}", value),
+                            Span::new(0, self.prog_line.len()));
+                        Err(SnekError::Compile(error))
+                    },
+                    res => res,
+                };
+                if let Ok(Some(value)) = &res {
+                    if self.puzzle.is_none() && *value == Value::Int(self.goal) {
+                        self.reached_goal = true;
+                    }
+                    // save result of program line to heap or temp binding
+                    if let (Value::Int(n), true) = (value, self.is_def_line) {
+                        self.symbols.define(*n);
+                        self.jit.save_def(*n);
+                    }
+                }
+                if let (Some(puzzle), Some(score)) = (self.puzzle, score) {
+                    if score == puzzle.inputs.len() {
+                        self.reached_goal = true;
+                    }
+                }
+
+                // what the level has to say about the line, shown under it like its output
+                let mut level_notes = vec![];
+                if let Some(level) = &self.level {
+                    // the goal only counts once the program uses what the level asks for, in
+                    // lines that ran
+                    let mut lines: Vec<&str> = self.program.iter()
+                        .filter(|prog| prog.result.is_ok())
+                        .map(|prog| prog.line.as_str())
+                        .collect();
+                    if res.is_ok() {
+                        lines.push(&self.prog_line);
+                    }
+                    let missing = level.missing(&lines);
+                    if self.reached_goal && !missing.is_empty() {
+                        level_notes.push(format!("the level needs {}", missing.join(", ")));
+                        self.reached_goal = false;
+                    }
+                    if !self.reached_goal && level.max_lines.is_some_and(|max| self.program.len() + 1 >= max) {
+                        level_notes.push("out of lines".to_string());
+                        self.is_game_over = true;
+                    }
+                }
+
+                self.program.push(Program{
+                    line: self.prog_line.clone(), 
+                    result: res,
+                    output,
+                    score,
+                    asm: self.jit.asm().to_vec(),
+                    level_note: (!level_notes.is_empty()).then(|| level_notes.join(", ")),
+                });
+
+                // start new program line
+                self.prog_line = "( ".to_string();
+
+                self.tokens = self.new_line_tokens();
+                self.symbols.end_line();
+                self.is_def_line = false;
+                self.is_fun_line = false;
+                self.is_struct_line = false;
+                // self.snake = Snake::new(self.snake.head_position().0, self.snake.head_position().0); 
+            },
+            "def" => self.is_def_line = true,
+            "fun" => {
+                // the function's name and the paren opening its parameters come with it
+                self.is_fun_line = true;
+                self.prog_line = format!("fun {} ( ", name);
+            },
+            "struct" => {
+                // like fun, the struct's name and the brace opening its fields come with it
+                self.is_struct_line = true;
+                self.prog_line = format!("struct {} {{ ", name);
+            },
+            "field" => self.prog_line.push_str(&format!(" {} ", name)),
+            "param" => {
+                self.prog_line.push_str(&format!(" {} | ", name));
+                self.symbols.bind_param(name);
+            },
+            "var" => {
+                self.prog_line.push_str(" var ");
+                self.prog_line.push_str(&name);
+                self.prog_line.push_str(" := ");
+            },
+            "let" => {
+                self.prog_line.push_str(" let { ");
+            }
+            "{" => {
+                self.prog_line.push_str(" { ");
+            },
+            "}" => {
+                self.prog_line.push_str(" } ");
+            },
+            ")" => {
+                self.prog_line.push(' ');
+                self.prog_line.push_str(&instr_eaten);
+                self.prog_line.push(' ');
+            },
+            "+"| "-" | "*" | "/" | "%" | ">" | "<" | ">=" | "<=" | "=" | "if" | "true" | "false"
+                | "repeat-until" | "break" | "apply" | "null" | "alloc" | "lookup" | "index"
+                | "[" | "]" | "i64" | "boolean" | "print" => {
+                // if an existing var was eaten before this instr, don't add number of blocks moved to program
+                self.prog_line.push(' ');
+                self.prog_line.push_str(&instr_eaten);
+                self.prog_line.push(' ');
+            },
+            "(" => {
+                self.prog_line.push_str(&instr_eaten);
+                self.prog_line.push(' ');
+            },
+            ":=" | "add1" | "sub1" => {
+                self.prog_line.push_str(&instr_eaten);
+                self.prog_line.push(' ');
+            },
+            "set" => {
+                self.prog_line.push_str(" set ");
+            },
+            "|" => {
+                self.prog_line.push_str(" | ");
+            },
+            _ => match self.symbols.lookup(&instr_eaten) {
+                // just ate a heap variable, which the line holds as its value
+                Some(Binding { kind: BindingKind::Heap, value: Some(value), .. }) => {
+                    self.prog_line.push(' ');
+                    self.prog_line.push_str(&value.to_string());
+                },
+                // just ate a temp variable
+                Some(_) => {
+                    self.prog_line.push(' ');
+                    self.prog_line.push_str(&instr_eaten);
+                    self.prog_line.push(' ');
+                },
+                None => {
+                        self.prog_line.push(' ');
+                    self.prog_line.push_str(&instr_eaten);
+                    self.prog_line.push(' ');
+                },
+            },
+        }
+    }
+
+    fn check_eating(&mut self) {
+        let (head_x, head_y): (i32, i32) = self.snake.head_position();
+        let mut instr_eaten = "".to_string();
+        for food in &self.food_list {
+            if food.food_x == head_x && food.food_y == head_y { //ate food
+                instr_eaten = food.instr.clone();
+                break;
+            }
+        }
+        
+        if !instr_eaten.is_empty() { //ate something
+            self.last_instr = instr_eaten.clone();
+            self.update_prog();
+            self.update_food();
+            self.snake.restore_last_removed();
+        }
+    }
+
+    fn check_if_the_snake_alive(&self, dir: Option<Direction>) -> bool {
+        let (next_x, next_y) = self.snake.next_head_position(dir);
+
+        // Check if the snake hits itself
+        if self.snake.is_overlap_except_tail(next_x, next_y) {
+            return false;
+        }
+
+        if self.obstacles().contains(&(next_x, next_y)) {
+            return false;
+        }
+
+        // Check if the snake overlaps with the border
+        next_x > self.window_start_x && next_y > 0 && next_x < self.width + self.window_start_x - 1 && next_y < self.height - 1
+    }
+
+    fn update_food(&mut self) {
+        // in order, so that the same seed always puts the same food in the same place
+        let mut next_instrs = self.tokens.foods();
+        // the foods that finish the line go first, so that a board too full for every food
+        // leaves out the ones the line can do without
+        let finishing = self.tokens.completion().unwrap_or_default();
+        next_instrs.sort_by_key(|instr| !finishing.contains(instr));
+        // 5 end_int tokens if one token is end_int, the extra ones last
+        if next_instrs.iter().any(|instr| instr == "end_int") {
+            next_instrs.extend(std::iter::repeat("end_int".to_string()).take(4));
+        }
+
+        // anywhere inside the border that is not taken, in a shuffled order
+        let obstacles = self.obstacles();
+        let mut free_cells: Vec<(i32, i32)> = ((self.window_start_x + 1)..(self.window_start_x + self.width - 1))
+            .flat_map(|x| (1..(self.height - 1)).map(move |y| (x, y)))
+            .filter(|&(x, y)| !self.snake.is_overlap_except_tail(x, y) && !obstacles.contains(&(x, y)))
+            .collect();
+        free_cells.shuffle(&mut self.rng);
+
+        self.food_list = next_instrs.into_iter().zip(free_cells)
+            .map(|(instr, (food_x, food_y))| Food { food_x, food_y, instr })
+            .collect();
+    }
+
+    // Runs the finished line and gives back its result and what it printed. In a puzzle an
+    // expression line runs once per input; the first input's run is the one shown, and the
+    // score counts the inputs it got right.
+    fn run_line(&mut self) -> (Result<Option<Value>, SnekError>, Vec<String>, Option<usize>) {
+        let puzzle = match self.puzzle {
+            Some(puzzle) if !self.is_fun_line && !self.is_struct_line => puzzle,
+            _ => {
+                let res = self.jit.run_line(&self.prog_line);
+                return (res, self.jit.output().to_vec(), None);
+            },
+        };
+        let mut runs = vec![];
+        for input in puzzle.inputs {
+            self.jit.set_input(*input);
+            let res = self.jit.run_line(&self.prog_line);
+            runs.push((res, self.jit.output().to_vec()));
+        }
+        let results: Vec<Option<Value>> = runs.iter().map(|(res, _)| res.clone().ok().flatten()).collect();
+        let score = puzzle.score(&results);
+        let (res, output) = runs.swap_remove(0);
+        (res, output, Some(score))
+    }
+
+    // Writes the fun lines and the last line that ran to a file snek-compile can build.
+    // Def results only exist inside the game, so they are bound with a let around each body.
+    pub fn export_program(&self, path: &str) -> std::io::Result<()> {
+        let globals: Vec<String> = self.symbols.heap()
+            .filter_map(|binding| binding.value.map(|value| format!("var {} := {} |", binding.name, value)))
+            .collect();
+        let with_globals = |body: &str| match globals.is_empty() {
+            true => body.to_string(),
+            false => format!("let {{ {} }} {{ {} }}", globals.join(" "), body),
+        };
+
+        let mut contents = String::new();
+        for prog in &self.program {
+            if let Ok(None) = prog.result {
+                if prog.line.starts_with("struct") {
+                    contents.push_str(&format!("{};\n", prog.line.trim()));
+                    continue;
+                }
+                // fun fN ( params ) { body }; a line that is not shaped like that is left out
+                let (Some(open), Some(close)) = (prog.line.find('{'), prog.line.rfind('}')) else {
+                    continue;
+                };
+                if close < open {
+                    continue;
+                }
+                let body = prog.line[open + 1..close].trim();
+                contents.push_str(&format!("{}{{ {} }};\n", &prog.line[..open], with_globals(body)));
+            }
+        }
+        let main_line = self.program.iter().rev().find(|prog| matches!(prog.result, Ok(Some(_))));
+        match main_line {
+            Some(prog) => contents.push_str(&format!("{};\n", with_globals(&prog.line))),
+            None => return Err(std::io::Error::other("no line has run successfully yet")),
+        }
+        std::fs::write(path, contents)
+    }
+
+    // A new round keeps the goal but gets a fresh seed, so that it can be replayed on its own.
+    pub fn restart(&mut self) {
+        self.seed = self.rng.gen();
+        self.rng = StdRng::seed_from_u64(self.seed);
+        self.ticks = 0;
+        self.turns.clear();
+        self.snake = Snake::new(self.window_start_x + 2, 2); 
+        self.last_instr = "".to_string();
+        self.prog_line = "( ".to_string();
+        self.program.clear();
+        self.is_game_over = false;
+        self.is_def_line = false;
+        self.jit.clear();
+        self.symbols.clear();
+        self.tokens = self.new_line_tokens();
+        self.is_fun_line = false;
+        self.is_struct_line = false;
+        self.reached_goal = false;
+        self.update_food();
+
+    }
+}
@@ -0,0 +1,139 @@
+use im::HashMap;
+use std::mem;
+use dynasmrt::DynasmApi;
+
+use crate::compile::{check_fun, compile_line, instrs_to_asm};
+use crate::grammar::LineParser;
+use crate::lexer::Lexer;
+use crate::optimize::OptLevel;
+use crate::runtime::{RunCtx, Value};
+use crate::utils::{check_struct, instr_to_str, CompileError, Expr, FunDef, Instr, Line, SnekError, StructDef};
+
+// Everything a session of program lines shares: the results saved by def lines,
+// which later lines see as x0..xn, and the structs and functions defined so far.
+#[derive(Default)]
+pub struct Jit {
+    def_bindings: Vec<i64>,
+    struct_defs: Vec<StructDef>,
+    fun_defs: Vec<FunDef>,
+    // what the last line printed
+    output: Vec<String>,
+    // the instructions the last line compiled to, one per entry; a fun line's are its body's
+    asm: Vec<String>,
+    // what lines read as `input`
+    input: i64,
+    opt_level: OptLevel,
+    // the fuel, heap, output and error of the line being run
+    run: RunCtx,
+}
+
+impl Jit {
+    pub fn new() -> Jit {
+        Jit::default()
+    }
+
+    pub fn def_bindings(&self) -> &[i64] {
+        &self.def_bindings
+    }
+
+    pub fn struct_defs(&self) -> &[StructDef] {
+        &self.struct_defs
+    }
+
+    pub fn fun_defs(&self) -> &[FunDef] {
+        &self.fun_defs
+    }
+
+    // the value the following lines are run with as `input`
+    pub fn set_input(&mut self, input: i64) {
+        self.input = input;
+    }
+
+    pub fn set_opt_level(&mut self, opt_level: OptLevel) {
+        self.opt_level = opt_level;
+    }
+
+    pub fn output(&self) -> &[String] {
+        &self.output
+    }
+
+    pub fn asm(&self) -> &[String] {
+        &self.asm
+    }
+
+    // keep the result of a def line so later lines can use it as the next x variable
+    pub fn save_def(&mut self, value: i64) {
+        self.def_bindings.push(value);
+    }
+
+    pub fn clear(&mut self) {
+        self.def_bindings.clear();
+        self.struct_defs.clear();
+        self.fun_defs.clear();
+    }
+
+    // x0..xn are compiled in as constants
+    fn compilation_bindings(&self) -> HashMap<String, i64> {
+        self.def_bindings.iter().enumerate()
+            .map(|(idx, value)| (format!("x{}", idx), *value))
+            .collect()
+    }
+
+    // Runs one program line. Fun and struct lines are only checked and remembered, so they
+    // give back None.
+    pub fn run_line(&mut self, line: &str) -> Result<Option<Value>, SnekError> {
+        self.output.clear();
+        self.asm.clear();
+        let lexer = Lexer::new(line);
+        let parser = LineParser::new();
+        match parser.parse(lexer).map_err(CompileError::from)? {
+            Line::Fun(def) => {
+                self.define_fun(def)?;
+                Ok(None)
+            },
+            Line::Struct(def) => {
+                self.define_struct(def)?;
+                Ok(None)
+            },
+            Line::Expr(expression) => Ok(Some(self.run_expr(&expression)?)),
+        }
+    }
+
+    pub fn define_fun(&mut self, def: FunDef) -> Result<(), SnekError> {
+        let instrs = check_fun(&def, &self.struct_defs, &self.fun_defs, &self.compilation_bindings())?;
+        self.asm = to_asm_lines(&instrs);
+        self.fun_defs.push(def);
+        Ok(())
+    }
+
+    pub fn define_struct(&mut self, def: StructDef) -> Result<(), SnekError> {
+        check_struct(&def, &self.struct_defs)?;
+        self.struct_defs.push(def);
+        Ok(())
+    }
+
+    pub fn run_expr(&mut self, expression: &Expr) -> Result<Value, SnekError> {
+        self.output.clear();
+        self.asm.clear();
+        let mut ops = dynasmrt::x64::Assembler::new().unwrap();
+        let start = ops.offset();
+
+        let instrs = compile_line(expression, &self.struct_defs, &self.fun_defs, &self.compilation_bindings(), self.opt_level)?;
+        self.asm = to_asm_lines(&instrs);
+        instrs_to_asm(&instrs, &mut ops)?;
+        let buf = ops.finalize().unwrap();
+        let jitted_fn: extern "C" fn(i64, *mut RunCtx) -> i64 = unsafe { mem::transmute(buf.ptr(start)) };
+
+        self.run.reset();
+        let res = jitted_fn(self.input, &mut self.run);
+        self.output = self.run.take_output();
+        if let Some(error) = self.run.take_error() {
+            return Err(error.into());
+        }
+        Ok(self.run.decode(res))
+    }
+}
+
+fn to_asm_lines(instrs: &[Instr]) -> Vec<String> {
+    instrs.iter().map(|instr| instr_to_str(instr).trim_end().to_string()).collect()
+}
@@ -0,0 +1,169 @@
+use std::fs;
+
+use super::jit::Jit;
+use super::next_tokens::{Globals, NextTokens, KEYWORD_FOODS};
+use super::puzzle::Puzzle;
+
+// the window is laid out for a board this big, and food needs room on a smaller one
+pub const MAX_BOARD: (i32, i32) = (30, 20);
+pub const MIN_BOARD: (i32, i32) = (16, 10);
+// Room for every food at once, with the four extra end_ints that come after an int, and for
+// the snake, which grows a block with every food it eats, after a couple of long lines.
+const SNAKE_ROOM: usize = 40;
+pub const MIN_FREE_CELLS: usize = KEYWORD_FOODS.len() + 4 + SNAKE_ROOM;
+
+// Foods a level can ask for. Each of them is written into the line it is eaten into, so the
+// program shows whether it was used.
+const CONSTRUCTS: [&str; 12] = [
+    "let", "set", "if", "repeat-until", "break", "print", "fun", "apply", "struct", "alloc", "lookup", "index",
+];
+
+// One level of a campaign:
+//
+//   name first steps
+//   goal 12
+//   board 20 12
+//   max-lines 2
+//   allow int + *
+//   obstacle 8 4
+//   obstacle 8 5
+//   require let
+//
+// `puzzle <name>` can stand in for the goal. The rest is optional: the board is the full one
+// unless it is given, in blocks including the border, every food can appear unless there is
+// an allow list, and there is no limit on lines. Obstacles are counted from the board's top
+// left corner and end the round like the border does. Lines starting with "#" are comments.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Level {
+    pub name: String,
+    pub goal: Option<i64>,
+    pub puzzle: Option<String>,
+    pub board: (i32, i32),
+    pub max_lines: Option<usize>,
+    // the ")" and ";" ending every line, and end_int after int, are always allowed
+    pub allowed: Option<Vec<String>>,
+    pub obstacles: Vec<(i32, i32)>,
+    pub required: Vec<String>,
+    // the file the level was read from, which replays of it refer to
+    pub path: Option<String>,
+}
+
+// where the snake starts out, and the blocks in front of it, which cannot be obstacles
+fn in_snake_start(x: i32, y: i32) -> bool {
+    y == 2 && (2..8).contains(&x)
+}
+
+impl Level {
+    pub fn parse(src: &str) -> Result<Level, String> {
+        let mut name = None;
+        let mut goal = None;
+        let mut puzzle = None;
+        let mut board = MAX_BOARD;
+        let mut max_lines = None;
+        let mut allowed: Option<Vec<String>> = None;
+        let mut obstacles = vec![];
+        let mut required = vec![];
+        for (line_num, line) in src.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let bad_line = || format!("line {}: cannot read '{}'", line_num + 1, line);
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                ["name", rest @ ..] if !rest.is_empty() => name = Some(rest.join(" ")),
+                ["goal", value] => goal = Some(value.parse().map_err(|_| bad_line())?),
+                ["puzzle", value] => match Puzzle::by_name(value) {
+                    Some(found) => puzzle = Some(found.name.to_string()),
+                    None => return Err(format!("line {}: unknown puzzle {}", line_num + 1, value)),
+                },
+                ["board", width, height] => {
+                    board = (width.parse().map_err(|_| bad_line())?, height.parse().map_err(|_| bad_line())?);
+                    if !(MIN_BOARD.0..=MAX_BOARD.0).contains(&board.0) || !(MIN_BOARD.1..=MAX_BOARD.1).contains(&board.1) {
+                        return Err(format!("line {}: the board has to be from {} by {} to {} by {}",
+                            line_num + 1, MIN_BOARD.0, MIN_BOARD.1, MAX_BOARD.0, MAX_BOARD.1));
+                    }
+                },
+                ["max-lines", value] => max_lines = Some(value.parse().map_err(|_| bad_line())?),
+                ["allow", foods @ ..] => {
+                    if let Some(food) = foods.iter().find(|food| !KEYWORD_FOODS.contains(food)) {
+                        return Err(format!("line {}: {} is not a food", line_num + 1, food));
+                    }
+                    allowed.get_or_insert_with(Vec::new).extend(foods.iter().map(|food| food.to_string()));
+                },
+                ["obstacle", x, y] => {
+                    obstacles.push((x.parse().map_err(|_| bad_line())?, y.parse().map_err(|_| bad_line())?));
+                },
+                ["require", construct] if CONSTRUCTS.contains(construct) => required.push(construct.to_string()),
+                ["require", construct] => {
+                    return Err(format!("line {}: cannot require {}, only one of: {}",
+                        line_num + 1, construct, CONSTRUCTS.join(", ")));
+                },
+                _ => return Err(bad_line()),
+            }
+        }
+        // checked once the board is known, which can come after the obstacles
+        for (x, y) in &obstacles {
+            if !(1..board.0 - 1).contains(x) || !(1..board.1 - 1).contains(y) {
+                return Err(format!("obstacle {} {} is not inside the board", x, y));
+            }
+            if in_snake_start(*x, *y) {
+                return Err(format!("obstacle {} {} is in the snake's way at the start", x, y));
+            }
+        }
+        // food and the growing snake go on the free cells
+        let free_cells = (1..board.0 - 1)
+            .flat_map(|x| (1..board.1 - 1).map(move |y| (x, y)))
+            .filter(|&(x, y)| !obstacles.contains(&(x, y)) && !in_snake_start(x, y))
+            .count();
+        if free_cells < MIN_FREE_CELLS {
+            return Err(format!("the obstacles leave {} free cells, and food and the snake need {}", free_cells, MIN_FREE_CELLS));
+        }
+        if goal.is_some() == puzzle.is_some() {
+            return Err("a level needs either a goal or a puzzle".to_string());
+        }
+        if max_lines == Some(0) {
+            return Err("max-lines has to allow at least one line".to_string());
+        }
+        let level = Level {
+            name: name.ok_or("missing name")?,
+            goal,
+            puzzle,
+            board,
+            max_lines,
+            allowed,
+            obstacles,
+            required,
+            path: None,
+        };
+        // the foods offered are the ones a line can be finished with, so there have to be some
+        if level.first_line_tokens().completion().is_none() {
+            return Err("no line can be made from the allowed foods".to_string());
+        }
+        if let Some(allowed) = &level.allowed {
+            if let Some(construct) = level.required.iter().find(|construct| !allowed.contains(construct)) {
+                return Err(format!("{} is required but not allowed", construct));
+            }
+        }
+        Ok(level)
+    }
+
+    pub fn read(path: &str) -> Result<Level, String> {
+        let src = fs::read_to_string(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+        let level = Level::parse(&src).map_err(|e| format!("{}: {}", path, e))?;
+        Ok(Level { path: Some(path.to_string()), ..level })
+    }
+
+    // what can start the first line of the level
+    fn first_line_tokens(&self) -> NextTokens {
+        NextTokens::new(Globals::new(&Jit::new(), self.puzzle.is_some()).restricted_to(self.allowed.clone()))
+    }
+
+    // The required constructs that none of `lines` uses.
+    pub fn missing<'a>(&'a self, lines: &[&str]) -> Vec<&'a str> {
+        self.required.iter()
+            .filter(|construct| !lines.iter().any(|line| line.split_whitespace().any(|word| word == construct.as_str())))
+            .map(|construct| construct.as_str())
+            .collect()
+    }
+}
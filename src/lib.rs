@@ -3,8 +3,20 @@ pub mod lexer;
 pub mod tokens;
 pub mod snake;
 pub mod game;
+pub mod game_core;
+pub mod replay;
+pub mod puzzle;
+pub mod level;
+pub mod campaign;
 pub mod drawing;
 pub mod compile;
+pub mod optimize;
+pub mod regalloc;
+pub mod runtime;
+pub mod jit;
+pub mod next_tokens;
+pub mod symbols;
+pub mod eval;
 
 use lalrpop_util::lalrpop_mod;
 
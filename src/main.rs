@@ -4,15 +4,85 @@ extern crate rand;
 use piston_window::*;
 use piston_window::types::Color;
 
+use std::env;
+use std::process::exit;
+
 use rand::Rng;
+use snake_interpreter::campaign::Campaign;
 use snake_interpreter::game::{Game, GameState};
 use snake_interpreter::drawing::to_gui_coord_u32;
+use snake_interpreter::puzzle::{Puzzle, PUZZLES};
+use snake_interpreter::replay::Replay;
 
 const BACK_COLOR: Color = [0.204, 0.286, 0.369, 1.0];
 
 
 
+const USAGE: &str = "usage: snake-interpreter [--seed <n>] [--puzzle <name>] | --replay <file> | --campaign <dir>";
+
+// how the first round starts: from a seed, by playing back a recorded round, or at the
+// first level of a campaign that is not finished yet
+enum Start {
+    Seed(u64, Option<&'static Puzzle>),
+    Replay(Replay),
+    Campaign(Campaign),
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    exit(1);
+}
+
+// `--seed <n>` plays the board of an earlier run again and `--replay <file>` plays back a
+// round saved at its end; without either a fresh seed is picked. `--puzzle <name>` asks for
+// a line that works for each of the puzzle's inputs instead of a single goal value.
+// `--campaign <dir>` plays the levels in a directory one after the other.
+fn start_from_args() -> Start {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let [flag, path] = args.as_slice() {
+        if flag == "--replay" {
+            return Start::Replay(Replay::read(path).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                exit(1);
+            }));
+        }
+        if flag == "--campaign" {
+            let campaign = Campaign::load(path).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                exit(1);
+            });
+            if campaign.current().is_none() {
+                eprintln!("every level in {} is finished; remove its progress.txt to play them again", path);
+                exit(0);
+            }
+            return Start::Campaign(campaign);
+        }
+    }
+
+    let mut seed = None;
+    let mut puzzle = None;
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let value = args.next().unwrap_or_else(|| usage());
+        match flag.as_str() {
+            "--seed" if seed.is_none() => seed = Some(value.parse().unwrap_or_else(|_| {
+                eprintln!("seed must be a non-negative integer, got {}", value);
+                exit(1);
+            })),
+            "--puzzle" if puzzle.is_none() => puzzle = Some(Puzzle::by_name(value).unwrap_or_else(|| {
+                let names: Vec<&str> = PUZZLES.iter().map(|puzzle| puzzle.name).collect();
+                eprintln!("unknown puzzle {}, pick one of: {}", value, names.join(", "));
+                exit(1);
+            })),
+            _ => usage(),
+        }
+    }
+    Start::Seed(seed.unwrap_or_else(|| rand::thread_rng().gen()), puzzle)
+}
+
 fn main() {
+    let start = start_from_args();
+
     let (width, height) = (70, 20);
     let snake_window_start_x = 10;
     let game_window_width = (to_gui_coord_u32(width - snake_window_start_x) as f64) / 2.0;
@@ -28,8 +98,32 @@ fn main() {
     let mut window: PistonWindow = window_settings.build().unwrap();
 
     // Create a snake
-    let goal = rand::thread_rng().gen_range(0..100);
-    let mut game = Game::new(snake_window_start_x, (width - snake_window_start_x) / 2, height, goal);
+    let game_width = (width - snake_window_start_x) / 2;
+    // a level of the campaign, with a fresh seed, or None once every level is finished
+    let level_game = |campaign: &Campaign| campaign.current().map(|(num, level)| {
+        println!("level {} of {}: {}", num, campaign.num_levels(), level.name);
+        Game::for_level(snake_window_start_x, game_width, height, rand::thread_rng().gen(), level.clone())
+    });
+    let mut campaign = None;
+    let mut game = match start {
+        Start::Seed(seed, puzzle) => {
+            println!("seed: {}", seed);
+            Game::new(snake_window_start_x, game_width, height, seed, puzzle)
+        },
+        Start::Replay(replay) => {
+            println!("replaying seed {} for {} ticks", replay.seed, replay.ticks);
+            Game::from_replay(snake_window_start_x, game_width, height, replay).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                exit(1);
+            })
+        },
+        Start::Campaign(started) => {
+            let game = level_game(&started).unwrap();
+            campaign = Some(started);
+            game
+        },
+    };
+    let mut goal = game.goal_text();
     let mut curr_game_state = GameState::StartScreen;
     let mut font = window.load_font("src/Poppins-Bold.ttf").unwrap(); // Load a font
 
@@ -91,7 +185,7 @@ fn main() {
                     
                     text::Text::new_color([1.0, 1.0, 1.0, 1.0], 30)
                         .draw(
-                            &("Goal: ".to_owned() + &goal.to_string()),
+                            &("Goal: ".to_owned() + &goal),
                             &mut font,
                             &DrawState::default(),
                             transform,
@@ -161,18 +255,39 @@ fn main() {
                     curr_game_state = game.update(arg.dt);
                     
                 });
+                // a level counts as finished as soon as its goal is reached
+                if let (GameState::ReachedGoal, Some(campaign)) = (&curr_game_state, campaign.as_mut()) {
+                    if let Err(error) = campaign.finish_current() {
+                        println!("{}", error);
+                    }
+                }
             },
             GameState::ReachedGoal => {
+                let campaign_done = campaign.as_ref().is_some_and(|campaign| campaign.current().is_none());
                 if let Some(Button::Keyboard(Key::Return)) = event.press_args() {
-                    curr_game_state = GameState::GameStarted;
+                    // in a campaign, the next level; after the last one there is nothing to go on to
+                    match campaign.as_ref().map(level_game) {
+                        Some(Some(next)) => {
+                            game = next;
+                            goal = game.goal_text();
+                            curr_game_state = GameState::GameStarted;
+                        },
+                        Some(None) => {},
+                        None => curr_game_state = GameState::GameStarted,
+                    }
                 }
+                let message = match (&campaign, campaign_done) {
+                    (Some(_), true) => "Every level is done. You ARE the king cobra! Press escape to quit.",
+                    (Some(_), false) => "Level done! Press enter for the next level.",
+                    (None, _) => "Congrats! You won! You ARE the king cobra. yay! Press enter to restart.",
+                };
                 window.draw_2d(&event, |c, g, device| {
                     clear([0.102, 0.58, 0.063, 1.0], g); // green background for you won :)
     
                     let transform = c.transform.trans(320.0, 240.0); // Position for the text
                     text::Text::new_color([1.0, 1.0, 1.0, 1.0], 32)
                         .draw(
-                            "Congrats! You won! You ARE the king cobra. yay! Press enter to restart.",
+                            message,
                             &mut font,
                             &DrawState::default(),
                             transform,
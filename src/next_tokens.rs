@@ -0,0 +1,849 @@
+use std::mem;
+use std::rc::Rc;
+
+use im::HashMap;
+
+use super::compile::global_types;
+use super::jit::Jit;
+use super::utils::{unify, Type};
+
+// Foods that are spelled the same wherever they appear. Names are tried on top of these.
+pub const KEYWORD_FOODS: [&str; 45] = [
+    "def", "fun", "struct", "(", ")", "{", "}", "[", "]", "|", ":=", ";",
+    "+", "-", "*", "/", "%", ">", "<", ">=", "<=", "=", "add1", "sub1",
+    "let", "var", "set", "if", "repeat-until", "break", "print", "apply", "alloc", "lookup", "index",
+    "null", "true", "false", "int", "end_int", "id", "param", "field", "i64", "boolean",
+];
+
+// The foods that bring a line closest to its end, best first. A completion tries these
+// before anything else, which ends the open frames rather than starting new ones.
+const CLOSING_FOODS: [&str; 13] = [";", ")", "}", "|", "]", "{", "end_int", "=", "true", "int", "null", ":=", "alloc"];
+
+// how many foods a completion may take, and how many it may try on the way
+const COMPLETION_DEPTH: usize = 200;
+const COMPLETION_TRIES: usize = 5000;
+
+const ARITH_OPS: [&str; 5] = ["+", "-", "*", "/", "%"];
+const CMP_OPS: [&str; 5] = [">", "<", ">=", "<=", "="];
+
+// What a line can refer to besides its own bindings and parameters.
+#[derive(Debug, Default)]
+pub struct Globals {
+    // the x variables, and `input` in a puzzle; they are all ints
+    vars: Vec<String>,
+    // name, number of parameters and result of each function
+    funs: Vec<(String, usize, Type)>,
+    structs: Vec<(String, Vec<(String, Type)>)>,
+    // the only foods a level lets appear, if it limits them
+    allowed: Option<Vec<String>>,
+}
+
+impl Globals {
+    pub fn new(jit: &Jit, input: bool) -> Globals {
+        let mut vars: Vec<String> = (0..jit.def_bindings().len()).map(|idx| format!("x{}", idx)).collect();
+        if input {
+            vars.push("input".to_string());
+        }
+        // every function on the jit has been checked, so its type is there
+        let defined_vars: HashMap<String, i64> = vars.iter().map(|name| (name.clone(), 0)).collect();
+        let types = global_types(&defined_vars, jit.struct_defs(), jit.fun_defs()).unwrap_or_default();
+        let funs = jit.fun_defs().iter()
+            .filter_map(|def| match types.get(&def.name) {
+                Some(Type::Fun(params, ret)) => Some((def.name.clone(), params.len(), (**ret).clone())),
+                _ => None,
+            })
+            .collect();
+        let structs = jit.struct_defs().iter().map(|def| (def.name.clone(), def.fields.clone())).collect();
+        Globals { vars, funs, structs, allowed: None }
+    }
+
+    // Only offers the keyword foods in `allowed`, and the ")" and ";" that end every line,
+    // and end_int if int is there. Names come with whatever food asks for them.
+    pub fn restricted_to(self, allowed: Option<Vec<String>>) -> Globals {
+        Globals { allowed, ..self }
+    }
+
+    fn allows(&self, food: &str) -> bool {
+        let Some(allowed) = &self.allowed else { return true };
+        let listed = |food: &str| allowed.iter().any(|other| other == food);
+        match food {
+            ")" | ";" => true,
+            "end_int" => listed("int"),
+            _ => !KEYWORD_FOODS.contains(&food) || listed(food),
+        }
+    }
+}
+
+// One step of a production: a food, a child that is parsed by a frame of its own, or a name.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Item {
+    Food(&'static str),
+    Expr,
+    Summand,
+    // a summand, possibly under add1 or sub1
+    Factor,
+    Name(Names),
+    // zero or more of something, ended by whatever comes after it
+    Repeat(Repeat),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Names {
+    // variables in scope, after "id"
+    Var,
+    // let bindings and parameters in scope, which set can change
+    Settable,
+    Fun,
+    Struct,
+    // fields of the struct the pointer before "[" points to
+    Field,
+    // i64, boolean or a struct, including the one being declared
+    FieldType,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Repeat {
+    // "var" Expression "|"
+    Bindings,
+    // "param", which comes with its "|"
+    Params,
+    // "field" FieldType "|"
+    Fields,
+    // an operator and the factor after it
+    Ops,
+}
+
+#[derive(Debug, Clone)]
+enum Kind {
+    // "(" Expression ")" ";", where the "(" is on the line before anything is eaten. A def
+    // line's result is saved as the next x variable, which only holds ints.
+    ExprLine { def: bool },
+    FunLine { name: String, recursive: bool },
+    StructLine { name: String },
+    // Comparison, Term and Addend at once: factors joined by operators, with at most one
+    // comparison. Every arithmetic operator takes and gives ints, so precedence does not
+    // change what a line can type as.
+    Operation,
+    UnOp,
+    Int,
+    Id,
+    Paren,
+    Apply,
+    Alloc,
+    Lookup,
+    Update,
+    Let,
+    Set,
+    If,
+    Loop { breaks: Vec<Type> },
+    // the stack index of the loop it leaves
+    Break { target: usize },
+    Print,
+}
+
+// The rest of grammar.lalrpop's productions, as the foods spell them. A name after "apply"
+// or "alloc" adds its arguments once it is known how many there are.
+fn production(kind: &Kind) -> Vec<Item> {
+    use Item::*;
+    match kind {
+        Kind::ExprLine { .. } => vec![Expr, Food(")"), Food(";")],
+        Kind::FunLine { .. } => vec![Repeat(self::Repeat::Params), Food(")"), Food("{"), Expr, Food("}"), Food(";")],
+        Kind::StructLine { .. } => vec![Repeat(self::Repeat::Fields), Food("}"), Food(";")],
+        Kind::Operation => vec![Factor, Repeat(self::Repeat::Ops)],
+        Kind::UnOp => vec![Summand],
+        Kind::Int => vec![Food("end_int")],
+        Kind::Id => vec![Name(Names::Var)],
+        Kind::Paren => vec![Expr, Food(")")],
+        Kind::Apply => vec![Name(Names::Fun)],
+        Kind::Alloc => vec![Name(Names::Struct)],
+        Kind::Lookup => vec![Summand, Food("["), Name(Names::Field), Food("]")],
+        Kind::Update => vec![Summand, Food("["), Name(Names::Field), Food("]"), Food(":="), Expr],
+        // "let" comes with the "{" opening the bindings
+        Kind::Let => vec![Repeat(self::Repeat::Bindings), Food("}"), Food("{"), Expr, Food("}")],
+        Kind::Set => vec![Food("id"), Name(Names::Settable), Food(":="), Expr],
+        Kind::If => vec![Expr, Food("{"), Expr, Food("}"), Food("{"), Expr, Food("}")],
+        Kind::Loop { .. } => vec![Food("{"), Expr, Food("}"), Food("{"), Expr, Food("}")],
+        Kind::Break { .. } | Kind::Print => vec![Expr],
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Frame {
+    kind: Kind,
+    // the production being worked through, and how far it got
+    items: Vec<Item>,
+    pos: usize,
+    // types of the children finished so far, in order
+    types: Vec<Type>,
+    // names picked so far: bindings, parameters, fields or operators, or the one name a
+    // call, alloc, field access or set is about
+    names: Vec<String>,
+}
+
+impl Frame {
+    fn new(kind: Kind) -> Frame {
+        Frame { items: production(&kind), kind, pos: 0, types: vec![], names: vec![] }
+    }
+
+    fn item(&self) -> Option<Item> {
+        self.items.get(self.pos).copied()
+    }
+
+    fn at_child(&self) -> bool {
+        matches!(self.item(), Some(Item::Expr | Item::Summand | Item::Factor))
+    }
+}
+
+// What the frames between the one being looked at and the top commit the frames below to:
+// break values their loop has to agree with, and whether a fun line calls itself.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Ctx {
+    breaks: Vec<(usize, Type)>,
+    recursive: bool,
+}
+
+// the types a value can have to be stored where `target` is expected
+fn fits(target: &Type) -> Vec<Type> {
+    match target {
+        Type::Struct(_) => vec![target.clone(), Type::Null],
+        _ => vec![target.clone()],
+    }
+}
+
+// a comparison needs ints on both sides, except "=", which needs types that unify
+fn compatible(op: &str, lhs: &Type, rhs: &Type) -> bool {
+    match op {
+        "=" => unify(lhs, rhs).is_some(),
+        _ => *lhs == Type::Int && *rhs == Type::Int,
+    }
+}
+
+// the type of factors joined by arithmetic operators only
+fn side_type(types: &[Type], ops: &[String]) -> Option<Type> {
+    match ops.is_empty() {
+        true => types.first().cloned(),
+        false => types.iter().all(|ty| *ty == Type::Int).then_some(Type::Int),
+    }
+}
+
+// The foods that can come next on a program line, worked out from the grammar one food
+// at a time, together with the types and scopes of what has been eaten so far. A food is
+// only offered if the line can still be finished into one that typechecks after it.
+#[derive(Debug, Clone)]
+pub struct NextTokens {
+    globals: Rc<Globals>,
+    stack: Vec<Frame>,
+    // only def, fun and struct lines begin with their own food
+    line_start: bool,
+    // let bindings started on this line, which is also the number in the next one's name
+    bindings: usize,
+    // set once a frame finishes with types that do not fit it
+    ill_typed: bool,
+}
+
+impl NextTokens {
+    pub fn new(globals: Globals) -> NextTokens {
+        NextTokens {
+            globals: Rc::new(globals),
+            stack: vec![Frame::new(Kind::ExprLine { def: false })],
+            line_start: true,
+            bindings: 0,
+            ill_typed: false,
+        }
+    }
+
+    // The let bindings in scope for the next food, one list for each let around it,
+    // outermost first. Like compile_to_instrs, a binding only comes into scope once its
+    // value is finished, and goes out of it with the "}" ending its let's body.
+    pub fn let_scopes(&self) -> Vec<Vec<String>> {
+        self.stack.iter()
+            .filter(|frame| matches!(frame.kind, Kind::Let))
+            .map(|frame| frame.names.iter().take(frame.types.len()).cloned().collect())
+            .collect()
+    }
+
+    // whether the ";" ending the line has been eaten
+    pub fn is_finished(&self) -> bool {
+        self.stack.is_empty()
+    }
+
+    // The name a food brings with it if it is eaten now: a let binding for "var", a
+    // parameter for "param", a field for "field" and the new function or struct.
+    pub fn name_for(&self, food: &str) -> Option<String> {
+        let count = self.stack.last().map_or(0, |frame| frame.names.len());
+        match food {
+            "var" => Some(format!("y{}", self.bindings)),
+            "param" => Some(format!("a{}", count)),
+            "field" => Some(format!("g{}", count)),
+            "fun" => Some(format!("f{}", self.globals.funs.len())),
+            "struct" => Some(format!("s{}", self.globals.structs.len())),
+            _ => None,
+        }
+    }
+
+    // Every food that can come next, in order.
+    pub fn foods(&self) -> Vec<String> {
+        let mut candidates = self.candidates();
+        candidates.retain(|food| {
+            let mut next = self.clone();
+            next.step(food) && next.viable()
+        });
+        // viable counts on every food being there to build a child from, which an allow
+        // list can take away, so then the line has to be finished for real
+        if self.globals.allowed.is_some() {
+            candidates.retain(|food| {
+                let mut next = self.clone();
+                next.eat(food) && next.completion().is_some()
+            });
+        }
+        candidates
+    }
+
+    // Foods that finish the line from here, which shows that it can be finished at all. The
+    // search eats the closing foods first and backs up out of dead ends, and gives up after
+    // COMPLETION_TRIES foods; a line `viable` lets through never needs that many.
+    pub fn completion(&self) -> Option<Vec<String>> {
+        let mut tries = COMPLETION_TRIES;
+        let mut foods = self.finish(COMPLETION_DEPTH, &mut tries)?;
+        foods.reverse();
+        Some(foods)
+    }
+
+    // the foods of a completion, last first
+    fn finish(&self, depth: usize, tries: &mut usize) -> Option<Vec<String>> {
+        if self.is_finished() {
+            return Some(vec![]);
+        }
+        if depth == 0 {
+            return None;
+        }
+        let mut candidates = self.candidates();
+        candidates.sort_by_key(|food| {
+            CLOSING_FOODS.iter().position(|closing| closing == food).unwrap_or(CLOSING_FOODS.len())
+        });
+        for food in candidates {
+            if *tries == 0 {
+                return None;
+            }
+            *tries -= 1;
+            let mut next = self.clone();
+            if !next.eat(&food) {
+                continue;
+            }
+            if let Some(mut rest) = next.finish(depth - 1, tries) {
+                rest.push(food);
+                return Some(rest);
+            }
+        }
+        None
+    }
+
+    // every food that could be on offer, before the line is checked against them
+    fn candidates(&self) -> Vec<String> {
+        let mut candidates: Vec<String> = KEYWORD_FOODS.iter().map(|food| food.to_string()).collect();
+        candidates.extend(self.globals.vars.iter().cloned());
+        candidates.extend(self.globals.funs.iter().map(|(name, _, _)| name.clone()));
+        for (name, fields) in &self.globals.structs {
+            candidates.push(name.clone());
+            candidates.extend(fields.iter().map(|(field, _)| field.clone()));
+        }
+        for frame in &self.stack {
+            match &frame.kind {
+                Kind::Let => candidates.extend(frame.names.iter().cloned()),
+                Kind::FunLine { name, .. } | Kind::StructLine { name } => {
+                    candidates.push(name.clone());
+                    candidates.extend(frame.names.iter().cloned());
+                },
+                _ => {},
+            }
+        }
+        candidates.sort();
+        candidates.dedup();
+        candidates.retain(|food| self.globals.allows(food));
+        candidates
+    }
+
+    // Moves past one food, if it is one of `foods`.
+    pub fn eat(&mut self, food: &str) -> bool {
+        let mut next = self.clone();
+        if !(next.step(food) && next.viable()) {
+            return false;
+        }
+        *self = next;
+        true
+    }
+
+    // the types a child can be given, which are all the ones something can be built for
+    fn universe(&self) -> Vec<Type> {
+        let mut types = vec![Type::Int, Type::Bool, Type::Null];
+        types.extend(self.globals.structs.iter().map(|(name, _)| Type::Struct(name.clone())));
+        types
+    }
+
+    // what a null could turn into once it is unified with whatever comes later
+    fn closure(&self, ty: &Type) -> Vec<Type> {
+        match ty {
+            Type::Null => self.universe().into_iter().filter(|other| unify(ty, other).is_some()).collect(),
+            _ => vec![ty.clone()],
+        }
+    }
+
+    fn fields(&self, ty: &Type) -> Option<&[(String, Type)]> {
+        let Type::Struct(name) = ty else { return None };
+        self.globals.structs.iter()
+            .find(|(other, _)| other == name)
+            .map(|(_, fields)| fields.as_slice())
+    }
+
+    fn field_type(&self, pointer: &Type, field: &str) -> Option<Type> {
+        let fields = self.fields(pointer)?;
+        fields.iter().find(|(name, _)| name == field).map(|(_, ty)| ty.clone())
+    }
+
+    // Variables that the frame at `idx` sees, innermost first: the finished bindings of the
+    // lets around it, the parameters of a fun line and, unless they are to be set, the
+    // global ones.
+    fn scope(&self, idx: usize, settable: bool) -> Vec<(String, Type)> {
+        let mut vars: Vec<(String, Type)> = vec![];
+        for frame in self.stack[..idx].iter().rev() {
+            match frame.kind {
+                Kind::Let => vars.extend(frame.names.iter().cloned().zip(frame.types.iter().cloned()).rev()),
+                Kind::FunLine { .. } => vars.extend(frame.names.iter().map(|name| (name.clone(), Type::Int))),
+                _ => {},
+            }
+        }
+        if !settable {
+            vars.extend(self.globals.vars.iter().map(|name| (name.clone(), Type::Int)));
+        }
+        let mut seen: Vec<String> = vec![];
+        vars.retain(|(name, _)| {
+            let shadowed = seen.contains(name);
+            seen.push(name.clone());
+            !shadowed
+        });
+        vars
+    }
+
+    // functions that can be called: the earlier ones and the one being defined, which
+    // returns an int as far as its own body is concerned
+    fn callable(&self) -> Vec<(String, usize, Type)> {
+        let mut funs = self.globals.funs.clone();
+        if let Some(Frame { kind: Kind::FunLine { name, .. }, names, .. }) = self.stack.first() {
+            funs.push((name.clone(), names.len(), Type::Int));
+        }
+        funs
+    }
+
+    fn is_self(&self, fun: &str) -> bool {
+        matches!(self.stack.first(), Some(Frame { kind: Kind::FunLine { name, .. }, .. }) if name == fun)
+    }
+
+    // the names that can be picked for the top frame's current item
+    fn names(&self, names: Names) -> Vec<String> {
+        let idx = self.stack.len() - 1;
+        let frame = &self.stack[idx];
+        match names {
+            Names::Var | Names::Settable => {
+                self.scope(idx, names == Names::Settable).into_iter().map(|(name, _)| name).collect()
+            },
+            Names::Fun => self.callable().into_iter().map(|(name, _, _)| name).collect(),
+            Names::Struct => self.globals.structs.iter().map(|(name, _)| name.clone()).collect(),
+            Names::Field => frame.types.first()
+                .and_then(|pointer| self.fields(pointer))
+                .map_or(vec![], |fields| fields.iter().map(|(name, _)| name.clone()).collect()),
+            Names::FieldType => {
+                let mut types = vec!["i64".to_string(), "boolean".to_string()];
+                types.extend(self.globals.structs.iter().map(|(name, _)| name.clone()));
+                if let Kind::StructLine { name } = &frame.kind {
+                    types.push(name.clone());
+                }
+                types
+            },
+        }
+    }
+
+    fn push(&mut self, kind: Kind) {
+        self.stack.push(Frame::new(kind));
+    }
+
+    // Takes one food through the grammar, without checking types. A frame that is waiting
+    // on something optional ends when the food is not that, and hands it to its parent.
+    fn step(&mut self, food: &str) -> bool {
+        if mem::take(&mut self.line_start) {
+            match food {
+                "def" => {
+                    self.stack[0].kind = Kind::ExprLine { def: true };
+                    return true;
+                },
+                "fun" => {
+                    let name = format!("f{}", self.globals.funs.len());
+                    self.stack = vec![Frame::new(Kind::FunLine { name, recursive: false })];
+                    return true;
+                },
+                "struct" => {
+                    let name = format!("s{}", self.globals.structs.len());
+                    self.stack = vec![Frame::new(Kind::StructLine { name })];
+                    return true;
+                },
+                _ => {},
+            }
+        }
+        loop {
+            let Some(top) = self.stack.last() else { return false };
+            let Some(item) = top.item() else {
+                self.finish_top();
+                continue;
+            };
+            match item {
+                Item::Food(expected) => {
+                    if food != expected {
+                        return false;
+                    }
+                    self.advance();
+                    return true;
+                },
+                Item::Name(names) => {
+                    if !self.names(names).iter().any(|name| name == food) {
+                        return false;
+                    }
+                    self.pick(names, food);
+                    return true;
+                },
+                Item::Repeat(repeat) => {
+                    if self.repeat(repeat, food) {
+                        return true;
+                    }
+                    self.stack.last_mut().unwrap().pos += 1;
+                },
+                Item::Expr | Item::Summand | Item::Factor => return self.start(item, food),
+            }
+        }
+    }
+
+    // starts the child the top frame is waiting for with its first food
+    fn start(&mut self, item: Item, food: &str) -> bool {
+        if item == Item::Expr {
+            let body = match food {
+                "let" => Kind::Let,
+                "set" => Kind::Set,
+                "if" => Kind::If,
+                "repeat-until" => Kind::Loop { breaks: vec![] },
+                "break" => match self.stack.iter().rposition(|frame| matches!(frame.kind, Kind::Loop { .. })) {
+                    Some(target) => Kind::Break { target },
+                    None => return false,
+                },
+                "print" => Kind::Print,
+                "index" => Kind::Update,
+                _ => {
+                    self.push(Kind::Operation);
+                    return self.start(Item::Factor, food);
+                },
+            };
+            self.push(body);
+            return true;
+        }
+        match food {
+            "add1" | "sub1" if item == Item::Factor => self.push(Kind::UnOp),
+            "int" => self.push(Kind::Int),
+            "id" => self.push(Kind::Id),
+            "(" => self.push(Kind::Paren),
+            "apply" => self.push(Kind::Apply),
+            "alloc" => self.push(Kind::Alloc),
+            "lookup" => self.push(Kind::Lookup),
+            "true" | "false" => self.child_done(Type::Bool),
+            "null" => self.child_done(Type::Null),
+            _ => return false,
+        }
+        true
+    }
+
+    // One more round of a repeated item, if `food` starts one. What the round needs is put
+    // in front of the item, which comes up again after it.
+    fn repeat(&mut self, repeat: Repeat, food: &str) -> bool {
+        let name = self.name_for(food);
+        let top = self.stack.last_mut().unwrap();
+        let round = match (repeat, food) {
+            (Repeat::Bindings, "var") => vec![Item::Expr, Item::Food("|")],
+            (Repeat::Params, "param") => vec![],
+            (Repeat::Fields, "field") => vec![Item::Name(Names::FieldType), Item::Food("|")],
+            (Repeat::Ops, op) if ARITH_OPS.contains(&op) => vec![Item::Factor],
+            (Repeat::Ops, op) if CMP_OPS.contains(&op) && !top.names.iter().any(|op| CMP_OPS.contains(&op.as_str())) => {
+                vec![Item::Factor]
+            },
+            _ => return false,
+        };
+        top.names.push(name.unwrap_or_else(|| food.to_string()));
+        top.items.splice(top.pos..top.pos, round);
+        // a let nested in the value gets the names after this one
+        if repeat == Repeat::Bindings {
+            self.bindings += 1;
+        }
+        true
+    }
+
+    fn pick(&mut self, names: Names, name: &str) {
+        let idx = self.stack.len() - 1;
+        match names {
+            Names::Var => {
+                let ty = self.scope(idx, false).into_iter().find(|(var, _)| var == name).unwrap().1;
+                self.stack[idx].types.push(ty);
+            },
+            Names::Fun => {
+                let arity = self.callable().into_iter().find(|(fun, _, _)| fun == name).unwrap().1;
+                self.expand_args(arity);
+            },
+            Names::Struct => {
+                let arity = self.globals.structs.iter().find(|(other, _)| other == name).unwrap().1.len();
+                self.expand_args(arity);
+            },
+            Names::Settable | Names::Field | Names::FieldType => {},
+        }
+        if names != Names::Var && names != Names::FieldType {
+            self.stack[idx].names.push(name.to_string());
+        }
+        self.advance();
+    }
+
+    // "(" (Expression "|")* ")" with one argument per parameter or field
+    fn expand_args(&mut self, arity: usize) {
+        let top = self.stack.last_mut().unwrap();
+        top.items.push(Item::Food("("));
+        for _ in 0..arity {
+            top.items.extend([Item::Expr, Item::Food("|")]);
+        }
+        top.items.push(Item::Food(")"));
+    }
+
+    fn advance(&mut self) {
+        self.stack.last_mut().unwrap().pos += 1;
+        self.settle();
+    }
+
+    fn child_done(&mut self, ty: Type) {
+        let top = self.stack.last_mut().unwrap();
+        top.types.push(ty);
+        top.pos += 1;
+        self.settle();
+    }
+
+    // ends the frames that have nothing left to wait for
+    fn settle(&mut self) {
+        while self.stack.last().is_some_and(|top| top.item().is_none()) {
+            self.finish_top();
+        }
+    }
+
+    fn finish_top(&mut self) {
+        // a frame can finish on the same food as its last child, before viable sees it
+        if self.results(self.stack.len() - 1, None, &Ctx::default()).is_empty() {
+            self.ill_typed = true;
+        }
+        let frame = self.stack.pop().unwrap();
+        let ty = match self.ill_typed {
+            true => Type::Int,
+            false => self.final_type(&frame),
+        };
+        match frame.kind {
+            Kind::Break { target } => {
+                if let Kind::Loop { breaks } = &mut self.stack[target].kind {
+                    breaks.push(ty.clone());
+                }
+            },
+            Kind::Apply if self.is_self(&frame.names[0]) => {
+                if let Kind::FunLine { recursive, .. } = &mut self.stack[0].kind {
+                    *recursive = true;
+                }
+            },
+            _ => {},
+        }
+        if !self.stack.is_empty() {
+            self.child_done(ty);
+        }
+    }
+
+    // the type of a finished frame, which viable has made sure there is
+    fn final_type(&self, frame: &Frame) -> Type {
+        let types = &frame.types;
+        match &frame.kind {
+            Kind::Operation if frame.names.iter().any(|op| CMP_OPS.contains(&op.as_str())) => Type::Bool,
+            Kind::Operation if !frame.names.is_empty() => Type::Int,
+            Kind::UnOp | Kind::Int => Type::Int,
+            Kind::Apply => self.callable().into_iter().find(|(name, _, _)| *name == frame.names[0]).unwrap().2,
+            Kind::Alloc => Type::Struct(frame.names[0].clone()),
+            Kind::Lookup => self.field_type(&types[0], &frame.names[0]).unwrap(),
+            Kind::Update => types[1].clone(),
+            Kind::If => unify(&types[1], &types[2]).unwrap(),
+            Kind::Loop { breaks } => breaks.iter().fold(types[0].clone(), |ty, other| unify(&ty, other).unwrap()),
+            Kind::Let => types.last().unwrap().clone(),
+            Kind::ExprLine { .. } | Kind::FunLine { .. } | Kind::StructLine { .. } => Type::Int,
+            _ => types[0].clone(),
+        }
+    }
+
+    // Whether the line can still be finished into one that typechecks. Starting from the
+    // types the top frame can still end up with, each frame below turns the types its
+    // child can have into the ones it can have itself, down to the line.
+    fn viable(&self) -> bool {
+        if self.ill_typed {
+            return false;
+        }
+        let Some(top) = self.stack.len().checked_sub(1) else { return true };
+        let ctx = Ctx::default();
+        let mut states: Vec<(Type, Ctx)> = match self.stack[top].at_child() {
+            // nothing of the child has been eaten yet, so it can still be anything
+            true => self.universe().iter().flat_map(|ty| self.results(top, Some(ty), &ctx)).collect(),
+            false => self.results(top, None, &ctx),
+        };
+        for idx in (0..top).rev() {
+            let mut next: Vec<(Type, Ctx)> = vec![];
+            for (ty, ctx) in &states {
+                for state in self.results(idx, Some(ty), ctx) {
+                    if !next.contains(&state) {
+                        next.push(state);
+                    }
+                }
+            }
+            states = next;
+        }
+        !states.is_empty()
+    }
+
+    // The types the frame at `idx` can end up with, given the type of the child it is
+    // working on, if any, and what the frames above commit it to.
+    fn results(&self, idx: usize, child: Option<&Type>, ctx: &Ctx) -> Vec<(Type, Ctx)> {
+        let frame = &self.stack[idx];
+        let mut types = frame.types.clone();
+        types.extend(child.cloned());
+        let names = &frame.names;
+        let only = |tys: Vec<Type>| tys.into_iter().map(|ty| (ty, ctx.clone())).collect::<Vec<_>>();
+        match &frame.kind {
+            Kind::ExprLine { def } => match types.first() {
+                Some(ty) if *def && *ty != Type::Int => vec![],
+                Some(Type::Fun(..)) => vec![],
+                _ => only(vec![Type::Int]),
+            },
+            // a function that calls itself is checked as returning an int first
+            Kind::FunLine { recursive, .. } => match types.first() {
+                Some(ty) if (*recursive || ctx.recursive) && *ty != Type::Int => vec![],
+                _ => only(vec![Type::Int]),
+            },
+            Kind::StructLine { .. } => only(vec![Type::Int]),
+            Kind::Operation => {
+                // a comparison can still follow unless there already is one, and "=" takes any type
+                let tys = match names.iter().position(|op| CMP_OPS.contains(&op.as_str())) {
+                    None => side_type(&types, names).map_or(vec![], |ty| vec![ty, Type::Bool]),
+                    Some(at) => {
+                        let lhs = side_type(&types[..=at], &names[..at]);
+                        let rhs = side_type(&types[at + 1..], &names[at + 1..]);
+                        match (lhs, rhs) {
+                            (Some(lhs), Some(rhs)) if compatible(&names[at], &lhs, &rhs) => vec![Type::Bool],
+                            _ => vec![],
+                        }
+                    },
+                };
+                only(tys)
+            },
+            Kind::UnOp => match types.first() {
+                Some(ty) if *ty != Type::Int => vec![],
+                _ => only(vec![Type::Int]),
+            },
+            Kind::Int => only(vec![Type::Int]),
+            Kind::Id => match types.first() {
+                Some(ty) => only(vec![ty.clone()]),
+                None => only(self.scope(idx, false).into_iter().map(|(_, ty)| ty).collect()),
+            },
+            Kind::Paren | Kind::Print => only(types),
+            Kind::Apply => {
+                let funs = self.callable();
+                let funs = funs.iter().filter(|(name, _, _)| names.first().map_or(true, |picked| picked == name));
+                if types.iter().any(|ty| *ty != Type::Int) {
+                    return vec![];
+                }
+                funs.map(|(name, _, ret)| {
+                    let recursive = ctx.recursive || self.is_self(name);
+                    (ret.clone(), Ctx { recursive, ..ctx.clone() })
+                }).collect()
+            },
+            Kind::Alloc => match names.first() {
+                None => only(self.globals.structs.iter().map(|(name, _)| Type::Struct(name.clone())).collect()),
+                Some(name) => {
+                    let ty = Type::Struct(name.clone());
+                    let fields = self.fields(&ty).unwrap();
+                    match types.iter().zip(fields).all(|(value, (_, field))| fits(field).contains(value)) {
+                        true => only(vec![ty]),
+                        false => vec![],
+                    }
+                },
+            },
+            Kind::Lookup | Kind::Update => {
+                let Some(fields) = types.first().and_then(|pointer| self.fields(pointer)) else { return vec![] };
+                let fields = fields.iter().filter(|(field, _)| names.first().map_or(true, |picked| picked == field));
+                let mut tys: Vec<Type> = vec![];
+                for (_, field_ty) in fields {
+                    let allowed = match frame.kind {
+                        Kind::Lookup => vec![field_ty.clone()],
+                        _ => fits(field_ty).into_iter().filter(|ty| types.get(1).map_or(true, |value| value == ty)).collect(),
+                    };
+                    for ty in allowed {
+                        if !tys.contains(&ty) {
+                            tys.push(ty);
+                        }
+                    }
+                }
+                only(tys)
+            },
+            Kind::Let => match types.get(names.len()) {
+                Some(body) => only(vec![body.clone()]),
+                None => only(self.universe()),
+            },
+            Kind::Set => {
+                let vars = self.scope(idx, true);
+                let vars = vars.iter().filter(|(var, _)| names.first().map_or(true, |picked| picked == var));
+                let mut tys: Vec<Type> = vec![];
+                for (_, var_ty) in vars {
+                    for ty in fits(var_ty) {
+                        if types.first().map_or(true, |value| *value == ty) && !tys.contains(&ty) {
+                            tys.push(ty);
+                        }
+                    }
+                }
+                only(tys)
+            },
+            Kind::If => match types.as_slice() {
+                [cond, ..] if *cond != Type::Bool => vec![],
+                [_, thn, els] => only(unify(thn, els).into_iter().collect()),
+                [_, thn] => only(self.closure(thn)),
+                _ => only(self.universe()),
+            },
+            Kind::Loop { breaks } => {
+                if types.get(1).is_some_and(|cond| *cond != Type::Bool) {
+                    return vec![];
+                }
+                let pending = ctx.breaks.iter().filter(|(target, _)| *target == idx).map(|(_, ty)| ty);
+                let mut agreed: Option<Type> = None;
+                for ty in types.first().into_iter().chain(breaks).chain(pending) {
+                    agreed = match agreed {
+                        None => Some(ty.clone()),
+                        Some(agreed) => match unify(&agreed, ty) {
+                            Some(ty) => Some(ty),
+                            None => return vec![],
+                        },
+                    };
+                }
+                let ctx = Ctx { breaks: ctx.breaks.iter().filter(|(target, _)| *target != idx).cloned().collect(), ..ctx.clone() };
+                // A later break could still turn a null into a struct, but only if it fits
+                // where it is too, so the loop is only counted on for what it has so far.
+                let tys = match agreed {
+                    Some(ty) => vec![ty],
+                    None => self.universe(),
+                };
+                tys.into_iter().map(|ty| (ty, ctx.clone())).collect()
+            },
+            Kind::Break { target } => types.into_iter().map(|ty| {
+                let mut ctx = ctx.clone();
+                ctx.breaks.push((*target, ty.clone()));
+                (ty, ctx)
+            }).collect(),
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use im::HashMap;
+
+use super::regalloc::LET_REGS;
+use super::runtime;
+use super::utils::{Expr, FunDef, Instr, Op1, Op2, Reg, Val};
+
+// How much work the compiler puts into a line. Every level gives the same results and
+// errors; the lower ones are there to compare against and to read the plain output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum OptLevel {
+    // the instructions exactly as compile_to_instrs writes them
+    None,
+    // only the peephole pass over the instructions
+    Peephole,
+    // constant folding over the expression and let bindings in registers too
+    #[default]
+    Full,
+}
+
+impl OptLevel {
+    // the level of a -O<n> flag
+    pub fn from_level(level: &str) -> Option<OptLevel> {
+        match level {
+            "0" => Some(OptLevel::None),
+            "1" => Some(OptLevel::Peephole),
+            "2" => Some(OptLevel::Full),
+            _ => None,
+        }
+    }
+}
+
+// an int result, unless it would overflow once tagged, which is left for the jitted code to report
+fn int(value: Option<i64>) -> Option<Expr> {
+    value.filter(|value| runtime::encode_int(*value).is_some()).map(Expr::Number)
+}
+
+// A literal that the jitted code can hold. Larger ones are an overflow error when they are
+// evaluated, so they are never folded away.
+fn literal(e: &Expr) -> Option<i64> {
+    match e {
+        Expr::Number(n) if runtime::encode_int(*n).is_some() => Some(*n),
+        _ => None,
+    }
+}
+
+fn fold_op2(op: &Op2, lhs: &Expr, rhs: &Expr) -> Option<Expr> {
+    if let (Some(a), Some(b)) = (literal(lhs), literal(rhs)) {
+        return match op {
+            Op2::Plus => int(a.checked_add(b)),
+            Op2::Minus => int(a.checked_sub(b)),
+            Op2::Times => int(a.checked_mul(b)),
+            Op2::Divide => int(a.checked_div(b)),
+            Op2::Modulo => int(a.checked_rem(b)),
+            Op2::Greater => Some(Expr::Boolean(a > b)),
+            Op2::Less => Some(Expr::Boolean(a < b)),
+            Op2::GreaterEqual => Some(Expr::Boolean(a >= b)),
+            Op2::LessEqual => Some(Expr::Boolean(a <= b)),
+            Op2::Equal => Some(Expr::Boolean(a == b)),
+        };
+    }
+    match (op, lhs, rhs) {
+        (Op2::Equal, Expr::Boolean(a), Expr::Boolean(b)) => Some(Expr::Boolean(a == b)),
+        // identities that keep the other operand, which is still evaluated
+        (Op2::Plus, e, Expr::Number(0)) | (Op2::Plus, Expr::Number(0), e)
+            | (Op2::Minus, e, Expr::Number(0))
+            | (Op2::Times, e, Expr::Number(1)) | (Op2::Times, Expr::Number(1), e)
+            | (Op2::Divide, e, Expr::Number(1)) => Some(e.clone()),
+        _ => None,
+    }
+}
+
+// Whether an expression is sure not to have the null type. An if takes the type of both its
+// branches, so one that is null next to a struct is a struct pointer, and folding the if down
+// to that branch would leave a lookup of it nothing to find the field in.
+fn never_null(e: &Expr) -> bool {
+    match e {
+        Expr::Number(_) | Expr::Boolean(_) | Expr::UnOp(..) | Expr::BinOp(..)
+            | Expr::Alloc(..) | Expr::Lookup(..) => true,
+        Expr::Let(_, body, _) => never_null(body),
+        Expr::If(_, thn, els, _) => never_null(thn) && never_null(els),
+        Expr::Set(_, value, _) | Expr::Print(value, _) | Expr::Update(_, _, value, _) => never_null(value),
+        _ => false,
+    }
+}
+
+// Folds the parts of an expression that are known before it runs. `consts` are the heap
+// variables that are not shadowed at this point, which compile_to_instrs would inline
+// anyway. The expression has to typecheck already, so that the branches folded away
+// have been checked.
+pub fn fold(e: &Expr, consts: &HashMap<String, i64>) -> Expr {
+    match e {
+        Expr::Id(x, _) => match consts.get(x) {
+            Some(value) => Expr::Number(*value),
+            None => e.clone(),
+        },
+        Expr::UnOp(op, subexpr, span) => {
+            let subexpr = fold(subexpr, consts);
+            let folded = match (op, literal(&subexpr)) {
+                (Op1::Add1, Some(n)) => int(n.checked_add(1)),
+                (Op1::Sub1, Some(n)) => int(n.checked_sub(1)),
+                _ => None,
+            };
+            folded.unwrap_or_else(|| Expr::UnOp(op.clone(), Box::new(subexpr), *span))
+        },
+        Expr::BinOp(op, lhs, rhs, span) => {
+            let lhs = fold(lhs, consts);
+            let rhs = fold(rhs, consts);
+            fold_op2(op, &lhs, &rhs)
+                .unwrap_or_else(|| Expr::BinOp(op.clone(), Box::new(lhs), Box::new(rhs), *span))
+        },
+        // each binding shadows the heap variable of the same name from there on
+        Expr::Let(bindings, body, span) => {
+            let mut scope = consts.clone();
+            let mut folded = vec![];
+            for (name, value) in bindings {
+                folded.push((name.clone(), fold(value, &scope)));
+                scope.remove(name);
+            }
+            Expr::Let(folded, Box::new(fold(body, &scope)), *span)
+        },
+        Expr::Set(name, value, span) => Expr::Set(name.clone(), Box::new(fold(value, consts)), *span),
+        Expr::If(cond, thn, els, span) => match fold(cond, consts) {
+            Expr::Boolean(true) if never_null(thn) => fold(thn, consts),
+            Expr::Boolean(false) if never_null(els) => fold(els, consts),
+            cond => Expr::If(Box::new(cond), Box::new(fold(thn, consts)), Box::new(fold(els, consts)), *span),
+        },
+        Expr::RepeatUntil(body, cond, span) => {
+            Expr::RepeatUntil(Box::new(fold(body, consts)), Box::new(fold(cond, consts)), *span)
+        },
+        Expr::Break(value, span) => Expr::Break(Box::new(fold(value, consts)), *span),
+        Expr::Call(name, args, span) => {
+            Expr::Call(name.clone(), args.iter().map(|arg| fold(arg, consts)).collect(), *span)
+        },
+        Expr::Print(value, span) => Expr::Print(Box::new(fold(value, consts)), *span),
+        Expr::Alloc(name, values, span) => {
+            Expr::Alloc(name.clone(), values.iter().map(|value| fold(value, consts)).collect(), *span)
+        },
+        Expr::Lookup(pointer, field, span) => {
+            Expr::Lookup(Box::new(fold(pointer, consts)), field.clone(), *span)
+        },
+        Expr::Update(pointer, field, value, span) => {
+            Expr::Update(Box::new(fold(pointer, consts)), field.clone(), Box::new(fold(value, consts)), *span)
+        },
+        Expr::Number(_) | Expr::Boolean(_) | Expr::Null => e.clone(),
+    }
+}
+
+// a function with its body folded; its parameters shadow the heap variables
+pub fn fold_fun(def: &FunDef, consts: &HashMap<String, i64>) -> FunDef {
+    let mut scope = consts.clone();
+    for param in &def.params {
+        scope.remove(param);
+    }
+    FunDef { body: fold(&def.body, &scope), ..def.clone() }
+}
+
+// A load that does not read rax, rcx or the stack pointer: an immediate, a stack variable,
+// which is addressed from rbp, or a let binding in a register.
+fn plain_load(src: &Val) -> bool {
+    match src {
+        Val::Imm(_) | Val::RegOffset(Reg::RBP, _) => true,
+        Val::Reg(reg) => LET_REGS.contains(reg),
+        _ => false,
+    }
+}
+
+// Rewrites the end of `instrs` once, if it matches a pattern that can be shortened.
+fn rewrite_tail(instrs: &mut Vec<Instr>) -> bool {
+    let len = instrs.len();
+    match instrs.as_slice() {
+        // a move to itself does nothing
+        [.., Instr::IMov(Val::Reg(dest), Val::Reg(src))] if dest == src => {
+            instrs.pop();
+            true
+        },
+        // moving a value back to where it was just copied from does nothing, as when a let
+        // binding in a register is read right after it is bound
+        [.., Instr::IMov(Val::Reg(dest), Val::Reg(src)), Instr::IMov(Val::Reg(back), Val::Reg(from))]
+            if back == src && from == dest => {
+            instrs.pop();
+            true
+        },
+        // a value pushed and popped straight away only needs a move
+        [.., Instr::Push(Val::Reg(src)), Instr::Pop(Val::Reg(dest))] => {
+            let mov = Instr::IMov(Val::Reg(*dest), Val::Reg(*src));
+            instrs.truncate(len - 2);
+            instrs.push(mov);
+            true
+        },
+        // A binop whose right operand is a plain load saves the left one around loading it
+        // into rax; it can be loaded straight into rcx instead.
+        [.., Instr::Push(Val::Reg(Reg::RAX)), Instr::IMov(Val::Reg(Reg::RAX), src),
+            Instr::IMov(Val::Reg(Reg::RCX), Val::Reg(Reg::RAX)), Instr::Pop(Val::Reg(Reg::RAX))] if plain_load(src) => {
+            let src = match instrs.swap_remove(len - 3) {
+                Instr::IMov(_, src) => src,
+                _ => unreachable!(),
+            };
+            instrs.truncate(len - 4);
+            instrs.push(Instr::IMov(Val::Reg(Reg::RCX), src));
+            true
+        },
+        // the same around a single load into another register, as when a field is updated
+        [.., Instr::Push(Val::Reg(saved)), Instr::IMov(Val::Reg(loaded), src), Instr::Pop(Val::Reg(restored))]
+            if plain_load(src) && loaded != restored && *src != Val::Reg(*restored) => {
+            let (saved, restored) = (*saved, *restored);
+            let load = instrs.swap_remove(len - 2);
+            instrs.truncate(len - 3);
+            instrs.push(Instr::IMov(Val::Reg(restored), Val::Reg(saved)));
+            instrs.push(load);
+            true
+        },
+        _ => false,
+    }
+}
+
+// Shortens instruction sequences that compile_to_instrs leaves behind, without changing
+// what they do. Labels are never part of a pattern, so jumps keep their targets.
+pub fn peephole(instrs: Vec<Instr>) -> Vec<Instr> {
+    let mut optimized = Vec::with_capacity(instrs.len());
+    for instr in instrs {
+        optimized.push(instr);
+        while rewrite_tail(&mut optimized) {}
+    }
+    optimized
+}
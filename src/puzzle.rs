@@ -0,0 +1,61 @@
+use super::runtime::Value;
+
+// A goal that takes more than one right answer: the line has to turn every input into
+// the matching output. Lines read the input they are run with as `input`.
+pub struct Puzzle {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub inputs: &'static [i64],
+    answer: fn(i64) -> i64,
+}
+
+pub static PUZZLES: [Puzzle; 5] = [
+    Puzzle {
+        name: "double",
+        description: "twice the input",
+        inputs: &[0, 1, 7, -4],
+        answer: |input| 2 * input,
+    },
+    Puzzle {
+        name: "square",
+        description: "the input times itself",
+        inputs: &[0, 3, -5, 9],
+        answer: |input| input * input,
+    },
+    Puzzle {
+        name: "abs",
+        description: "the input without its sign",
+        inputs: &[5, -5, 0, -12],
+        answer: |input| input.abs(),
+    },
+    Puzzle {
+        name: "sign",
+        description: "1, 0 or -1 for positive, zero or negative inputs",
+        inputs: &[8, 0, -3, 1],
+        answer: |input| input.signum(),
+    },
+    Puzzle {
+        name: "sum-to",
+        description: "1 + 2 + ... + input",
+        inputs: &[0, 1, 4, 10],
+        answer: |input| input * (input + 1) / 2,
+    },
+];
+
+impl Puzzle {
+    pub fn by_name(name: &str) -> Option<&'static Puzzle> {
+        PUZZLES.iter().find(|puzzle| puzzle.name == name)
+    }
+
+    // the output each input should give, in the same order as `inputs`
+    pub fn expected(&self) -> Vec<i64> {
+        self.inputs.iter().map(|input| (self.answer)(*input)).collect()
+    }
+
+    // how many of the results, one per input, are right
+    pub fn score(&self, results: &[Option<Value>]) -> usize {
+        self.expected().iter().zip(results)
+            .filter(|(expected, result)| **result == Some(Value::Int(**expected)))
+            .count()
+    }
+}
@@ -0,0 +1,125 @@
+use super::utils::{Expr, Reg};
+
+// Registers that let bindings may live in. They are callee-saved, so calls into Rust keep
+// them; a snek function saves the ones it uses itself. r15 holds the run's context.
+pub const LET_REGS: [Reg; 4] = [Reg::RBX, Reg::R12, Reg::R13, Reg::R14];
+
+// whether `name` is read or set anywhere in `e`, ignoring shadowing, which only keeps a
+// binding around for longer than it needs to be
+fn mentions(e: &Expr, name: &str) -> bool {
+    match e {
+        Expr::Id(x, _) => x == name,
+        Expr::Set(x, value, _) => x == name || mentions(value, name),
+        Expr::Number(_) | Expr::Boolean(_) | Expr::Null => false,
+        Expr::UnOp(_, subexpr, _) | Expr::Break(subexpr, _) | Expr::Print(subexpr, _)
+            | Expr::Lookup(subexpr, _, _) => mentions(subexpr, name),
+        Expr::BinOp(_, lhs, rhs, _) | Expr::RepeatUntil(lhs, rhs, _) | Expr::Update(lhs, _, rhs, _) => {
+            mentions(lhs, name) || mentions(rhs, name)
+        },
+        Expr::If(cond, thn, els, _) => mentions(cond, name) || mentions(thn, name) || mentions(els, name),
+        Expr::Let(bindings, body, _) => {
+            bindings.iter().any(|(_, value)| mentions(value, name)) || mentions(body, name)
+        },
+        Expr::Call(_, values, _) | Expr::Alloc(_, values, _) => values.iter().any(|value| mentions(value, name)),
+    }
+}
+
+// whether the binding at `idx` is still needed once the bindings up to `idx` have been computed
+fn live_after(name: &str, bindings: &[(String, Expr)], idx: usize, body: &Expr) -> bool {
+    bindings[idx + 1..].iter().any(|(_, value)| mentions(value, name)) || mentions(body, name)
+}
+
+// the most let bindings of `e` that can hold a register at the same time
+fn regs_needed(e: &Expr) -> usize {
+    match e {
+        Expr::Let(bindings, body, _) => {
+            let values = bindings.iter().enumerate().map(|(idx, (_, value))| idx + regs_needed(value));
+            values.max().unwrap_or(0).max(bindings.len() + regs_needed(body))
+        },
+        Expr::Id(..) | Expr::Number(_) | Expr::Boolean(_) | Expr::Null => 0,
+        Expr::UnOp(_, subexpr, _) | Expr::Break(subexpr, _) | Expr::Print(subexpr, _)
+            | Expr::Lookup(subexpr, _, _) | Expr::Set(_, subexpr, _) => regs_needed(subexpr),
+        Expr::BinOp(_, lhs, rhs, _) | Expr::RepeatUntil(lhs, rhs, _) | Expr::Update(lhs, _, rhs, _) => {
+            regs_needed(lhs).max(regs_needed(rhs))
+        },
+        Expr::If(cond, thn, els, _) => regs_needed(cond).max(regs_needed(thn)).max(regs_needed(els)),
+        Expr::Call(_, values, _) | Expr::Alloc(_, values, _) => values.iter().map(regs_needed).max().unwrap_or(0),
+    }
+}
+
+// The registers one function body or line may put its let bindings in. The prologue saves
+// them all and the epilogue restores them.
+#[derive(Debug, Clone, Default)]
+pub struct RegPool {
+    saved: Vec<Reg>,
+    // taken from the end, so bindings get the saved registers in order
+    free: Vec<Reg>,
+}
+
+impl RegPool {
+    // as many registers as `body` can use, which are only saved if it has lets to put in them
+    pub fn for_body(body: &Expr) -> RegPool {
+        let saved = LET_REGS[..regs_needed(body).min(LET_REGS.len())].to_vec();
+        RegPool { free: saved.iter().rev().copied().collect(), saved }
+    }
+
+    // no registers, so that every binding goes on the stack
+    pub fn empty() -> RegPool {
+        RegPool::default()
+    }
+
+    pub fn saved(&self) -> &[Reg] {
+        &self.saved
+    }
+}
+
+// where a let binding is kept
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Home {
+    Reg(Reg),
+    // pushed, like every binding before there was a register allocator
+    Stack,
+    // never read or set again, so its value is only computed
+    Unused,
+}
+
+// The registers held by the bindings of one let. Nested lets take and give back their own
+// registers before the next binding is placed, so the pool is back where it started every
+// time `place` is called.
+#[derive(Default)]
+pub struct LetHomes {
+    held: Vec<(usize, Reg)>,
+}
+
+impl LetHomes {
+    pub fn new() -> LetHomes {
+        LetHomes::default()
+    }
+
+    // Picks where the binding at `idx` goes, once its value is computed. Registers of earlier
+    // bindings that nothing after this one mentions are free again, so it can take one of those.
+    pub fn place(&mut self, pool: &mut RegPool, bindings: &[(String, Expr)], idx: usize, body: &Expr) -> Home {
+        self.held.retain(|(other, reg)| {
+            let live = live_after(&bindings[*other].0, bindings, idx, body);
+            if !live {
+                pool.free.push(*reg);
+            }
+            live
+        });
+        if !live_after(&bindings[idx].0, bindings, idx, body) {
+            return Home::Unused;
+        }
+        match pool.free.pop() {
+            Some(reg) => {
+                self.held.push((idx, reg));
+                Home::Reg(reg)
+            },
+            None => Home::Stack,
+        }
+    }
+
+    // gives back the registers still held once the let's body is done
+    pub fn finish(self, pool: &mut RegPool) {
+        pool.free.extend(self.held.into_iter().rev().map(|(_, reg)| reg));
+    }
+}
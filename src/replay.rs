@@ -0,0 +1,125 @@
+use std::fmt;
+use std::fs;
+
+use super::game_core::GameCore;
+use super::puzzle::Puzzle;
+use super::snake::Direction;
+
+// Everything needed to play a round again: the seed places the food, and the turns are
+// indexed by the tick they happened on, with the snake going straight on every other tick.
+//
+//   seed 1234
+//   goal 42
+//   puzzle double
+//   level levels/01-first-steps.level
+//   turn 3 up
+//   turn 10 left
+//   ticks 57
+//
+// The puzzle line is only there for rounds played as a puzzle, and the level line, with the
+// level file's path, for rounds of a campaign. Lines starting with "#" are comments;
+// recorded rounds list their program lines there.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Replay {
+    pub seed: u64,
+    pub goal: i64,
+    pub puzzle: Option<String>,
+    pub level: Option<String>,
+    pub turns: Vec<(u64, Direction)>,
+    pub ticks: u64,
+}
+
+fn direction_to_str(dir: Direction) -> &'static str {
+    match dir {
+        Direction::Up => "up",
+        Direction::Down => "down",
+        Direction::Left => "left",
+        Direction::Right => "right",
+    }
+}
+
+fn str_to_direction(s: &str) -> Option<Direction> {
+    match s {
+        "up" => Some(Direction::Up),
+        "down" => Some(Direction::Down),
+        "left" => Some(Direction::Left),
+        "right" => Some(Direction::Right),
+        _ => None,
+    }
+}
+
+impl Replay {
+    // the direction given on a tick, if the snake turned then
+    pub fn turn_at(&self, tick: u64) -> Option<Direction> {
+        self.turns.iter().find(|(turn_tick, _)| *turn_tick == tick).map(|(_, dir)| *dir)
+    }
+
+    // Runs the whole round on a core made from this replay with `GameCore::from_replay`.
+    pub fn play(&self, core: &mut GameCore) {
+        while core.ticks() < self.ticks && !core.is_game_over() {
+            core.tick(self.turn_at(core.ticks()));
+        }
+    }
+
+    pub fn parse(src: &str) -> Result<Replay, String> {
+        let mut seed = None;
+        let mut goal = None;
+        let mut puzzle = None;
+        let mut level = None;
+        let mut ticks = None;
+        let mut turns = vec![];
+        for (line_num, line) in src.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let bad_line = || format!("line {}: cannot read '{}'", line_num + 1, line);
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                ["seed", value] => seed = Some(value.parse().map_err(|_| bad_line())?),
+                ["goal", value] => goal = Some(value.parse().map_err(|_| bad_line())?),
+                ["ticks", value] => ticks = Some(value.parse().map_err(|_| bad_line())?),
+                ["puzzle", name] => match Puzzle::by_name(name) {
+                    Some(found) => puzzle = Some(found.name.to_string()),
+                    None => return Err(format!("line {}: unknown puzzle {}", line_num + 1, name)),
+                },
+                ["level", path] => level = Some(path.to_string()),
+                ["turn", tick, dir] => {
+                    let tick = tick.parse().map_err(|_| bad_line())?;
+                    turns.push((tick, str_to_direction(dir).ok_or_else(bad_line)?));
+                },
+                _ => return Err(bad_line()),
+            }
+        }
+        Ok(Replay {
+            seed: seed.ok_or("missing seed")?,
+            goal: goal.ok_or("missing goal")?,
+            puzzle,
+            level,
+            turns,
+            ticks: ticks.ok_or("missing ticks")?,
+        })
+    }
+
+    pub fn read(path: &str) -> Result<Replay, String> {
+        let src = fs::read_to_string(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+        Replay::parse(&src)
+    }
+}
+
+impl fmt::Display for Replay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "seed {}", self.seed)?;
+        writeln!(f, "goal {}", self.goal)?;
+        if let Some(puzzle) = &self.puzzle {
+            writeln!(f, "puzzle {}", puzzle)?;
+        }
+        if let Some(level) = &self.level {
+            writeln!(f, "level {}", level)?;
+        }
+        for (tick, dir) in &self.turns {
+            writeln!(f, "turn {} {}", tick, direction_to_str(*dir))?;
+        }
+        writeln!(f, "ticks {}", self.ticks)
+    }
+}
@@ -0,0 +1,210 @@
+use std::fmt;
+use std::collections::VecDeque;
+use std::mem::offset_of;
+
+// number of loop back-edges and calls a single program line may take before it is stopped
+pub const FUEL: i64 = 1_000_000;
+
+// bytes of stack that nested function calls may use below the entry frame
+pub const STACK_LIMIT: i32 = 1 << 20;
+
+// printed values kept per line, so that a loop printing forever does not fill up memory
+pub const OUTPUT_LIMIT: usize = 100;
+
+// words of heap a single program line may allocate; the heap is emptied before every line
+pub const HEAP_WORDS: usize = 1 << 16;
+
+// The state of one run of a jitted line. The line is called with its address, keeps it in a
+// register for the whole run and reaches the words before `heap` at the offsets below, so
+// every Jit has its own and two lines can run at once.
+#[repr(C)]
+pub struct RunCtx {
+    fuel: i64,
+    entry_rsp: i64,
+    error: i64,
+    // the argument the line was called with, which it reads as `input`
+    input: i64,
+    // the next free word of the heap and the address just past it, both as addresses
+    heap_ptr: i64,
+    heap_end: i64,
+    heap: Vec<i64>,
+    // what the line has printed so far, up to the last OUTPUT_LIMIT values
+    output: VecDeque<String>,
+}
+
+pub const FUEL_OFFSET: i32 = offset_of!(RunCtx, fuel) as i32;
+pub const ENTRY_RSP_OFFSET: i32 = offset_of!(RunCtx, entry_rsp) as i32;
+pub const ERROR_OFFSET: i32 = offset_of!(RunCtx, error) as i32;
+pub const INPUT_OFFSET: i32 = offset_of!(RunCtx, input) as i32;
+pub const HEAP_PTR_OFFSET: i32 = offset_of!(RunCtx, heap_ptr) as i32;
+pub const HEAP_END_OFFSET: i32 = offset_of!(RunCtx, heap_end) as i32;
+
+// Values carry their type in their low bits, the same in runtime/start.rs. Ints are shifted
+// left by one so they end in 0, booleans end in 11 and struct pointers, whose addresses are
+// 8-aligned, end in 01. Null is the pointer to address 0.
+pub const TRUE_VAL: i64 = 7;
+pub const FALSE_VAL: i64 = 3;
+pub const NULL_VAL: i64 = 1;
+pub const POINTER_TAG: i64 = 1;
+
+// the tagged form of an int, if it fits in the 63 bits left for it
+pub fn encode_int(n: i64) -> Option<i64> {
+    n.checked_mul(2)
+}
+
+// A value as lines give it back and print shows it. Structs are shown as the word of the
+// heap they start at, which is the same from run to run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Bool(bool),
+    Null,
+    Struct(i64),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Null => write!(f, "null"),
+            Value::Struct(word) => write!(f, "<struct at {}>", word),
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeError {
+    Overflow,
+    OutOfFuel,
+    StackOverflow,
+    NullDereference,
+    OutOfMemory,
+    WrongType,
+    DivideByZero,
+    Unknown(i64),
+}
+
+impl RuntimeError {
+    pub fn code(&self) -> i64 {
+        match self {
+            RuntimeError::Overflow => 1,
+            RuntimeError::OutOfFuel => 2,
+            RuntimeError::StackOverflow => 3,
+            RuntimeError::NullDereference => 4,
+            RuntimeError::OutOfMemory => 5,
+            RuntimeError::WrongType => 6,
+            RuntimeError::DivideByZero => 7,
+            RuntimeError::Unknown(code) => *code,
+        }
+    }
+
+    pub fn from_code(code: i64) -> RuntimeError {
+        match code {
+            1 => RuntimeError::Overflow,
+            2 => RuntimeError::OutOfFuel,
+            3 => RuntimeError::StackOverflow,
+            4 => RuntimeError::NullDereference,
+            5 => RuntimeError::OutOfMemory,
+            6 => RuntimeError::WrongType,
+            7 => RuntimeError::DivideByZero,
+            _ => RuntimeError::Unknown(code),
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RuntimeError::Overflow => write!(f, "overflow"),
+            RuntimeError::OutOfFuel => write!(f, "ran out of fuel"),
+            RuntimeError::StackOverflow => write!(f, "too much recursion"),
+            RuntimeError::NullDereference => write!(f, "null dereference"),
+            RuntimeError::OutOfMemory => write!(f, "out of heap memory"),
+            RuntimeError::WrongType => write!(f, "wrong type"),
+            RuntimeError::DivideByZero => write!(f, "divide by zero"),
+            RuntimeError::Unknown(code) => write!(f, "runtime error {}", code),
+        }
+    }
+}
+
+impl Default for RunCtx {
+    fn default() -> RunCtx {
+        RunCtx {
+            fuel: FUEL,
+            entry_rsp: 0,
+            error: 0,
+            input: 0,
+            heap_ptr: 0,
+            heap_end: 0,
+            heap: vec![0; HEAP_WORDS],
+            output: VecDeque::new(),
+        }
+    }
+}
+
+impl RunCtx {
+    pub fn new() -> RunCtx {
+        RunCtx::default()
+    }
+
+    // refuel, empty the heap and clear the last error and output before running a line
+    pub fn reset(&mut self) {
+        self.fuel = FUEL;
+        self.error = 0;
+        self.output.clear();
+        self.heap_ptr = self.heap.as_ptr() as i64;
+        self.heap_end = self.heap_ptr + 8 * HEAP_WORDS as i64;
+    }
+
+    pub fn take_error(&mut self) -> Option<RuntimeError> {
+        match std::mem::take(&mut self.error) {
+            0 => None,
+            code => Some(RuntimeError::from_code(code)),
+        }
+    }
+
+    // the values printed since the last reset, oldest first
+    pub fn take_output(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.output).into()
+    }
+
+    // reads the type back out of a tagged value from the jitted code
+    pub fn decode(&self, value: i64) -> Value {
+        match value {
+            TRUE_VAL => Value::Bool(true),
+            FALSE_VAL => Value::Bool(false),
+            NULL_VAL => Value::Null,
+            value if value & 1 == 0 => Value::Int(value >> 1),
+            value => Value::Struct((value - POINTER_TAG - self.heap.as_ptr() as i64) / 8),
+        }
+    }
+}
+
+// Called from jitted code with the error code in rdi and the run's context in rsi. Instead
+// of exiting like runtime/start.rs does, the error is recorded and the caller unwinds to
+// the entry frame.
+extern "C" fn snek_error(errcode: i64, ctx: *mut RunCtx) {
+    let ctx = unsafe { &mut *ctx };
+    ctx.error = errcode;
+}
+
+// Called from jitted code with the tagged value in rdi and the run's context in rsi.
+extern "C" fn snek_print(value: i64, ctx: *mut RunCtx) {
+    let ctx = unsafe { &mut *ctx };
+    let shown = ctx.decode(value).to_string();
+    ctx.output.push_back(shown);
+    if ctx.output.len() > OUTPUT_LIMIT {
+        ctx.output.pop_front();
+    }
+}
+
+// addresses baked into the jitted code for the runtime functions it calls
+pub fn symbol_address(name: &str) -> i64 {
+    match name {
+        "snek_error" => snek_error as *const () as i64,
+        "snek_print" => snek_print as *const () as i64,
+        _ => panic!("unknown runtime symbol {}", name),
+    }
+}
@@ -10,7 +10,7 @@ use super::drawing::draw_block;
 
 const SNAKE_COLOR: Color = [0.18, 0.80, 0.44, 1.0];
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Direction {
     Up, Down, Left, Right
 }
@@ -58,23 +58,22 @@ impl Snake {
 
         Snake {
             moving_direction: Direction::Right,
-            body: body,
+            body,
             last_removed_block: None,
             blocks_traveled: 0,
         }
     }
 
-    pub fn draw(&self, con: &Context, g: &mut G2d, mut font: &mut Glyphs) {
+    pub fn draw(&self, con: &Context, g: &mut G2d, font: &mut Glyphs) {
         for block in &self.body {
-            draw_block(SNAKE_COLOR, "snake", block.x, block.y, con, g, &mut font);
+            draw_block(SNAKE_COLOR, "snake", block.x, block.y, con, g, font);
         }
     }
 
     pub fn move_forward(&mut self, dir: Option<Direction>, update_blocks: bool) {
         // Change moving direction
-        match dir {
-            Some(d) => self.moving_direction = d,
-            None => {}
+        if let Some(d) = dir {
+            self.moving_direction = d;
         }
 
         // Retrieve the position of the head block
@@ -117,7 +116,7 @@ impl Snake {
     }
 
     pub fn blocks_traveled(&self) -> i64 {
-        return self.blocks_traveled;
+        self.blocks_traveled
     }
     pub fn reset_blocks_traveled(&mut self) {
         self.blocks_traveled = 0;
@@ -128,11 +127,7 @@ impl Snake {
         let (head_x, head_y): (i32, i32) = self.head_position();
 
         // Get moving direction
-        let mut moving_dir = self.moving_direction;
-        match dir {
-            Some(d) => moving_dir = d,
-            None => {}
-        }
+        let moving_dir = dir.unwrap_or(self.moving_direction);
 
         // The snake moves
         match moving_dir {
@@ -161,6 +156,6 @@ impl Snake {
                 break;
             }
         }
-        return false;
+        false
     }
 }
@@ -0,0 +1,127 @@
+// What the names a program line can refer to stand for. The game writes heap variables
+// into the line as their values, so it has to know them by name, and it lists every
+// binding next to the board.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BindingKind {
+    // a def result, x0..xn, kept for the rest of the program
+    Heap,
+    // a let binding on the line being built, y0..yn
+    Let,
+    // a parameter of the fun being defined, a0..an
+    Param,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Binding {
+    pub name: String,
+    pub kind: BindingKind,
+    // a heap variable's value; the others only get one when the line runs
+    pub value: Option<i64>,
+    // how many lets the binding is inside, counting the one that binds it, so heap
+    // variables and parameters are at 0
+    pub depth: usize,
+}
+
+// The heap variables, and a stack of scopes for the line being built that mirrors the
+// nesting of its lets.
+#[derive(Debug, Clone)]
+pub struct SymbolTable {
+    // x0 first
+    heap: Vec<Binding>,
+    // the line's own scope, which holds a fun line's parameters, then one per open let,
+    // outermost first
+    scopes: Vec<Vec<Binding>>,
+}
+
+impl Default for SymbolTable {
+    fn default() -> SymbolTable {
+        SymbolTable { heap: vec![], scopes: vec![vec![]] }
+    }
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable::default()
+    }
+
+    // Keeps a def result as the next heap variable and gives back its name.
+    pub fn define(&mut self, value: i64) -> String {
+        let name = format!("x{}", self.heap.len());
+        self.heap.push(Binding { name: name.clone(), kind: BindingKind::Heap, value: Some(value), depth: 0 });
+        name
+    }
+
+    // a parameter of the fun line being built
+    pub fn bind_param(&mut self, name: String) {
+        self.scopes[0].push(Binding { name, kind: BindingKind::Param, value: None, depth: 0 });
+    }
+
+    // how many lets are open on the line
+    pub fn let_depth(&self) -> usize {
+        self.scopes.len() - 1
+    }
+
+    fn enter_let(&mut self) {
+        self.scopes.push(vec![]);
+    }
+
+    // the bindings of the innermost let go out of scope with it
+    fn leave_let(&mut self) {
+        if self.scopes.len() > 1 {
+            self.scopes.pop();
+        }
+    }
+
+    // a binding of the innermost let, whose value is finished
+    fn bind_let(&mut self, name: String) {
+        let depth = self.let_depth();
+        self.scopes.last_mut().unwrap().push(Binding { name, kind: BindingKind::Let, value: None, depth });
+    }
+
+    // Brings the line's scopes in line with `lets`, the finished bindings of each open let,
+    // outermost first. A let only opens or closes at its innermost end, and only its
+    // innermost bindings can grow, as one food moves the line along.
+    pub fn follow_lets(&mut self, lets: &[Vec<String>]) {
+        while self.let_depth() > lets.len() {
+            self.leave_let();
+        }
+        while self.let_depth() < lets.len() {
+            self.enter_let();
+        }
+        if let Some(names) = lets.last() {
+            let bound = self.scopes.last().unwrap().len();
+            for name in &names[bound.min(names.len())..] {
+                self.bind_let(name.clone());
+            }
+        }
+    }
+
+    // the binding a name refers to at this point of the line, the innermost one first
+    pub fn lookup(&self, name: &str) -> Option<&Binding> {
+        self.scopes.iter().rev()
+            .flat_map(|scope| scope.iter().rev())
+            .chain(self.heap.iter())
+            .find(|binding| binding.name == name)
+    }
+
+    // the heap variables, x0 first
+    pub fn heap(&self) -> impl Iterator<Item = &Binding> {
+        self.heap.iter()
+    }
+
+    // the parameters and let bindings in scope on the line, outermost first
+    pub fn line_bindings(&self) -> impl Iterator<Item = &Binding> {
+        self.scopes.iter().flatten()
+    }
+
+    // forgets the line's own bindings once it has run
+    pub fn end_line(&mut self) {
+        self.scopes = vec![vec![]];
+    }
+
+    pub fn clear(&mut self) {
+        self.heap.clear();
+        self.end_line();
+    }
+}
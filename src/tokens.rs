@@ -45,6 +45,8 @@ pub enum Token {
     KeywordSet,
     #[token("repeat-until")]
     KeywordRepeatUntil,
+    #[token("break")]
+    KeywordBreak,
     #[token("if")]
     KeywordIf,
     #[token("struct")]
@@ -68,8 +70,8 @@ pub enum Token {
 
     #[regex("[_a-zA-Z][_0-9a-zA-Z]*", |lex| lex.slice().to_string())]
     Identifier(String),
-    #[regex("[0-9]+|-[1-9]+", |lex| lex.slice().parse())]
-    Integer(i32),
+    #[regex("[0-9]+|-[1-9][0-9]*", |lex| lex.slice().parse())]
+    Integer(i64),
 
     #[token("null")]
     NullValue,
@@ -104,7 +106,9 @@ pub enum Token {
     #[token("*")]
     OperatorMul,
     #[token("/")]
-    CmpToken,
+    OperatorDiv,
+    #[token("%")]
+    OperatorMod,
     #[token(">")]
     OperatorGreater,
     #[token("<")]
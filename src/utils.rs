@@ -1,29 +1,37 @@
 use im::HashMap;
 use core::panic;
+use lalrpop_util::ParseError;
+use std::collections::HashSet;
+use std::fmt;
 use std::sync::LazyLock;
 
+use crate::runtime::RuntimeError;
+use crate::tokens::{LexicalError, Token};
+
 pub static KEYWORD_LIST : LazyLock<Vec<String>> =
  std::sync::LazyLock::new(
-    || vec!["set!", "let", "if", "block", "true", "false", "add1", "sub1", "+", "-", "*", "input", "null"].into_iter().map(
+    || vec!["set!", "let", "if", "block", "true", "false", "add1", "sub1", "+", "-", "*", "/", "%", "input", "null",
+        "repeat-until", "break", "struct", "alloc", "lookup", "index", "print"].into_iter().map(
         |s| s.to_string()
     ).collect()
  );
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Val {
     Reg(Reg),
-    Imm(i32),
+    Imm(i64),
     RegOffset(Reg, i32),
     Str(String),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Reg {
     RAX,
     RBP,
     RBX,
     RDI,
     RSI,
+    RDX,
     RSP,
     RCX,
     R12,
@@ -38,11 +46,32 @@ pub enum Instr {
     IAdd(Val, Val),
     ISub(Val, Val),
     IMul(Val, Val),
+    // rdx:rax divided by the operand, quotient in rax and remainder in rdx
+    IDiv(Val),
+    // sign-extends rax into rdx for IDiv
+    ICqo(),
+    IAnd(Val, Val),
+    ISar(Val, Val),
+    ICmp(Val, Val),
+    ITest(Val, Val),
+    ICMovg(Val, Val),
+    ICMovl(Val, Val),
+    ICMovge(Val, Val),
+    ICMovle(Val, Val),
+    ICMove(Val, Val),
     Label(String, i32),
+    Jmp(String, i32),
+    Je(String, i32),
+    Jne(String, i32),
+    Jg(String, i32),
+    Jle(String, i32),
     Jno(String, i32),
+    Call(String, i32),
     CallSnekErr(),
+    CallSnekPrint(),
     Pop(Val),
     Push(Val),
+    Ret(),
 }
 
 #[derive(Debug)]
@@ -59,17 +88,172 @@ pub enum Op2 {
     Plus,
     Minus,
     Times,
+    Divide,
+    Modulo,
+    Greater,
+    Less,
+    GreaterEqual,
+    LessEqual,
+    Equal,
+}
+
+// byte offsets into the program line, as produced by the lexer
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
 }
 
 #[derive(Debug)]
 #[derive(Clone)]
 pub enum Expr {
-    Number(i32),
-    Id(String),
-    Let(Vec<(String, Expr)>, Box<Expr>),
-    UnOp(Op1, Box<Expr>),
-    BinOp(Op2, Box<Expr>, Box<Expr>),
-    Set(String, Box<Expr>),
+    Number(i64),
+    Boolean(bool),
+    Id(String, Span),
+    Let(Vec<(String, Expr)>, Box<Expr>, Span),
+    UnOp(Op1, Box<Expr>, Span),
+    BinOp(Op2, Box<Expr>, Box<Expr>, Span),
+    Set(String, Box<Expr>, Span),
+    If(Box<Expr>, Box<Expr>, Box<Expr>, Span),
+    RepeatUntil(Box<Expr>, Box<Expr>, Span),
+    Break(Box<Expr>, Span),
+    Call(String, Vec<Expr>, Span),
+    Null,
+    // a new struct of the named type with its fields in declaration order
+    Alloc(String, Vec<Expr>, Span),
+    Lookup(Box<Expr>, String, Span),
+    // index e [ field ] := value
+    Update(Box<Expr>, String, Box<Expr>, Span),
+    // prints the value and evaluates to it
+    Print(Box<Expr>, Span),
+}
+
+// a function defined on its own program line; parameters are ints
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct FunDef {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: Expr,
+    pub span: Span,
+}
+
+// a struct type declared on its own program line; its values live on the heap
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct StructDef {
+    pub name: String,
+    pub fields: Vec<(String, Type)>,
+    pub span: Span,
+}
+
+// what a program line parses to
+#[derive(Debug)]
+#[derive(Clone)]
+pub enum Line {
+    Expr(Expr),
+    Fun(FunDef),
+    Struct(StructDef),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompileError {
+    UnboundIdentifier(String, Span),
+    DuplicateBinding(String, Span),
+    KeywordAsName(String, Span),
+    TypeMismatch(String, Span),
+    BreakOutsideLoop(Span),
+    WrongArity(String, Span),
+    UnknownField(String, Span),
+    Parse(String, Span),
+    // an instruction the compiler produced that has no x64 encoding, which is a compiler bug
+    Assemble(String),
+}
+
+impl CompileError {
+    pub fn span(&self) -> Span {
+        match self {
+            CompileError::UnboundIdentifier(_, span)
+            | CompileError::DuplicateBinding(_, span)
+            | CompileError::KeywordAsName(_, span)
+            | CompileError::TypeMismatch(_, span)
+            | CompileError::BreakOutsideLoop(span)
+            | CompileError::WrongArity(_, span)
+            | CompileError::UnknownField(_, span)
+            | CompileError::Parse(_, span) => *span,
+            // instructions do not know which part of the line they came from
+            CompileError::Assemble(_) => Span::default(),
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompileError::UnboundIdentifier(name, _) => write!(f, "unbound variable {}", name),
+            CompileError::DuplicateBinding(name, _) => write!(f, "duplicate binding {}", name),
+            CompileError::KeywordAsName(name, _) => write!(f, "{} is a keyword", name),
+            CompileError::TypeMismatch(msg, _) => write!(f, "type mismatch: {}", msg),
+            CompileError::BreakOutsideLoop(_) => write!(f, "break outside of repeat-until"),
+            CompileError::WrongArity(name, _) => write!(f, "wrong number of arguments to {}", name),
+            CompileError::UnknownField(name, _) => write!(f, "no field {}", name),
+            CompileError::Parse(msg, _) => write!(f, "{}", msg),
+            CompileError::Assemble(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+// anything that can go wrong between eating ";" and getting a result back
+#[derive(Debug, Clone, PartialEq)]
+pub enum SnekError {
+    Compile(CompileError),
+    Runtime(RuntimeError),
+}
+
+impl fmt::Display for SnekError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnekError::Compile(e) => write!(f, "{}", e),
+            SnekError::Runtime(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<CompileError> for SnekError {
+    fn from(err: CompileError) -> Self {
+        SnekError::Compile(err)
+    }
+}
+
+impl From<RuntimeError> for SnekError {
+    fn from(err: RuntimeError) -> Self {
+        SnekError::Runtime(err)
+    }
+}
+
+impl From<ParseError<usize, Token, LexicalError>> for CompileError {
+    fn from(err: ParseError<usize, Token, LexicalError>) -> Self {
+        let span = match &err {
+            ParseError::InvalidToken { location } => Span::new(*location, *location),
+            ParseError::UnrecognizedEof { location, .. } => Span::new(*location, *location),
+            ParseError::UnrecognizedToken { token: (l, _, r), .. } => Span::new(*l, *r),
+            ParseError::ExtraToken { token: (l, _, r) } => Span::new(*l, *r),
+            ParseError::User { .. } => Span::default(),
+        };
+        let msg = match err {
+            ParseError::UnrecognizedEof { .. } => "unfinished line".to_string(),
+            ParseError::UnrecognizedToken { token: (_, tok, _), .. } => format!("unexpected {}", tok),
+            ParseError::ExtraToken { token: (_, tok, _) } => format!("extra {}", tok),
+            e => e.to_string(),
+        };
+        CompileError::Parse(msg, span)
+    }
 }
 
 #[derive(PartialEq)]
@@ -78,68 +262,298 @@ pub enum Expr {
 pub enum Type {
     Int,
     Bool,
+    Fun(Vec<Type>, Box<Type>),
+    // a pointer to a struct of the named type, which may be null
+    Struct(String),
+    // the type of null itself, which fits any struct
+    Null,
+    // the fields of a struct type, kept in the typing context under "struct <name>"
+    StructDef(Vec<(String, Type)>),
+}
+
+// The type two values share, if any. Null can stand in for any struct.
+pub fn unify(ty1: &Type, ty2: &Type) -> Option<Type> {
+    match (ty1, ty2) {
+        (Type::Null, Type::Struct(_)) => Some(ty2.clone()),
+        (Type::Struct(_), Type::Null) => Some(ty1.clone()),
+        _ if ty1 == ty2 => Some(ty1.clone()),
+        _ => None,
+    }
 }
 
-pub fn typecheck(e: &Expr, mut ctx: &mut HashMap<String, Type>) -> Type {
+// the declared fields of a struct type
+pub fn struct_fields(name: &str, ctx: &HashMap<String, Type>) -> Option<Vec<(String, Type)>> {
+    match ctx.get(&format!("struct {}", name)) {
+        Some(Type::StructDef(fields)) => Some(fields.clone()),
+        _ => None,
+    }
+}
+
+// `in_loop` says whether the expression sits inside a repeat-until, where it may break.
+pub fn typecheck(e: &Expr, ctx: &HashMap<String, Type>, in_loop: bool) -> Result<Type, CompileError> {
+    let mut break_ty = None;
+    check(e, ctx, if in_loop { Some(&mut break_ty) } else { None })
+}
+
+// `breaks` is None outside a loop; inside one it holds the type of the first break seen.
+fn check(e: &Expr, ctx: &HashMap<String, Type>, mut breaks: Option<&mut Option<Type>>) -> Result<Type, CompileError> {
     match e {
-        Expr::Number(_) => Type::Int,
-        Expr::BinOp(_, e1, e2) => {
-            let ty1 = typecheck(e1, &mut ctx);
-            let ty2 = typecheck(e2, &mut ctx);
-            if ty1 != ty2 {
-                panic!("Invalid: type mismatch binop");             
-            }
-            return Type::Int;
-            
+        Expr::Number(_) => Ok(Type::Int),
+        Expr::Boolean(_) => Ok(Type::Bool),
+        Expr::BinOp(op, e1, e2, span) => {
+            let ty1 = check(e1, ctx, breaks.as_deref_mut())?;
+            let ty2 = check(e2, ctx, breaks.as_deref_mut())?;
+            let ty1 = match unify(&ty1, &ty2) {
+                Some(ty) => ty,
+                None => return Err(CompileError::TypeMismatch("binop operands differ".to_string(), *span)),
+            };
+            match op {
+                Op2::Equal => Ok(Type::Bool),
+                _ if ty1 != Type::Int => {
+                    Err(CompileError::TypeMismatch("binop expects int".to_string(), *span))
+                },
+                Op2::Greater | Op2::Less | Op2::GreaterEqual | Op2::LessEqual => Ok(Type::Bool),
+                Op2::Plus | Op2::Minus | Op2::Times | Op2::Divide | Op2::Modulo => Ok(Type::Int),
+            }
         },
-        Expr::Id(name) => {   
-            match ctx.get(&name.to_string()) {
-                Some(ty) => ty.clone(),
-                None => panic!("Invalid: type error id {}", name),
+        Expr::Id(name, span) => {
+            match ctx.get(name) {
+                Some(ty) => Ok(ty.clone()),
+                None => Err(CompileError::UnboundIdentifier(name.clone(), *span)),
             }
         },
-        Expr::Let(bindings, body) => {
-            for binding in bindings {
+        Expr::Let(bindings, body, span) => {
+            // the bindings are only in scope inside the let
+            let mut let_ctx = ctx.clone();
+            for (idx, binding) in bindings.iter().enumerate() {
                 if KEYWORD_LIST.contains(&binding.0) {
-                    panic!("Invalid: variable name is a keyword");
+                    return Err(CompileError::KeywordAsName(binding.0.clone(), *span));
                 }
-                let ty1 = typecheck(&binding.1, &mut ctx);
-                *ctx = ctx.update(binding.0.clone(), ty1.clone());
+                if bindings[..idx].iter().any(|(name, _)| *name == binding.0) {
+                    return Err(CompileError::DuplicateBinding(binding.0.clone(), *span));
+                }
+                let ty1 = check(&binding.1, &let_ctx, breaks.as_deref_mut())?;
+                let_ctx.insert(binding.0.clone(), ty1);
             }
-            typecheck(body, &mut ctx)
+            check(body, &let_ctx, breaks)
         },
-        Expr::UnOp(op1, expr) => {
-            let ty1 = typecheck(expr, &mut ctx);
-            match op1 {
-                _ => {
-                    if ty1 != Type::Int {
-                        panic!("Invalid: type mismatch: UnOp expects int");
-                    }
-                    Type::Int
-                }
+        Expr::UnOp(_, expr, span) => {
+            if check(expr, ctx, breaks.as_deref_mut())? != Type::Int {
+                return Err(CompileError::TypeMismatch("unop expects int".to_string(), *span));
             }
+            Ok(Type::Int)
         },
-        Expr::Set(var_name, var_value) => {
+        Expr::Set(var_name, var_value, span) => {
             if KEYWORD_LIST.contains(var_name) {
-                panic!("Invalid: variable name is a keyword");
+                return Err(CompileError::KeywordAsName(var_name.clone(), *span));
+            }
+            let ty = check(var_value, ctx, breaks.as_deref_mut())?;
+            // a variable keeps its type, so a pointer can never be set to a number
+            match ctx.get(var_name) {
+                Some(var_ty) if unify(&ty, var_ty).as_ref() != Some(var_ty) => {
+                    Err(CompileError::TypeMismatch(format!("{} cannot change type", var_name), *span))
+                },
+                _ => Ok(ty),
+            }
+        },
+        Expr::If(cond, thn, els, span) => {
+            if check(cond, ctx, breaks.as_deref_mut())? != Type::Bool {
+                return Err(CompileError::TypeMismatch("if condition expects bool".to_string(), *span));
+            }
+            let thn_ty = check(thn, ctx, breaks.as_deref_mut())?;
+            let els_ty = check(els, ctx, breaks.as_deref_mut())?;
+            match unify(&thn_ty, &els_ty) {
+                Some(ty) => Ok(ty),
+                None => Err(CompileError::TypeMismatch("if branches differ".to_string(), *span)),
+            }
+        },
+        Expr::RepeatUntil(body, cond, span) => {
+            // a break in the body or the condition leaves this loop, not an enclosing one
+            let mut break_ty = None;
+            let body_ty = check(body, ctx, Some(&mut break_ty))?;
+            let cond_ty = check(cond, ctx, Some(&mut break_ty))?;
+            if cond_ty != Type::Bool {
+                return Err(CompileError::TypeMismatch("repeat-until condition expects bool".to_string(), *span));
+            }
+            match break_ty {
+                Some(ty) => match unify(&ty, &body_ty) {
+                    Some(ty) => Ok(ty),
+                    None => Err(CompileError::TypeMismatch("break value differs from loop body".to_string(), *span)),
+                },
+                None => Ok(body_ty),
+            }
+        },
+        Expr::Call(name, args, span) => {
+            let (param_tys, ret_ty) = match ctx.get(name) {
+                Some(Type::Fun(param_tys, ret_ty)) => (param_tys.clone(), *ret_ty.clone()),
+                Some(_) => return Err(CompileError::TypeMismatch(format!("{} is not a function", name), *span)),
+                None => return Err(CompileError::UnboundIdentifier(name.clone(), *span)),
+            };
+            if param_tys.len() != args.len() {
+                return Err(CompileError::WrongArity(name.clone(), *span));
+            }
+            for (arg, param_ty) in args.iter().zip(param_tys.iter()) {
+                if check(arg, ctx, breaks.as_deref_mut())? != *param_ty {
+                    return Err(CompileError::TypeMismatch(format!("argument to {}", name), *span));
+                }
+            }
+            Ok(ret_ty)
+        },
+        Expr::Break(value, span) => {
+            let Some(loop_break) = breaks else {
+                return Err(CompileError::BreakOutsideLoop(*span));
+            };
+            let ty = check(value, ctx, Some(&mut *loop_break))?;
+            let break_ty = match loop_break {
+                Some(break_ty) => unify(break_ty, &ty),
+                None => Some(ty.clone()),
+            };
+            match break_ty {
+                Some(break_ty) => {
+                    *loop_break = Some(break_ty);
+                    Ok(ty)
+                },
+                None => Err(CompileError::TypeMismatch("break values differ".to_string(), *span)),
+            }
+        },
+        Expr::Print(value, _) => check(value, ctx, breaks.as_deref_mut()),
+        Expr::Null => Ok(Type::Null),
+        Expr::Alloc(name, values, span) => {
+            let fields = match struct_fields(name, ctx) {
+                Some(fields) => fields,
+                None => return Err(CompileError::UnboundIdentifier(name.clone(), *span)),
+            };
+            if fields.len() != values.len() {
+                return Err(CompileError::WrongArity(name.clone(), *span));
+            }
+            for ((field, field_ty), value) in fields.iter().zip(values.iter()) {
+                if unify(&check(value, ctx, breaks.as_deref_mut())?, field_ty).as_ref() != Some(field_ty) {
+                    return Err(CompileError::TypeMismatch(format!("field {} of {}", field, name), *span));
+                }
+            }
+            Ok(Type::Struct(name.clone()))
+        },
+        Expr::Lookup(pointer, field, span) => {
+            let (_, field_ty) = field_of(check(pointer, ctx, breaks)?, field, *span, ctx)?;
+            Ok(field_ty)
+        },
+        Expr::Update(pointer, field, value, span) => {
+            let (_, field_ty) = field_of(check(pointer, ctx, breaks.as_deref_mut())?, field, *span, ctx)?;
+            let ty = check(value, ctx, breaks)?;
+            if unify(&ty, &field_ty).as_ref() != Some(&field_ty) {
+                return Err(CompileError::TypeMismatch(format!("field {}", field), *span));
             }
-            typecheck(var_value, &mut ctx)
+            Ok(ty)
         },
     }
 }
 
+// Where a field sits in the struct a pointer of type `pointer_ty` points to, and its type.
+pub fn field_of(pointer_ty: Type, field: &str, span: Span, ctx: &HashMap<String, Type>) -> Result<(usize, Type), CompileError> {
+    let name = match pointer_ty {
+        Type::Struct(name) => name,
+        _ => return Err(CompileError::TypeMismatch(format!("{} of something that is not a struct", field), span)),
+    };
+    let fields = match struct_fields(&name, ctx) {
+        Some(fields) => fields,
+        None => return Err(CompileError::UnboundIdentifier(name, span)),
+    };
+    match fields.iter().position(|(field_name, _)| field_name == field) {
+        Some(idx) => Ok((idx, fields[idx].1.clone())),
+        None => Err(CompileError::UnknownField(field.to_string(), span)),
+    }
+}
+
+// Checks a struct declaration against the structs before it. Fields may point to
+// structs of the type being declared.
+pub fn check_struct(def: &StructDef, structs: &[StructDef]) -> Result<(), CompileError> {
+    if KEYWORD_LIST.contains(&def.name) {
+        return Err(CompileError::KeywordAsName(def.name.clone(), def.span));
+    }
+    if structs.iter().any(|other| other.name == def.name) {
+        return Err(CompileError::DuplicateBinding(def.name.clone(), def.span));
+    }
+    let mut seen: HashSet<&String> = HashSet::new();
+    for (field, ty) in &def.fields {
+        if !seen.insert(field) {
+            return Err(CompileError::DuplicateBinding(field.clone(), def.span));
+        }
+        if let Type::Struct(name) = ty {
+            if *name != def.name && structs.iter().all(|other| other.name != *name) {
+                return Err(CompileError::UnboundIdentifier(name.clone(), def.span));
+            }
+        }
+    }
+    Ok(())
+}
+
+
+// Type of a function definition. The body may call the function itself, so it is first
+// checked assuming an int result and, if it turns out to be something else, checked again.
+pub fn typecheck_fun(def: &FunDef, ctx: &HashMap<String, Type>) -> Result<Type, CompileError> {
+    let mut seen: HashSet<&String> = HashSet::new();
+    for param in &def.params {
+        if KEYWORD_LIST.contains(param) {
+            return Err(CompileError::KeywordAsName(param.clone(), def.span));
+        }
+        if !seen.insert(param) {
+            return Err(CompileError::DuplicateBinding(param.clone(), def.span));
+        }
+    }
+    let param_tys = vec![Type::Int; def.params.len()];
+    let mut ret_ty = Type::Int;
+    for _ in 0..2 {
+        let mut body_ctx = ctx.update(def.name.clone(), Type::Fun(param_tys.clone(), Box::new(ret_ty.clone())));
+        for param in &def.params {
+            body_ctx.insert(param.clone(), Type::Int);
+        }
+        let body_ty = typecheck(&def.body, &body_ctx, false)?;
+        if body_ty == ret_ty {
+            return Ok(Type::Fun(param_tys, Box::new(ret_ty)));
+        }
+        ret_ty = body_ty;
+    }
+    Err(CompileError::TypeMismatch(format!("{} returns different types", def.name), def.span))
+}
 
 pub fn instr_to_str(i: &Instr) -> String {
     match i {
-        Instr::IMov(val1, val2) => format!("mov {}, {}\n", val_to_str(val1), val_to_str(val2)),
-        Instr::IAdd(val1, val2) => format!("add {}, {}\n", val_to_str(val1), val_to_str(val2)),
-        Instr::ISub(val1, val2) => format!("sub {}, {}\n", val_to_str(val1), val_to_str(val2)),
+        Instr::IMov(val1, val2) => format!("mov {}, {}\n", dest_to_str(val1, val2), val_to_str(val2)),
+        Instr::IAdd(val1, val2) => format!("add {}, {}\n", dest_to_str(val1, val2), val_to_str(val2)),
+        Instr::ISub(val1, val2) => format!("sub {}, {}\n", dest_to_str(val1, val2), val_to_str(val2)),
         Instr::IMul(val1, val2) => format!("imul {}, {}\n", val_to_str(val1), val_to_str(val2)),
-        Instr::Label(name, num) => format!("{}{}:\n", name.to_string(), num.to_string()),
-        Instr::CallSnekErr() => format!("call snek_error\n"),
-        Instr::Jno(name, num) => format!("jno {}{}\n", name.to_string(), num.to_string()),
+        Instr::IDiv(val1) => format!("idiv {}\n", val_to_str(val1)),
+        Instr::ICqo() => "cqo\n".to_string(),
+        Instr::IAnd(val1, val2) => format!("and {}, {}\n", val_to_str(val1), val_to_str(val2)),
+        Instr::ISar(val1, val2) => format!("sar {}, {}\n", val_to_str(val1), val_to_str(val2)),
+        Instr::ICmp(val1, val2) => format!("cmp {}, {}\n", dest_to_str(val1, val2), val_to_str(val2)),
+        Instr::ITest(val1, val2) => format!("test {}, {}\n", val_to_str(val1), val_to_str(val2)),
+        Instr::ICMovg(val1, val2) => format!("cmovg {}, {}\n", val_to_str(val1), val_to_str(val2)),
+        Instr::ICMovl(val1, val2) => format!("cmovl {}, {}\n", val_to_str(val1), val_to_str(val2)),
+        Instr::ICMovge(val1, val2) => format!("cmovge {}, {}\n", val_to_str(val1), val_to_str(val2)),
+        Instr::ICMovle(val1, val2) => format!("cmovle {}, {}\n", val_to_str(val1), val_to_str(val2)),
+        Instr::ICMove(val1, val2) => format!("cmove {}, {}\n", val_to_str(val1), val_to_str(val2)),
+        Instr::Label(name, num) => format!("{}{}:\n", name, num),
+        Instr::Jmp(name, num) => format!("jmp {}{}\n", name, num),
+        Instr::Je(name, num) => format!("je {}{}\n", name, num),
+        Instr::Jne(name, num) => format!("jne {}{}\n", name, num),
+        Instr::Jg(name, num) => format!("jg {}{}\n", name, num),
+        Instr::Jle(name, num) => format!("jle {}{}\n", name, num),
+        Instr::Call(name, num) => format!("call {}{}\n", name, num),
+        Instr::CallSnekErr() => "call snek_error\n".to_string(),
+        Instr::CallSnekPrint() => "call snek_print\n".to_string(),
+        Instr::Jno(name, num) => format!("jno {}{}\n", name, num),
         Instr::Pop(val1) => format!("pop {}\n", val_to_str(val1)),
         Instr::Push(val1) => format!("push {}\n", val_to_str(val1)),
+        Instr::Ret() => "ret\n".to_string(),
+    }
+}
+
+// memory operands need an explicit size when the other operand is an immediate
+fn dest_to_str(dest: &Val, src: &Val) -> String {
+    match (dest, src) {
+        (Val::RegOffset(..), Val::Imm(_)) => format!("qword {}", val_to_str(dest)),
+        _ => val_to_str(dest),
     }
 }
 
@@ -158,12 +572,14 @@ fn val_to_str(v: &Val) -> String {
         Val::Reg(Reg::R15) => String::from("r15"),
         Val::Reg(Reg::RDI) => String::from("rdi"),
         Val::Reg(Reg::RSI) => String::from("rsi"),
+        Val::Reg(Reg::RDX) => String::from("rdx"),
         Val::RegOffset(Reg::RBP, i) => format!("[rbp + {}]", *i),
         Val::RegOffset(Reg::RSP, i) => format!("[rsp + {}]", *i),
         Val::RegOffset(Reg::RAX, i) => format!("[rax + {}]", *i),
         Val::RegOffset(Reg::RBX, i) => format!("[rbx + {}]", *i),
         Val::RegOffset(Reg::RDI, i) => format!("[rdi + {}]", *i),
         Val::RegOffset(Reg::RSI, i) => format!("[rsi + {}]", *i),
+        Val::RegOffset(Reg::RDX, i) => format!("[rdx + {}]", *i),
         Val::RegOffset(Reg::RCX, i) => format!("[rcx + {}]", *i),
         Val::RegOffset(Reg::R12, i) => format!("[r12 + {}]", *i),
         Val::RegOffset(Reg::R13, i) => format!("[r13 + {}]", *i),
@@ -172,19 +588,25 @@ fn val_to_str(v: &Val) -> String {
     }
 }
 
-pub fn str_to_type(str: &String) -> Type {
+pub fn str_to_type(str: &str) -> Type {
     if str == "int" {
-        return Type::Int;
+        Type::Int
     } else if str == "bool" {
-        return Type::Bool;
+        Type::Bool
     } else {
         panic!("invalid type");
     }
 }
 
-pub fn _type_to_str(t: Type) -> String {
+pub fn type_to_str(t: Type) -> String {
     match t {
         Type::Bool => "bool".to_string(),
         Type::Int => "int".to_string(),
+        Type::Fun(params, ret) => format!("({}) -> {}",
+            params.into_iter().map(type_to_str).collect::<Vec<String>>().join(", "), type_to_str(*ret)),
+        Type::Struct(name) => name,
+        Type::Null => "null".to_string(),
+        Type::StructDef(fields) => format!("{{ {} }}", fields.into_iter()
+            .map(|(name, ty)| format!("{} {}", name, type_to_str(ty))).collect::<Vec<String>>().join(", ")),
     }
 }
\ No newline at end of file
@@ -0,0 +1,25 @@
+use snake_interpreter::compile::instrs_to_asm;
+use snake_interpreter::utils::{CompileError, Instr, Reg, Val};
+
+fn assemble(instr: &Instr) -> Result<(), CompileError> {
+    let mut ops = dynasmrt::x64::Assembler::new().unwrap();
+    instrs_to_asm(std::slice::from_ref(instr), &mut ops)
+}
+
+// instructions x64 cannot encode are reported with the operation they are, instead of panicking
+#[test]
+fn unencodable_instructions_are_errors() {
+    let cases = [
+        (Instr::ISub(Val::Imm(1), Val::Reg(Reg::RAX)), "cannot assemble sub"),
+        (Instr::IMul(Val::Reg(Reg::RAX), Val::Imm(3)), "cannot assemble imul"),
+        (Instr::Push(Val::RegOffset(Reg::RSP, 8)), "cannot assemble push"),
+        (Instr::IAdd(Val::Reg(Reg::RAX), Val::Imm(1 << 40)), "does not fit in 32 bits"),
+        (Instr::ICmp(Val::Reg(Reg::RCX), Val::Imm(i64::MIN)), "does not fit in 32 bits"),
+    ];
+    for (instr, expected) in cases {
+        let error = assemble(&instr).expect_err("should not assemble").to_string();
+        assert!(error.contains(expected), "{:?} gave {:?}, expected {:?}", instr, error, expected);
+    }
+    assert!(assemble(&Instr::IMov(Val::Reg(Reg::RAX), Val::Imm(1 << 40))).is_ok());
+    assert!(assemble(&Instr::ISub(Val::Reg(Reg::RSP), Val::Imm(i32::MAX as i64))).is_ok());
+}
@@ -0,0 +1,431 @@
+use im::HashMap;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use snake_interpreter::eval::{eval, Env};
+use snake_interpreter::grammar::LineParser;
+use snake_interpreter::jit::Jit;
+use snake_interpreter::lexer::Lexer;
+use snake_interpreter::runtime::{RuntimeError, Value};
+use snake_interpreter::utils::{Expr, FunDef, Line, Op1, Op2, SnekError, Span, StructDef, Type};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Ty {
+    Int,
+    Bool,
+    // a pointer to the struct of the given index, which is never null where this is asked for
+    Struct(usize),
+}
+
+// Builds random expressions that typecheck, so that every difference between the two
+// implementations is a bug in one of them rather than a rejected program.
+struct Gen {
+    rng: StdRng,
+    // functions defined so far and their arity; all of them return ints
+    funs: Vec<(String, usize)>,
+    // field types of the structs defined so far, which are called s0, s1, ... with fields g0, g1, ...
+    structs: Vec<Vec<Ty>>,
+    next_var: usize,
+}
+
+struct Scope {
+    // let bindings and parameters that can be read and set
+    locals: Vec<(String, Ty)>,
+    // heap variables, which can only be read
+    globals: Vec<String>,
+    // body type of the innermost loop, which is what a break there must produce
+    loop_ty: Option<Ty>,
+}
+
+impl Scope {
+    // locals of the given type that are not shadowed by a later binding of the same name
+    fn visible(&self, ty: Ty) -> Vec<String> {
+        self.locals.iter().enumerate()
+            .filter(|(idx, (name, var_ty))| {
+                *var_ty == ty && !self.locals[idx + 1..].iter().any(|(other, _)| other == name)
+            })
+            .map(|(_, (name, _))| name.clone())
+            .collect()
+    }
+}
+
+impl Gen {
+    fn new(seed: u64) -> Gen {
+        Gen { rng: StdRng::seed_from_u64(seed), funs: vec![], structs: vec![], next_var: 0 }
+    }
+
+    fn number(&mut self) -> Expr {
+        match self.rng.gen_range(0..10) {
+            0 => Expr::Number(self.rng.gen()),
+            1 => Expr::Number(self.rng.gen_range(i32::MIN as i64..=i32::MAX as i64)),
+            2..=3 => Expr::Number(self.rng.gen_range(-1000..1000)),
+            _ => Expr::Number(self.rng.gen_range(0..10)),
+        }
+    }
+
+    fn expr(&mut self, ty: Ty, depth: u32, scope: &mut Scope) -> Expr {
+        if depth == 0 || self.rng.gen_range(0..6) == 0 {
+            return self.leaf(ty, scope);
+        }
+        let span = Span::default();
+        match self.rng.gen_range(0..12) {
+            0 => self.let_expr(ty, depth, scope),
+            1 => {
+                let cond = self.expr(Ty::Bool, depth - 1, scope);
+                let thn = self.expr(ty, depth - 1, scope);
+                let els = self.expr(ty, depth - 1, scope);
+                Expr::If(Box::new(cond), Box::new(thn), Box::new(els), span)
+            },
+            2 if self.rng.gen_range(0..3) == 0 => self.loop_expr(ty, depth, scope),
+            3 if scope.loop_ty == Some(ty) => {
+                Expr::Break(Box::new(self.expr(ty, depth - 1, scope)), span)
+            },
+            4 => {
+                let settable = scope.visible(ty);
+                if settable.is_empty() {
+                    return self.leaf(ty, scope);
+                }
+                let name = settable[self.rng.gen_range(0..settable.len())].clone();
+                Expr::Set(name, Box::new(self.expr(ty, depth - 1, scope)), span)
+            },
+            6 if self.rng.gen_range(0..3) == 0 => {
+                Expr::Print(Box::new(self.expr(ty, depth - 1, scope)), span)
+            },
+            5 if ty == Ty::Int && !self.funs.is_empty() => {
+                let (name, arity) = self.funs[self.rng.gen_range(0..self.funs.len())].clone();
+                let args = (0..arity).map(|_| self.expr(Ty::Int, depth - 1, scope)).collect();
+                Expr::Call(name, args, span)
+            },
+            7 if !self.fields_of(ty).is_empty() => {
+                let (pointer, field) = self.field(ty, depth, scope);
+                Expr::Lookup(Box::new(pointer), field, span)
+            },
+            8 if !self.fields_of(ty).is_empty() => {
+                let (pointer, field) = self.field(ty, depth, scope);
+                Expr::Update(Box::new(pointer), field, Box::new(self.expr(ty, depth - 1, scope)), span)
+            },
+            _ if ty == Ty::Int => {
+                if self.rng.gen_range(0..4) == 0 {
+                    let op = match self.rng.gen_range(0..2) {
+                        0 => Op1::Add1,
+                        _ => Op1::Sub1,
+                    };
+                    return Expr::UnOp(op, Box::new(self.expr(Ty::Int, depth - 1, scope)), span);
+                }
+                let op = match self.rng.gen_range(0..5) {
+                    0 => Op2::Plus,
+                    1 => Op2::Minus,
+                    2 => Op2::Times,
+                    3 => Op2::Divide,
+                    _ => Op2::Modulo,
+                };
+                let e1 = self.expr(Ty::Int, depth - 1, scope);
+                let e2 = self.expr(Ty::Int, depth - 1, scope);
+                Expr::BinOp(op, Box::new(e1), Box::new(e2), span)
+            },
+            _ if ty == Ty::Bool => {
+                let (op, operand_ty) = match self.rng.gen_range(0..7) {
+                    0 => (Op2::Greater, Ty::Int),
+                    1 => (Op2::Less, Ty::Int),
+                    2 => (Op2::GreaterEqual, Ty::Int),
+                    3 => (Op2::LessEqual, Ty::Int),
+                    4 => (Op2::Equal, Ty::Int),
+                    5 if !self.structs.is_empty() => (Op2::Equal, Ty::Struct(self.rng.gen_range(0..self.structs.len()))),
+                    _ => (Op2::Equal, Ty::Bool),
+                };
+                // pointers are compared by where they point, and either can be null
+                let e1 = self.field_value(operand_ty, depth - 1, scope);
+                let e2 = self.field_value(operand_ty, depth - 1, scope);
+                Expr::BinOp(op, Box::new(e1), Box::new(e2), span)
+            },
+            _ => {
+                let Ty::Struct(idx) = ty else { unreachable!() };
+                let values = self.structs[idx].clone().into_iter()
+                    .map(|field_ty| self.field_value(field_ty, depth - 1, scope))
+                    .collect();
+                Expr::Alloc(format!("s{}", idx), values, span)
+            },
+        }
+    }
+
+    // what can go in a field of the given type, which is null now and then for a pointer
+    fn field_value(&mut self, ty: Ty, depth: u32, scope: &mut Scope) -> Expr {
+        match ty {
+            Ty::Struct(_) if self.rng.gen_range(0..3) == 0 => Expr::Null,
+            _ => self.expr(ty, depth, scope),
+        }
+    }
+
+    // the structs and their fields that have the given type
+    fn fields_of(&self, ty: Ty) -> Vec<(usize, usize)> {
+        self.structs.iter().enumerate()
+            .flat_map(|(idx, fields)| {
+                fields.iter().enumerate().filter(move |(_, field_ty)| **field_ty == ty).map(move |(field, _)| (idx, field))
+            })
+            .collect()
+    }
+
+    // a pointer and the name of one of its fields that has the given type
+    fn field(&mut self, ty: Ty, depth: u32, scope: &mut Scope) -> (Expr, String) {
+        let fields = self.fields_of(ty);
+        let (idx, field) = fields[self.rng.gen_range(0..fields.len())];
+        (self.expr(Ty::Struct(idx), depth - 1, scope), format!("g{}", field))
+    }
+
+    fn leaf(&mut self, ty: Ty, scope: &Scope) -> Expr {
+        let mut vars = scope.visible(ty);
+        if ty == Ty::Int {
+            vars.extend(scope.globals.iter().cloned());
+            vars.push("input".to_string());
+        }
+        if !vars.is_empty() && self.rng.gen_range(0..2) == 0 {
+            return Expr::Id(vars[self.rng.gen_range(0..vars.len())].clone(), Span::default());
+        }
+        match ty {
+            Ty::Int => self.number(),
+            Ty::Bool => Expr::Boolean(self.rng.gen()),
+            Ty::Struct(idx) => {
+                let values = self.structs[idx].clone().into_iter()
+                    .map(|field_ty| match field_ty {
+                        Ty::Struct(_) => Expr::Null,
+                        _ => self.leaf(field_ty, scope),
+                    })
+                    .collect();
+                Expr::Alloc(format!("s{}", idx), values, Span::default())
+            },
+        }
+    }
+
+    // mostly ints, as those are what most of the language works on
+    fn any_ty(&mut self) -> Ty {
+        match self.rng.gen_range(0..6) {
+            0 => Ty::Bool,
+            1 if !self.structs.is_empty() => Ty::Struct(self.rng.gen_range(0..self.structs.len())),
+            _ => Ty::Int,
+        }
+    }
+
+    fn let_expr(&mut self, ty: Ty, depth: u32, scope: &mut Scope) -> Expr {
+        let outer_len = scope.locals.len();
+        let mut bindings = vec![];
+        for _ in 0..self.rng.gen_range(1..4) {
+            // a small pool of names, so inner lets shadow outer ones now and then
+            let name = format!("y{}", self.next_var % 4);
+            self.next_var += 1;
+            if bindings.iter().any(|(other, _)| *other == name) {
+                continue;
+            }
+            let var_ty = self.any_ty();
+            bindings.push((name.clone(), self.expr(var_ty, depth - 1, scope)));
+            scope.locals.push((name, var_ty));
+        }
+        let body = self.expr(ty, depth - 1, scope);
+        scope.locals.truncate(outer_len);
+        Expr::Let(bindings, Box::new(body), Span::default())
+    }
+
+    // loops count a fresh variable down so that most of them end on their own
+    fn loop_expr(&mut self, ty: Ty, depth: u32, scope: &mut Scope) -> Expr {
+        let span = Span::default();
+        let counter = format!("i{}", self.next_var);
+        self.next_var += 1;
+        let start = Expr::Number(self.rng.gen_range(0..20));
+
+        scope.locals.push((counter.clone(), Ty::Int));
+        let outer_loop_ty = scope.loop_ty.replace(ty);
+        let tick = Expr::Set(counter.clone(), Box::new(Expr::UnOp(Op1::Sub1,
+            Box::new(Expr::Id(counter.clone(), span)), span)), span);
+        let body = self.expr(ty, depth - 1, scope);
+        let done = Expr::BinOp(Op2::Less, Box::new(tick), Box::new(Expr::Number(0)), span);
+        let cond = match self.rng.gen_range(0..4) {
+            0 => self.expr(Ty::Bool, depth - 1, scope),
+            _ => done,
+        };
+        scope.loop_ty = outer_loop_ty;
+        scope.locals.pop();
+
+        let repeat = Expr::RepeatUntil(Box::new(body), Box::new(cond), span);
+        Expr::Let(vec![(counter, start)], Box::new(repeat), span)
+    }
+
+    fn fun_def(&mut self, globals: &[String]) -> FunDef {
+        let name = format!("f{}", self.funs.len());
+        let params: Vec<String> = (0..self.rng.gen_range(0..4)).map(|idx| format!("a{}", idx)).collect();
+        let mut scope = Scope {
+            locals: params.iter().map(|param| (param.clone(), Ty::Int)).collect(),
+            globals: globals.to_vec(),
+            loop_ty: None,
+        };
+        // some functions call themselves, which mostly ends in running out of stack or fuel
+        let recursive = self.rng.gen_range(0..4) == 0;
+        if recursive {
+            self.funs.push((name.clone(), params.len()));
+        }
+        let body = self.expr(Ty::Int, 4, &mut scope);
+        if !recursive {
+            self.funs.push((name.clone(), params.len()));
+        }
+        FunDef { name, params, body, span: Span::default() }
+    }
+
+    fn struct_def(&mut self) -> StructDef {
+        let idx = self.structs.len();
+        let fields: Vec<Ty> = (0..self.rng.gen_range(0..4))
+            .map(|_| match self.rng.gen_range(0..4) {
+                0 => Ty::Bool,
+                1 => Ty::Struct(self.rng.gen_range(0..=idx)),
+                _ => Ty::Int,
+            })
+            .collect();
+        let typed = fields.iter().enumerate().map(|(field, ty)| {
+            let ty = match ty {
+                Ty::Int => Type::Int,
+                Ty::Bool => Type::Bool,
+                Ty::Struct(other) => Type::Struct(format!("s{}", other)),
+            };
+            (format!("g{}", field), ty)
+        });
+        let def = StructDef { name: format!("s{}", idx), fields: typed.collect(), span: Span::default() };
+        self.structs.push(fields);
+        def
+    }
+}
+
+// Every snek call is a few nested Rust calls in the interpreter, so reaching the jitted code's
+// stack limit takes far more stack than a test thread has. Gives back what the line printed too.
+fn eval_result(e: &Expr, env: Env) -> (Result<Value, SnekError>, Vec<String>) {
+    let e = e.clone();
+    let mut env = env;
+    std::thread::Builder::new()
+        .stack_size(1 << 30)
+        .spawn(move || (eval(&e, &mut env), env.output()))
+        .unwrap()
+        .join()
+        .unwrap()
+}
+
+#[test]
+fn jit_agrees_with_eval_on_random_programs() {
+    for seed in 0..500 {
+        let mut gen = Gen::new(seed);
+        let mut jit = Jit::new();
+        let mut globals = vec![];
+        for idx in 0..gen.rng.gen_range(0..3) {
+            jit.save_def(gen.rng.gen_range(-100..100));
+            globals.push(format!("x{}", idx));
+        }
+        for _ in 0..gen.rng.gen_range(0..3) {
+            let def = gen.struct_def();
+            jit.define_struct(def.clone()).unwrap_or_else(|e| panic!("seed {}: {} in {:?}", seed, e, def));
+        }
+        for _ in 0..gen.rng.gen_range(0..3) {
+            let def = gen.fun_def(&globals);
+            jit.define_fun(def.clone()).unwrap_or_else(|e| panic!("seed {}: {} in {:?}", seed, e, def));
+        }
+        let mut scope = Scope { locals: vec![], globals: globals.clone(), loop_ty: None };
+        let ty = gen.any_ty();
+        let expr = gen.expr(ty, 6, &mut scope);
+
+        let input = match gen.rng.gen_range(0..4) {
+            0 => gen.rng.gen(),
+            _ => gen.rng.gen_range(-100..100),
+        };
+        jit.set_input(input);
+
+        let defined_vars: HashMap<String, i64> = globals.iter().cloned()
+            .zip(jit.def_bindings().iter().copied()).collect();
+        let mut env = Env::new(defined_vars, jit.struct_defs(), jit.fun_defs());
+        env.set_input(input);
+        let expected = eval_result(&expr, env);
+        let actual = (jit.run_expr(&expr), jit.output().to_vec());
+        assert_eq!(actual, expected, "seed {}: {:?} with {:?}", seed, expr, jit.fun_defs());
+    }
+}
+
+#[test]
+fn jit_agrees_with_eval_on_edge_cases() {
+    let programs: Vec<&[&str]> = vec![
+        &["( 2147483647 + 1 )"],
+        &["( ( 2147483647 * 2147483647 ) * 2147483647 )"],
+        &["( 4611686018427387903 + 0 )"],
+        &["( -4611686018427387904 / -1 )"],
+        &["( -4611686018427387904 % -1 )"],
+        &["( ( 7 / -2 ) * ( -7 % 2 ) )"],
+        &["( 1 / ( 3 - 3 ) )"],
+        &["( 1 % 0 )"],
+        &["( 4611686018427387903 + 1 )"],
+        &["( -4611686018427387904 - 1 )"],
+        &["( -4611686018427387904 * -1 )"],
+        &["9223372036854775807"],
+        &["repeat-until { 1 } { false }"],
+        &["fun f0 ( a0 | ) { ( apply f0 ( a0 | ) ) }", "( apply f0 ( 1 | ) )"],
+        &["fun f0 ( a0 | ) { let { var y0 := print add1 a0 | var y1 := ( y0 * 2 ) | } { apply f0 ( ( y1 - y0 ) | ) } }",
+            "let { var y0 := 0 | } { apply f0 ( y0 | ) }"],
+        &["fun f0 ( a0 | ) { if ( a0 = 0 ) { 0 } { ( a0 + ( apply f0 ( ( a0 - 1 ) | ) ) ) } }",
+            "( apply f0 ( 1000 | ) )"],
+        &["let { var y0 := 3 | } { repeat-until { ( let { var y1 := 1 | } { break y0 } ) } { true } }"],
+        &["let { var y0 := 5 | } { ( ( set y0 := 1 ) + y0 ) }"],
+        &["struct s0 { g0 i64 | g1 s0 | }",
+            "let { var y0 := alloc s0 ( 1 | alloc s0 ( 2 | null | ) | ) | } { ( lookup y0 [ g0 ] + lookup lookup y0 [ g1 ] [ g0 ] ) }"],
+        &["struct s0 { g0 i64 | g1 s0 | }", "lookup lookup alloc s0 ( 1 | null | ) [ g1 ] [ g0 ]"],
+        &["struct s0 { g0 i64 | }", "let { var y0 := alloc s0 ( 1 | ) | } { ( ( index y0 [ g0 ] := 41 ) + lookup y0 [ g0 ] ) }"],
+        &["struct s0 { }", "( alloc s0 ( ) = alloc s0 ( ) )"],
+        &["let { var y0 := 3 | } { repeat-until { print ( set y0 := sub1 y0 ) } { print ( y0 = 0 ) } }"],
+        &["struct s0 { g0 i64 | g1 s0 | }",
+            "let { var y0 := print alloc s0 ( 1 | print alloc s0 ( 2 | print null | ) | ) | } { lookup y0 [ g0 ] }"],
+        &["fun f0 ( a0 | ) { if ( a0 = 0 ) { print 0 } { ( print a0 + apply f0 ( sub1 a0 | ) ) } }", "apply f0 ( 3 | )"],
+        &["struct s0 { g0 i64 | }", "repeat-until { lookup alloc s0 ( 1 | ) [ g0 ] } { false }"],
+        &["struct s0 { g0 i64 | g1 s0 | }",
+            "let { var y0 := 0 | } { repeat-until { set y0 := lookup alloc s0 ( add1 y0 | null | ) [ g0 ] } { false } }"],
+        // lines can give back structs and null, shown by where they are on the heap
+        &["struct s0 { g0 i64 | g1 s0 | }", "let { var y0 := alloc s0 ( 1 | null | ) | } { alloc s0 ( 2 | y0 | ) }"],
+        &["struct s0 { g0 i64 | g1 s0 | }", "lookup alloc s0 ( 1 | null | ) [ g1 ]"],
+        &["null"],
+    ];
+    for lines in programs {
+        let mut jit = Jit::new();
+        let (main_line, fun_lines) = lines.split_last().unwrap();
+        for line in fun_lines {
+            jit.run_line(line).unwrap();
+        }
+        let expr = match LineParser::new().parse(Lexer::new(main_line)).unwrap() {
+            Line::Expr(expr) => expr,
+            _ => panic!("{} is not an expression", main_line),
+        };
+        let env = Env::new(HashMap::new(), jit.struct_defs(), jit.fun_defs());
+        let actual = (jit.run_expr(&expr), jit.output().to_vec());
+        assert_eq!(actual, eval_result(&expr, env), "{:?}", lines);
+    }
+}
+
+// Every Jit runs its lines with its own fuel, heap and error, so lines running at the same
+// time on different threads cannot unwind to each other's stack or see each other's output.
+#[test]
+fn jits_on_different_threads_keep_their_own_state() {
+    let lines = [
+        "repeat-until { 1 } { false }",
+        "( 1 / 0 )",
+        "let { var y0 := 3 | } { repeat-until { print ( set y0 := sub1 y0 ) } { ( y0 = 0 ) } }",
+        "( input * 2 )",
+    ];
+    let run = |line: &str| {
+        let mut jit = Jit::new();
+        jit.set_input(21);
+        (jit.run_line(line), jit.output().to_vec())
+    };
+    let expected: Vec<_> = lines.iter().map(|line| run(line)).collect();
+    assert_eq!(expected[0].0, Err(RuntimeError::OutOfFuel.into()));
+    assert_eq!(expected[1].0, Err(RuntimeError::DivideByZero.into()));
+    assert_eq!(expected[2], (Ok(Some(Value::Int(0))), vec!["2".to_string(), "1".to_string(), "0".to_string()]));
+    assert_eq!(expected[3].0, Ok(Some(Value::Int(42))));
+
+    std::thread::scope(|scope| {
+        for (line, expected) in lines.iter().zip(&expected) {
+            scope.spawn(move || {
+                for _ in 0..20 {
+                    assert_eq!(run(line), *expected, "{}", line);
+                }
+            });
+        }
+    });
+}
@@ -0,0 +1,271 @@
+use std::collections::{HashSet, VecDeque};
+
+use snake_interpreter::game_core::GameCore;
+use snake_interpreter::jit::Jit;
+use snake_interpreter::level::Level;
+use snake_interpreter::next_tokens::{Globals, NextTokens};
+use snake_interpreter::puzzle::Puzzle;
+use snake_interpreter::runtime::Value;
+use snake_interpreter::snake::Direction;
+use snake_interpreter::symbols::{BindingKind, SymbolTable};
+use snake_interpreter::utils::SnekError;
+
+// the board the game window has
+const START_X: i32 = 10;
+const WIDTH: i32 = 30;
+const HEIGHT: i32 = 20;
+
+const DIRECTIONS: [(Direction, (i32, i32)); 4] = [
+    (Direction::Up, (0, -1)),
+    (Direction::Down, (0, 1)),
+    (Direction::Left, (-1, 0)),
+    (Direction::Right, (1, 0)),
+];
+
+// Steers the snake to the nearest food showing `instr` the shortest way that does not cross
+// the border, the snake or any other food, and eats it.
+fn eat(core: &mut GameCore, instr: &str) {
+    let targets: HashSet<(i32, i32)> = core.food().iter()
+        .filter(|food| food.instr == instr)
+        .map(|food| (food.food_x, food.food_y))
+        .collect();
+    let foods: Vec<&str> = core.food().iter().map(|food| food.instr.as_str()).collect();
+    assert!(!targets.is_empty(), "no {} on the board after {:?}, only {:?}", instr, core.prog_line(), foods);
+    let (width, height) = core.board();
+    let blocked = |(x, y): (i32, i32)| {
+        x <= START_X || x >= START_X + width - 1 || y <= 0 || y >= height - 1
+            || core.snake().is_overlap_except_tail(x, y)
+            || core.obstacles().contains(&(x, y))
+            || (core.food().iter().any(|food| (food.food_x, food.food_y) == (x, y)) && !targets.contains(&(x, y)))
+    };
+
+    // breadth first from the head, remembering the way each block was reached
+    let head = core.snake().head_position();
+    let mut came_from = std::collections::HashMap::new();
+    let mut queue = VecDeque::from([head]);
+    let mut found = None;
+    while let Some(at) = queue.pop_front() {
+        if targets.contains(&at) {
+            found = Some(at);
+            break;
+        }
+        for (dir, (dx, dy)) in DIRECTIONS {
+            let next = (at.0 + dx, at.1 + dy);
+            if next != head && !came_from.contains_key(&next) && !blocked(next) {
+                came_from.insert(next, (at, dir));
+                queue.push_back(next);
+            }
+        }
+    }
+    let mut at = found.unwrap_or_else(|| panic!("no way to {} after {:?}", instr, core.prog_line()));
+    let mut path = vec![];
+    while at != head {
+        let (prev, dir) = came_from[&at];
+        path.push(dir);
+        at = prev;
+    }
+    // eating the food can end the round, but nothing on the way to it can
+    for dir in path.into_iter().rev() {
+        assert!(!core.is_game_over(), "the snake died on the way to {}", instr);
+        core.tick(Some(dir));
+    }
+}
+
+fn eat_all(core: &mut GameCore, foods: &[&str]) {
+    for food in foods {
+        eat(core, food);
+    }
+}
+
+#[test]
+fn lines_run_when_their_semicolon_is_eaten() {
+    let mut core = GameCore::with_goal(START_X, WIDTH, HEIGHT, 1, 1000);
+    eat_all(&mut core, &["true", ")"]);
+    assert_eq!(core.prog_line().split_whitespace().collect::<Vec<_>>(), ["(", "true", ")"]);
+    assert!(core.program().is_empty());
+    eat(&mut core, ";");
+    let prog = &core.program()[0];
+    assert_eq!(prog.result, Ok(Some(Value::Bool(true))));
+    assert_eq!(prog.score, None);
+    assert!(!prog.asm.is_empty());
+    assert_eq!(core.prog_line(), "( ");
+    assert!(!core.reached_goal());
+}
+
+#[test]
+fn ints_count_the_blocks_traveled_and_reach_the_goal() {
+    let mut core = GameCore::with_goal(START_X, WIDTH, HEIGHT, 2, 1000);
+    eat(&mut core, "int");
+    let start = core.ticks();
+    eat(&mut core, "end_int");
+    let blocks = (core.ticks() - start) as i64;
+    eat_all(&mut core, &[")", ";"]);
+    assert_eq!(core.program()[0].result, Ok(Some(Value::Int(blocks))));
+    assert!(!core.reached_goal());
+
+    // the same number again, on a core whose goal it is
+    let mut core = GameCore::with_goal(START_X, WIDTH, HEIGHT, 2, blocks);
+    eat_all(&mut core, &["int", "end_int", ")", ";"]);
+    assert!(core.reached_goal());
+}
+
+#[test]
+fn def_lines_save_their_result_as_the_next_x() {
+    let mut core = GameCore::with_goal(START_X, WIDTH, HEIGHT, 3, 1000);
+    eat_all(&mut core, &["def", "int", "end_int", ")", ";"]);
+    let Ok(Some(Value::Int(saved))) = core.program()[0].result else {
+        panic!("the def line gave {:?}", core.program()[0].result);
+    };
+    let heap: Vec<(String, Option<i64>)> = core.symbols().heap().map(|binding| (binding.name.clone(), binding.value)).collect();
+    assert_eq!(heap, vec![("x0".to_string(), Some(saved))]);
+    assert_eq!(core.jit().def_bindings(), &[saved]);
+
+    // a heap variable is written into the line as its value
+    eat_all(&mut core, &["id", "x0", ")", ";"]);
+    assert_eq!(core.program()[1].line.split_whitespace().collect::<Vec<_>>(), ["(", &saved.to_string(), ")"]);
+    assert_eq!(core.program()[1].result, Ok(Some(Value::Int(saved))));
+}
+
+#[test]
+fn def_lines_only_save_ints() {
+    let mut core = GameCore::with_goal(START_X, WIDTH, HEIGHT, 4, 1000);
+    eat(&mut core, "def");
+    assert!(core.food().iter().all(|food| food.instr != "true"), "a def line was offered true");
+    eat_all(&mut core, &["int", "end_int", ")", ";"]);
+    assert_eq!(core.symbols().heap().count(), 1);
+}
+
+#[test]
+fn errors_stay_with_their_line() {
+    let mut core = GameCore::with_goal(START_X, WIDTH, HEIGHT, 5, 1000);
+    eat_all(&mut core, &["def", "int", "end_int", ")", ";"]);
+    // x0 divided by x0 - x0
+    eat_all(&mut core, &["id", "x0", "/", "(", "id", "x0", "-", "id", "x0", ")", ")", ";"]);
+    let prog = &core.program()[1];
+    assert!(matches!(prog.result, Err(SnekError::Runtime(_))), "{} gave {:?}", prog.line, prog.result);
+    // the line after it starts over, with x0 still there
+    eat_all(&mut core, &["id", "x0", ")", ";"]);
+    assert!(core.program()[2].result.is_ok());
+}
+
+#[test]
+fn puzzle_lines_are_scored_on_every_input() {
+    let puzzle = Puzzle::by_name("double").unwrap();
+
+    let mut core = GameCore::new(START_X, WIDTH, HEIGHT, 13);
+    core.set_puzzle(puzzle);
+    // only the input of 0 comes out right
+    eat_all(&mut core, &["id", "input", ")", ";"]);
+    assert_eq!(core.program()[0].score, Some(1));
+    assert!(!core.reached_goal());
+
+    eat_all(&mut core, &["id", "input", "+", "id", "input", ")", ";"]);
+    assert_eq!(core.program()[1].score, Some(puzzle.inputs.len()));
+    // the line shows its result for the first input
+    assert_eq!(core.program()[1].result, Ok(Some(Value::Int(2 * puzzle.inputs[0]))));
+    assert!(core.reached_goal());
+}
+
+#[test]
+fn fun_lines_define_functions_without_running() {
+    let mut core = GameCore::with_goal(START_X, WIDTH, HEIGHT, 7, 1000);
+    eat_all(&mut core, &["fun", "param", ")", "{", "id", "a0", "}", ";"]);
+    assert_eq!(core.program()[0].result, Ok(None));
+    assert_eq!(core.program()[0].score, None);
+    assert_eq!(core.jit().fun_defs().len(), 1);
+    // the parameter is only bound on its own line
+    assert_eq!(core.symbols().line_bindings().count(), 0);
+    assert!(core.food().iter().any(|food| food.instr == "apply"));
+}
+
+#[test]
+fn restarting_forgets_the_program() {
+    let mut core = GameCore::with_goal(START_X, WIDTH, HEIGHT, 8, 1000);
+    eat_all(&mut core, &["def", "int", "end_int", ")", ";"]);
+    let seed = core.seed();
+    core.restart();
+    assert_ne!(core.seed(), seed);
+    assert_eq!((core.ticks(), core.program().len(), core.prog_line()), (0, 0, "( "));
+    assert_eq!(core.symbols().heap().count(), 0);
+    assert!(core.jit().def_bindings().is_empty());
+    assert_eq!(core.goal(), 1000);
+}
+
+#[test]
+fn exported_programs_keep_the_funs_and_the_last_line() {
+    let mut core = GameCore::with_goal(START_X, WIDTH, HEIGHT, 9, 1000);
+    let path = std::env::temp_dir().join(format!("snek-export-{}.snek", std::process::id()));
+    let path = path.to_str().unwrap();
+    assert!(core.export_program(path).is_err(), "nothing has run yet");
+
+    eat_all(&mut core, &["fun", "param", ")", "{", "id", "a0", "}", ";"]);
+    eat_all(&mut core, &["true", ")", ";"]);
+    core.export_program(path).unwrap();
+    let exported = std::fs::read_to_string(path).unwrap();
+    std::fs::remove_file(path).unwrap();
+    let lines: Vec<Vec<&str>> = exported.lines().map(|line| line.split_whitespace().collect()).collect();
+    assert_eq!(lines, vec![
+        vec!["fun", "f0", "(", "a0", "|", ")", "{", "a0", "};"],
+        vec!["(", "true", ")", ";"],
+    ]);
+}
+
+#[test]
+fn levels_hold_the_goal_back_until_the_line_uses_what_they_require() {
+    let level = Level::parse("name doubling\npuzzle double\nrequire let\nmax-lines 2\n").unwrap();
+    let mut core = GameCore::new(START_X, WIDTH, HEIGHT, 13);
+    core.set_level(level);
+    eat_all(&mut core, &["id", "input", "+", "id", "input", ")", ";"]);
+    assert!(!core.reached_goal());
+    assert_eq!(core.program()[0].level_note.as_deref(), Some("the level needs let"));
+    assert!(!core.is_game_over());
+
+    // the second line is the last one the level allows
+    eat_all(&mut core, &["id", "input", ")", ";"]);
+    assert_eq!(core.program()[1].level_note.as_deref(), Some("out of lines"));
+    assert!(core.is_game_over());
+}
+
+#[test]
+fn symbols_follow_the_lets_of_the_line() {
+    let mut symbols = SymbolTable::new();
+    assert_eq!(symbols.define(5), "x0");
+    assert_eq!(symbols.define(7), "x1");
+    symbols.bind_param("a0".to_string());
+    symbols.follow_lets(&[vec!["y0".to_string()]]);
+    symbols.follow_lets(&[vec!["y0".to_string()], vec!["y1".to_string()]]);
+
+    let bindings: Vec<(&str, BindingKind, usize)> = symbols.line_bindings()
+        .map(|binding| (binding.name.as_str(), binding.kind, binding.depth))
+        .collect();
+    assert_eq!(bindings, vec![("a0", BindingKind::Param, 0), ("y0", BindingKind::Let, 1), ("y1", BindingKind::Let, 2)]);
+    assert_eq!(symbols.lookup("x1").map(|binding| binding.value), Some(Some(7)));
+    assert_eq!(symbols.let_depth(), 2);
+
+    // closing the inner let takes its binding out of scope
+    symbols.follow_lets(&[vec!["y0".to_string()]]);
+    assert!(symbols.lookup("y1").is_none());
+    assert!(symbols.lookup("y0").is_some());
+
+    symbols.end_line();
+    assert_eq!(symbols.line_bindings().count(), 0);
+    assert_eq!(symbols.heap().count(), 2);
+    symbols.clear();
+    assert_eq!(symbols.heap().count(), 0);
+}
+
+// A board with room for a few foods only keeps the ones that finish the line.
+#[test]
+fn a_full_board_keeps_the_food_that_finishes_the_line() {
+    let finishing = NextTokens::new(Globals::new(&Jit::new(), false)).completion().unwrap();
+    for seed in 0..20 {
+        // 4 by 2 blocks inside the border, 2 of them under the snake
+        let core = GameCore::new(0, 6, 4, seed);
+        let foods: Vec<&str> = core.food().iter().map(|food| food.instr.as_str()).collect();
+        assert_eq!(foods.len(), 6, "seed {}", seed);
+        assert!(foods.contains(&finishing[0].as_str()), "seed {}: {:?} has no {}", seed, foods, finishing[0]);
+        for food in core.food() {
+            assert!(!core.snake().is_overlap_except_tail(food.food_x, food.food_y));
+        }
+    }
+}
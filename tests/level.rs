@@ -0,0 +1,121 @@
+use std::fs;
+
+use snake_interpreter::campaign::Campaign;
+use snake_interpreter::level::{Level, MIN_BOARD, MIN_FREE_CELLS};
+
+// a level that parses, which the cases below add one line to
+const BASE: &str = "name test\ngoal 5\n";
+
+fn parse_err(src: &str) -> String {
+    match Level::parse(src) {
+        Ok(level) => panic!("{:?} should not parse, got {:?}", src, level),
+        Err(error) => error,
+    }
+}
+
+#[test]
+fn bad_levels_are_rejected() {
+    // every obstacle on the smallest board except the snake's row, which leaves too few cells
+    let walled: String = (1..15)
+        .flat_map(|x| (3..9).map(move |y| format!("obstacle {} {}\n", x, y)))
+        .collect();
+    let cases = [
+        (format!("{}board 40 12\n", BASE), "the board has to be"),
+        (format!("{}board 16 9\n", BASE), "the board has to be"),
+        (format!("{}board wide 12\n", BASE), "cannot read"),
+        (format!("{}obstacle 0 4\n", BASE), "is not inside the board"),
+        (format!("{}obstacle 29 4\n", BASE), "is not inside the board"),
+        // inside the full board, but not the smaller one given after it
+        (format!("{}obstacle 20 4\nboard 16 10\n", BASE), "is not inside the board"),
+        (format!("{}obstacle 4 2\n", BASE), "in the snake's way"),
+        (format!("{}board 16 10\n{}", BASE, walled), "free cells"),
+        ("name test\ngoal 5\npuzzle double\n".to_string(), "either a goal or a puzzle"),
+        ("name test\n".to_string(), "either a goal or a puzzle"),
+        ("name test\npuzzle nothing\n".to_string(), "unknown puzzle"),
+        (format!("{}require while\n", BASE), "cannot require while"),
+        (format!("{}allow int + ( def id\nrequire let\n", BASE), "let is required but not allowed"),
+        (format!("{}allow int + ( banana\n", BASE), "banana is not a food"),
+        (format!("{}max-lines 0\n", BASE), "at least one line"),
+        ("goal 5\n".to_string(), "missing name"),
+        (format!("{}speed 3\n", BASE), "cannot read"),
+    ];
+    for (src, expected) in cases {
+        let error = parse_err(&src);
+        assert!(error.contains(expected), "{:?} gave {:?}, expected {:?}", src, error, expected);
+    }
+}
+
+// the smallest board, with obstacles on all but `free` of the cells the snake does not start on
+fn walled_level(free: usize) -> String {
+    let (width, height) = MIN_BOARD;
+    let cells: Vec<(i32, i32)> = (1..height - 1)
+        .flat_map(|y| (1..width - 1).map(move |x| (x, y)))
+        .filter(|&(x, y)| !(y == 2 && (2..8).contains(&x)))
+        .collect();
+    let obstacles: String = cells[free..].iter().map(|(x, y)| format!("obstacle {} {}\n", x, y)).collect();
+    format!("{}board {} {}\n{}", BASE, width, height, obstacles)
+}
+
+#[test]
+fn boards_keep_room_for_food_and_the_snake() {
+    assert!(Level::parse(&walled_level(MIN_FREE_CELLS)).is_ok());
+    let error = parse_err(&walled_level(MIN_FREE_CELLS - 1));
+    assert!(error.contains("free cells"), "{}", error);
+}
+
+#[test]
+fn levels_parse_their_rules() {
+    let level = Level::parse("# a comment\nname two words\npuzzle double\nboard 20 12\nmax-lines 2\n\
+        allow int + ( let\nobstacle 8 4\nrequire let\n").unwrap();
+    assert_eq!(level.name, "two words");
+    assert_eq!((level.goal, level.puzzle.as_deref()), (None, Some("double")));
+    assert_eq!(level.board, (20, 12));
+    assert_eq!(level.max_lines, Some(2));
+    assert_eq!(level.obstacles, vec![(8, 4)]);
+    assert_eq!(level.required, vec!["let"]);
+}
+
+#[test]
+fn missing_lists_what_no_line_uses() {
+    let level = Level::parse(&format!("{}require let\nrequire if\nrequire print\n", BASE)).unwrap();
+    assert_eq!(level.missing(&[]), vec!["let", "if", "print"]);
+    assert_eq!(level.missing(&["( 1 + 2 )"]), vec!["let", "if", "print"]);
+    assert_eq!(level.missing(&["let { var y0 := 1 | } { y0 }", "( print 1 )"]), vec!["if"]);
+    // whole words only, so a name that contains one does not count
+    assert_eq!(level.missing(&["( letter + iffy )"]), vec!["let", "if", "print"]);
+    assert!(level.missing(&["let { var y0 := if true { 1 } { 2 } | } { ( print y0 ) }"]).is_empty());
+    assert!(Level::parse(BASE).unwrap().missing(&[]).is_empty());
+}
+
+#[test]
+fn campaign_progress_is_kept_between_loads() {
+    let dir = std::env::temp_dir().join(format!("snek-campaign-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("01-a.level"), "name first\ngoal 1\n").unwrap();
+    fs::write(dir.join("02-b.level"), "name second\ngoal 2\n").unwrap();
+    // not a level, so it is not played
+    fs::write(dir.join("notes.txt"), "name third\ngoal 3\n").unwrap();
+    let dir_str = dir.to_str().unwrap();
+
+    let mut campaign = Campaign::load(dir_str).unwrap();
+    assert_eq!(campaign.num_levels(), 2);
+    assert_eq!(campaign.current().map(|(num, level)| (num, level.name.clone())), Some((1, "first".to_string())));
+    campaign.finish_current().unwrap();
+    assert_eq!(fs::read_to_string(dir.join("progress.txt")).unwrap(), "finished first\n");
+
+    // a new load starts at the level after the finished one
+    let mut campaign = Campaign::load(dir_str).unwrap();
+    assert_eq!(campaign.current().map(|(num, level)| (num, level.name.clone())), Some((2, "second".to_string())));
+    campaign.finish_current().unwrap();
+    let campaign = Campaign::load(dir_str).unwrap();
+    assert!(campaign.current().is_none());
+
+    fs::write(dir.join("progress.txt"), "done first\n").unwrap();
+    assert!(Campaign::load(dir_str).is_err_and(|error| error.contains("cannot read")));
+    fs::write(dir.join("02-b.level"), "name first\ngoal 2\n").unwrap();
+    fs::remove_file(dir.join("progress.txt")).unwrap();
+    assert!(Campaign::load(dir_str).is_err_and(|error| error.contains("two levels are called first")));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
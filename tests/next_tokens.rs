@@ -0,0 +1,306 @@
+use im::HashMap;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use snake_interpreter::compile::{check_fun, compile_line};
+use snake_interpreter::grammar::LineParser;
+use snake_interpreter::jit::Jit;
+use snake_interpreter::lexer::Lexer;
+use snake_interpreter::next_tokens::{Globals, NextTokens};
+use snake_interpreter::optimize::OptLevel;
+use snake_interpreter::runtime::Value;
+use snake_interpreter::symbols::SymbolTable;
+use snake_interpreter::utils::{check_struct, CompileError, Line as Parsed, SnekError};
+
+// what a walk eats once its line is long, whichever of them comes first, to close what is open
+const CLOSING: [&str; 12] = [";", ")", "}", "|", "]", "end_int", "int", "true", "null", ":=", "{", "alloc"];
+
+// A line being played: what can come next, and the line as the game writes it.
+#[derive(Clone)]
+struct Line {
+    tokens: NextTokens,
+    text: String,
+    is_def: bool,
+    eaten: Vec<String>,
+}
+
+impl Line {
+    fn new(jit: &Jit, input: bool) -> Line {
+        Line { tokens: NextTokens::new(Globals::new(jit, input)), text: "(".to_string(), is_def: false, eaten: vec![] }
+    }
+
+    // eats a food that is on offer, with `number` as the int an end_int stands for
+    fn eat(&mut self, food: &str, number: i64) {
+        let spelled = match food {
+            "fun" => format!("fun {} (", self.tokens.name_for(food).unwrap()),
+            "struct" => format!("struct {} {{", self.tokens.name_for(food).unwrap()),
+            "var" => format!("var {} :=", self.tokens.name_for(food).unwrap()),
+            "param" => format!("{} |", self.tokens.name_for(food).unwrap()),
+            "field" => self.tokens.name_for(food).unwrap(),
+            "let" => "let {".to_string(),
+            "end_int" => number.to_string(),
+            "def" | "int" | "id" | ";" => String::new(),
+            _ => food.to_string(),
+        };
+        match food {
+            "fun" | "struct" => self.text = spelled,
+            "def" => self.is_def = true,
+            _ => {
+                self.text.push(' ');
+                self.text.push_str(&spelled);
+            },
+        }
+        assert!(self.tokens.eat(food), "{} was offered after {:?} but not eaten", food, self.eaten);
+        self.eaten.push(food.to_string());
+    }
+
+    // the line finished the way NextTokens::completion finishes it
+    fn completed(&self) -> Line {
+        let rest = self.tokens.completion();
+        let rest = rest.unwrap_or_else(|| panic!("{:?} cannot be finished", self.eaten));
+        let mut line = self.clone();
+        for food in rest {
+            line.eat(&food, 1);
+        }
+        assert!(line.tokens.is_finished(), "{:?} is not finished", line.eaten);
+        line
+    }
+}
+
+// Plays one line, eating a random food that is on offer each time.
+fn random_line(jit: &Jit, input: bool, rng: &mut StdRng) -> Line {
+    let mut line = Line::new(jit, input);
+    while !line.tokens.is_finished() {
+        let foods = line.tokens.foods();
+        assert!(!foods.is_empty(), "nothing can follow {:?}", line.eaten);
+        let closing = CLOSING.iter().find(|food| foods.iter().any(|other| other == *food));
+        let food = match closing {
+            Some(food) if line.eaten.len() > 30 => food.to_string(),
+            _ => foods[rng.gen_range(0..foods.len())].clone(),
+        };
+        line.eat(&food, rng.gen_range(-3..20));
+        assert!(line.eaten.len() < 500, "{:?} does not end", line.eaten);
+    }
+    line
+}
+
+// Compiles a finished line without running it or keeping what it defines, since a line
+// that loops runs until its fuel is gone.
+fn check(jit: &Jit, line: &Line) {
+    let defined_vars: HashMap<String, i64> = jit.def_bindings().iter().enumerate()
+        .map(|(idx, value)| (format!("x{}", idx), *value))
+        .collect();
+    let checked = match LineParser::new().parse(Lexer::new(&line.text)).map_err(CompileError::from) {
+        Ok(Parsed::Fun(def)) => check_fun(&def, jit.struct_defs(), jit.fun_defs(), &defined_vars).map(|_| ()),
+        Ok(Parsed::Struct(def)) => check_struct(&def, jit.struct_defs()),
+        Ok(Parsed::Expr(expression)) => {
+            compile_line(&expression, jit.struct_defs(), jit.fun_defs(), &defined_vars, OptLevel::Full).map(|_| ())
+        },
+        Err(error) => Err(error),
+    };
+    if let Err(error) = checked {
+        panic!("{} does not compile: {}", line.text, error);
+    }
+}
+
+// Runs a finished line, which has to compile, and keeps what a def line makes.
+fn run(jit: &mut Jit, line: &Line) {
+    match jit.run_line(&line.text) {
+        Err(SnekError::Compile(error)) => panic!("{} does not compile: {}", line.text, error),
+        Ok(Some(Value::Int(n))) if line.is_def => jit.save_def(n),
+        _ => {},
+    }
+}
+
+fn eat_all(jit: &Jit, foods: &[&str]) -> NextTokens {
+    let mut tokens = NextTokens::new(Globals::new(jit, false));
+    for food in foods {
+        assert!(tokens.eat(food), "{} cannot follow {:?}: {:?}", food, foods, tokens.foods());
+    }
+    tokens
+}
+
+fn offers(tokens: &NextTokens, food: &str) -> bool {
+    tokens.foods().iter().any(|other| other == food)
+}
+
+#[test]
+fn random_lines_compile() {
+    for seed in 0..40 {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut jit = Jit::new();
+        let input = seed % 2 == 0;
+        jit.set_input(3);
+        for _ in 0..15 {
+            let line = random_line(&jit, input, &mut rng);
+            run(&mut jit, &line);
+        }
+    }
+}
+
+// Every food that is offered along random lines, in sessions that define things as they
+// go, leaves a line that can be finished into one that compiles.
+#[test]
+fn every_offer_can_be_finished() {
+    for seed in 0..6 {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut jit = Jit::new();
+        let input = seed % 2 == 1;
+        for _ in 0..8 {
+            let played = random_line(&jit, input, &mut rng);
+            let mut line = Line::new(&jit, input);
+            for food in &played.eaten {
+                for offered in line.tokens.foods() {
+                    let mut next = line.clone();
+                    next.eat(&offered, 1);
+                    check(&jit, &next.completed());
+                }
+                line.eat(food, 1);
+            }
+            run(&mut jit, &line);
+        }
+    }
+}
+
+// x10 and on are names like any other, long after the first ten defs
+#[test]
+fn many_defs_stay_in_scope() {
+    let mut jit = Jit::new();
+    for value in 0..12 {
+        let mut line = Line::new(&jit, false);
+        for food in ["def", "int", "end_int", ")", ";"] {
+            line.eat(food, value);
+        }
+        run(&mut jit, &line);
+    }
+    assert_eq!(jit.def_bindings().len(), 12);
+    let id = eat_all(&jit, &["id"]).foods();
+    assert!(id.contains(&"x10".to_string()) && id.contains(&"x11".to_string()), "{:?}", id);
+    let mut line = Line::new(&jit, false);
+    for food in ["def", "id", "x11", "+", "id", "x10", ")", ";"] {
+        line.eat(food, 0);
+    }
+    run(&mut jit, &line);
+    assert_eq!(jit.def_bindings()[12], 21);
+    let mut rng = StdRng::seed_from_u64(7);
+    for _ in 0..10 {
+        let line = random_line(&jit, false, &mut rng);
+        run(&mut jit, &line);
+    }
+}
+
+#[test]
+fn lines_start_with_their_kind() {
+    let jit = Jit::new();
+    let start = eat_all(&jit, &[]);
+    for food in ["def", "fun", "struct", "(", "int", "let"] {
+        assert!(offers(&start, food), "{}", food);
+    }
+    for food in [")", ";", "+", "id", "set", "break", "apply", "alloc", "lookup"] {
+        assert!(!offers(&start, food), "{}", food);
+    }
+    assert!(!offers(&eat_all(&jit, &["def"]), "fun"));
+    assert!(!offers(&eat_all(&jit, &["int", "end_int"]), "def"));
+    assert_eq!(eat_all(&jit, &["int"]).foods(), vec!["end_int"]);
+}
+
+#[test]
+fn operators_follow_types() {
+    let jit = Jit::new();
+    let after_bool = eat_all(&jit, &["true"]);
+    assert!(!offers(&after_bool, "+") && !offers(&after_bool, "<"));
+    assert!(offers(&after_bool, "=") && offers(&after_bool, ")"));
+    let after_int = eat_all(&jit, &["int", "end_int"]);
+    for food in ["+", "%", "<", "=", ")"] {
+        assert!(offers(&after_int, food), "{}", food);
+    }
+    // only one comparison, and a def line has to come out as an int
+    assert!(!offers(&eat_all(&jit, &["int", "end_int", "<", "int", "end_int"]), "="));
+    assert!(!offers(&eat_all(&jit, &["def", "int", "end_int"]), "<"));
+    assert!(!offers(&eat_all(&jit, &["def"]), "true"));
+    // the condition of an if is a bool
+    let cond = eat_all(&jit, &["if", "int", "end_int"]);
+    assert!(!offers(&cond, "{") && offers(&cond, ">"));
+}
+
+#[test]
+fn names_follow_scope() {
+    let jit = Jit::new();
+    let bound = eat_all(&jit, &["let", "var", "true", "|", "}", "{"]);
+    assert!(offers(&bound, "id") && offers(&bound, "set"));
+    assert_eq!(eat_all(&jit, &["let", "var", "true", "|", "}", "{", "id"]).foods(), vec!["y0"]);
+    // a binding is not in scope in its own value, nor after its let
+    assert!(!offers(&eat_all(&jit, &["let", "var"]), "id"));
+    assert!(!offers(&eat_all(&jit, &["(", "let", "var", "true", "|", "}", "{", "true", "}", ")", "="]), "id"));
+    // a bool binding cannot be added to
+    let sum = eat_all(&jit, &["let", "var", "true", "|", "}", "{", "int", "end_int", "+"]);
+    assert!(!offers(&sum, "id"));
+    // and set keeps its type, so an int has to be compared into a bool
+    let set = eat_all(&jit, &["let", "var", "true", "|", "}", "{", "set", "id", "y0", ":=", "int", "end_int"]);
+    assert!(!offers(&set, "}") && offers(&set, "="));
+}
+
+// the scopes of the line after each food, the way the game's symbol table follows them
+fn scopes_along(jit: &Jit, foods: &[&str]) -> Vec<Vec<String>> {
+    let mut tokens = NextTokens::new(Globals::new(jit, false));
+    let mut symbols = SymbolTable::new();
+    let mut scopes = vec![];
+    for food in foods {
+        assert!(tokens.eat(food), "{} cannot follow {:?}", food, foods);
+        symbols.follow_lets(&tokens.let_scopes());
+        scopes.push(symbols.line_bindings().map(|binding| format!("{}@{}", binding.name, binding.depth)).collect());
+    }
+    scopes
+}
+
+#[test]
+fn lets_nest_and_close() {
+    let jit = Jit::new();
+    let foods = ["let", "var", "true", "|", "var", "let", "var", "int", "end_int", "|", "}", "{",
+        "id", "y2", "}", "|", "}", "{", "let", "}", "{", "id", "y1", "}", "}", ")", ";"];
+    let scopes = scopes_along(&jit, &foods);
+    let at = |count: usize| scopes[count - 1].join(" ");
+    // a binding is only in scope once its value is finished
+    assert_eq!(at(2), "");
+    assert_eq!(at(4), "y0@1");
+    // an inner let sees the outer bindings before it, and its own go with its "}"
+    assert_eq!(at(10), "y0@1 y2@2");
+    assert_eq!(at(14), "y0@1 y2@2");
+    assert_eq!(at(15), "y0@1 y1@1");
+    assert_eq!(at(21), "y0@1 y1@1");
+    assert_eq!(at(25), "");
+    // which is also what can be named
+    assert_eq!(eat_all(&jit, &[&foods[..12], &["id"]].concat()).foods(), vec!["y0", "y2"]);
+    assert_eq!(eat_all(&jit, &[&foods[..21], &["id"]].concat()).foods(), vec!["y0", "y1"]);
+}
+
+#[test]
+fn break_only_in_loops() {
+    let jit = Jit::new();
+    assert!(!offers(&eat_all(&jit, &["if"]), "break"));
+    let body = eat_all(&jit, &["repeat-until", "{"]);
+    assert!(offers(&body, "break"));
+    // every break of a loop and its body have to agree
+    let second = eat_all(&jit, &["repeat-until", "{", "if", "true", "{", "break", "true", "}", "{", "int", "end_int"]);
+    assert!(!offers(&second, "}") && offers(&second, "<"));
+}
+
+#[test]
+fn definitions_come_into_scope() {
+    let mut jit = Jit::new();
+    jit.run_line("struct s0 { g0 i64 | g1 s0 | }").unwrap();
+    jit.run_line("fun f0 ( a0 | a1 | ) { ( a0 < a1 ) }").unwrap();
+    assert_eq!(eat_all(&jit, &["apply"]).foods(), vec!["f0"]);
+    // two arguments, each an int
+    let args = eat_all(&jit, &["apply", "f0", "(", "int", "end_int", "|"]);
+    assert!(!offers(&args, ")") && !offers(&args, "true"));
+    assert_eq!(eat_all(&jit, &["apply", "f0", "(", "int", "end_int", "|", "int", "end_int", "|"]).foods(), vec![")"]);
+    // a lookup in an int line can only read g0
+    assert_eq!(eat_all(&jit, &["def", "lookup", "alloc", "s0", "(", "int", "end_int", "|", "null", "|", ")", "["]).foods(),
+        vec!["g0"]);
+    // a fun that calls itself returns an int
+    let recursive = eat_all(&jit, &["fun", "param", ")", "{", "if", "true", "{", "apply"]);
+    assert!(recursive.foods().contains(&"f1".to_string()));
+    let boolean = eat_all(&jit, &["fun", "param", ")", "{", "if", "true", "{", "true", "}", "{", "apply"]);
+    assert_eq!(boolean.foods(), vec!["f0"]);
+}
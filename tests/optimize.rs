@@ -0,0 +1,100 @@
+use im::HashMap;
+
+use snake_interpreter::compile::compile_line;
+use snake_interpreter::grammar::LineParser;
+use snake_interpreter::jit::Jit;
+use snake_interpreter::lexer::Lexer;
+use snake_interpreter::optimize::OptLevel;
+use snake_interpreter::runtime::Value;
+use snake_interpreter::utils::{Expr, Line, SnekError};
+
+const LEVELS: [OptLevel; 3] = [OptLevel::None, OptLevel::Peephole, OptLevel::Full];
+
+fn parse_expr(line: &str) -> Expr {
+    match LineParser::new().parse(Lexer::new(line)).unwrap() {
+        Line::Expr(expr) => expr,
+        _ => panic!("{} is not an expression", line),
+    }
+}
+
+// Runs the last line after the others, with x0 = 5 and x1 = -3 saved, and gives back its
+// result and what it printed.
+fn run(lines: &[&str], input: i64, opt: OptLevel) -> (Result<Option<Value>, SnekError>, Vec<String>) {
+    let mut jit = Jit::new();
+    jit.set_opt_level(opt);
+    jit.set_input(input);
+    jit.save_def(5);
+    jit.save_def(-3);
+    let (main_line, fun_lines) = lines.split_last().unwrap();
+    for line in fun_lines {
+        jit.run_line(line).unwrap();
+    }
+    (jit.run_line(main_line), jit.output().to_vec())
+}
+
+#[test]
+fn optimized_lines_agree_with_unoptimized() {
+    let programs: Vec<&[&str]> = vec![
+        &["( ( 2 + 3 ) * ( 10 - 4 ) )"],
+        &["( ( 7 / -2 ) * ( -7 % 2 ) )"],
+        &["( 4611686018427387903 + 1 )"],
+        &["( 4611686018427387903 + 0 )"],
+        &["( -4611686018427387904 / -1 )"],
+        &["( 2147483647 * 2147483647 )"],
+        &["9223372036854775807"],
+        &["( 5858366314625090250 = 1 )"],
+        &["sub1 4611686018427387904"],
+        &["( 9223372036854775807 + -9223372036854775807 )"],
+        &["( 1 / ( 3 - 3 ) )"],
+        &["( 1 % 0 )"],
+        &["( ( input + 0 ) * 1 )"],
+        &["( 0 + ( input / 1 ) )"],
+        &["( ( input * 4611686018427387903 ) + 0 )"],
+        &["( print input - 0 )"],
+        &["( ( 1 < 2 ) = ( 3 >= 4 ) )"],
+        &["if ( 1 < 2 ) { print 1 } { print 2 }"],
+        &["if ( x0 = 5 ) { ( x0 + x1 ) } { ( 1 / 0 ) }"],
+        &["add1 sub1 add1 x1"],
+        &["let { var x0 := 1 | var y0 := ( x0 + 1 ) | } { ( x0 + ( y0 + x1 ) ) }"],
+        &["let { var y0 := x0 | var x0 := ( y0 * 2 ) | } { ( x0 - y0 ) }"],
+        &["let { var y0 := 3 | } { ( ( set y0 := 1 ) + y0 ) }"],
+        &["let { var y0 := 3 | } { repeat-until { print ( set y0 := sub1 y0 ) } { print ( y0 = 0 ) } }"],
+        &["fun f0 ( x0 | ) { ( x0 + x1 ) }", "( apply f0 ( 10 | ) + x0 )"],
+        &["fun f0 ( a0 | ) { if ( a0 = 0 ) { 0 } { ( a0 + ( apply f0 ( ( a0 - 1 ) | ) ) ) } }",
+            "( apply f0 ( 100 | ) * 1 )"],
+        &["struct s0 { g0 i64 | g1 s0 | }",
+            "let { var y0 := alloc s0 ( ( 1 + 2 ) | alloc s0 ( 2 | null | ) | ) | } { ( lookup y0 [ g0 ] + lookup lookup y0 [ g1 ] [ g0 ] ) }"],
+        &["struct s0 { g0 i64 | }", "let { var y0 := alloc s0 ( 1 | ) | } { ( ( index y0 [ g0 ] := 41 ) + lookup y0 [ g0 ] ) }"],
+        &["struct s0 { g0 i64 | g1 s0 | }", "lookup lookup alloc s0 ( 1 | null | ) [ g1 ] [ g0 ]"],
+        &["struct s0 { g0 i64 | }", "lookup ( if true { null } { alloc s0 ( 1 | ) } ) [ g0 ]"],
+        &["struct s0 { g0 i64 | }", "lookup ( if false { alloc s0 ( 1 | ) } { null } ) [ g0 ]"],
+    ];
+    for lines in programs {
+        for input in [0, 7, -4611686018427387904] {
+            let expected = run(lines, input, OptLevel::None);
+            for opt in LEVELS {
+                assert_eq!(run(lines, input, opt), expected, "{:?} at {:?} with input {}", lines, opt, input);
+            }
+        }
+    }
+}
+
+#[test]
+fn optimizing_shortens_constant_lines() {
+    for line in ["( ( 2 + 3 ) * ( 10 - 4 ) )", "if ( 1 < 2 ) { ( x0 * 2 ) } { x1 }", "( ( input + 0 ) * 1 )"] {
+        let expr = parse_expr(line);
+        let defined_vars: HashMap<String, i64> = HashMap::from(vec![("x0".to_string(), 5), ("x1".to_string(), -3)]);
+        let lengths: Vec<usize> = LEVELS.iter()
+            .map(|opt| compile_line(&expr, &[], &[], &defined_vars, *opt).unwrap().len())
+            .collect();
+        assert!(lengths[1] < lengths[0] && lengths[2] < lengths[1], "{}: {:?}", line, lengths);
+    }
+}
+
+#[test]
+fn folded_branches_are_still_checked() {
+    let defined_vars = HashMap::new();
+    for line in ["if true { 1 } { ( 1 + false ) }", "let { var y0 := 1 | var y0 := 2 | } { y0 }"] {
+        assert!(compile_line(&parse_expr(line), &[], &[], &defined_vars, OptLevel::Full).is_err(), "{}", line);
+    }
+}
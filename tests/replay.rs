@@ -0,0 +1,123 @@
+use snake_interpreter::game_core::GameCore;
+use snake_interpreter::level::Level;
+use snake_interpreter::puzzle::Puzzle;
+use snake_interpreter::replay::Replay;
+use snake_interpreter::snake::Direction;
+
+// the board the game window has
+const START_X: i32 = 10;
+const WIDTH: i32 = 30;
+const HEIGHT: i32 = 20;
+
+// foods that bring a line closer to its end, the best first
+const CLOSING: [&str; 10] = [";", ")", "}", "|", "]", "end_int", "int", "id", "true", "null"];
+
+// Steers for the food that best closes the line, a row or column at a time, whatever it eats
+// on the way, until the line runs. It does not look out for the snake's own body, so it can
+// die on the way; it gives up after `ticks` ticks too.
+fn finish_line(core: &mut GameCore, ticks: u64) -> bool {
+    let lines = core.program().len();
+    let end = core.ticks() + ticks;
+    while core.program().len() == lines && core.ticks() < end {
+        let Some(food) = CLOSING.iter().find_map(|instr| core.food().iter().find(|food| food.instr == *instr)) else {
+            return false;
+        };
+        let target = (food.food_x, food.food_y);
+        let (x, y) = core.snake().head_position();
+        let dir = if x < target.0 {
+            Direction::Right
+        } else if x > target.0 {
+            Direction::Left
+        } else if y < target.1 {
+            Direction::Down
+        } else {
+            Direction::Up
+        };
+        // a snake cannot turn back on itself, so it goes around
+        let dir = match dir == core.snake().head_direction().opposite() {
+            true if y < target.1 || y == 1 => Direction::Down,
+            true => Direction::Up,
+            false => dir,
+        };
+        core.tick(Some(dir));
+        if core.is_game_over() {
+            return false;
+        }
+    }
+    core.program().len() > lines
+}
+
+// what the board and the program look like, to compare a round with its replay
+#[derive(Debug, PartialEq)]
+struct Snapshot {
+    food: Vec<(i32, i32, String)>,
+    lines: Vec<String>,
+    scores: Vec<Option<usize>>,
+    reached_goal: bool,
+}
+
+fn snapshot(core: &GameCore) -> Snapshot {
+    Snapshot {
+        food: core.food().iter().map(|food| (food.food_x, food.food_y, food.instr.clone())).collect(),
+        lines: core.program().iter().map(|prog| format!("{} {:?}", prog.line, prog.result)).collect(),
+        scores: core.program().iter().map(|prog| prog.score).collect(),
+        reached_goal: core.reached_goal(),
+    }
+}
+
+// Plays a round of two lines, then checks that its saved replay plays the same way.
+fn play_and_replay(core: &mut GameCore) -> Vec<Option<usize>> {
+    for _ in 0..2 {
+        if !finish_line(core, 300) || core.reached_goal() {
+            break;
+        }
+    }
+    let played = snapshot(core);
+
+    let replay = Replay::parse(&core.replay().to_string()).unwrap();
+    assert_eq!(replay, core.replay());
+    let mut again = GameCore::from_replay(START_X, WIDTH, HEIGHT, &replay).unwrap();
+    replay.play(&mut again);
+    assert_eq!(snapshot(&again), played, "replay of seed {}", replay.seed);
+    played.scores
+}
+
+// the rounds after the first start from a seed of their own, which their replays start from
+#[test]
+fn restarted_puzzle_rounds_replay_the_same() {
+    let mut core = GameCore::new(START_X, WIDTH, HEIGHT, 2);
+    core.set_puzzle(Puzzle::by_name("double").unwrap());
+    for round in 0..3 {
+        let scores = play_and_replay(&mut core);
+        assert!(scores.iter().any(|score| score.is_some()), "round {} scored no line", round);
+        core.restart();
+    }
+}
+
+#[test]
+fn restarted_level_rounds_replay_the_same() {
+    let level = Level::read("levels/01-first-steps.level").unwrap();
+    let mut core = GameCore::new(START_X, WIDTH, HEIGHT, 3);
+    core.set_level(level);
+    for round in 0..3 {
+        play_and_replay(&mut core);
+        assert!(!core.program().is_empty(), "round {} ran no line", round);
+        core.restart();
+    }
+}
+
+#[test]
+fn replays_read_what_they_write() {
+    let src = "# a comment\nseed 1234\ngoal 42\npuzzle double\nlevel levels/04-doubles.level\n\
+        turn 3 up\nturn 10 left\nticks 57\n";
+    let replay = Replay::parse(src).unwrap();
+    assert_eq!(replay.turns, vec![(3, Direction::Up), (10, Direction::Left)]);
+    assert_eq!(replay.turn_at(10), Some(Direction::Left));
+    assert_eq!(replay.turn_at(11), None);
+    assert_eq!(Replay::parse(&replay.to_string()).unwrap(), replay);
+
+    for bad in ["goal 1\nticks 2\n", "seed 1\ngoal 1\nticks 2\nturn 1 back\n", "seed 1\ngoal 1\nticks 2\npuzzle nothing\n"] {
+        assert!(Replay::parse(bad).is_err(), "{:?} should not parse", bad);
+    }
+}
+